@@ -0,0 +1,204 @@
+use std::{io, fs};
+use std::path::{Path, PathBuf};
+use serde_json;
+
+use super::types::PunterId;
+use super::map::{Map, Fingerprint, RiversIndex};
+use super::proto::{Setup, Future};
+
+#[derive(Debug)]
+pub enum Error {
+    CreateDir(io::Error),
+    ReadDir(io::Error),
+    OpenEntry(io::Error),
+    ReadEntry(serde_json::Error),
+    CreateEntry(io::Error),
+    WriteEntry(serde_json::Error),
+    RenameEntry(io::Error),
+    OpenMap(io::Error),
+    ReadMap(serde_json::Error),
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BookKey {
+    pub fingerprint: Fingerprint,
+    pub punters: usize,
+    // futures are selected for the seat, since the turn order matters
+    pub punter: PunterId,
+    // openings do not depend on splurges and options settings
+    pub futures: bool,
+}
+
+impl BookKey {
+    pub fn new(map: &Map, punters: usize, punter: PunterId, futures: bool) -> BookKey {
+        BookKey {
+            fingerprint: map.fingerprint(),
+            punters: punters,
+            punter: punter,
+            futures: futures,
+        }
+    }
+
+    pub fn from_setup(setup: &Setup) -> BookKey {
+        BookKey::new(&setup.map, setup.punters, setup.punter, setup.settings.futures)
+    }
+
+    fn file_name(&self) -> String {
+        format!("{}-{}p{}-f{}.json", self.fingerprint, self.punters, self.punter, if self.futures { 1 } else { 0 })
+    }
+}
+
+// Setup computations made ahead of time.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Opening {
+    pub rivers_bw: RiversIndex<f64>,
+    // in execution order
+    pub futures: Vec<Future>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: BookKey,
+    opening: Opening,
+}
+
+// Opening book stored as a directory with a file per key.
+#[derive(Clone, Debug)]
+pub struct Book {
+    dir: PathBuf,
+}
+
+impl Book {
+    pub fn new<P>(dir: P) -> Book where P: AsRef<Path> {
+        Book {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    pub fn lookup(&self, key: &BookKey) -> Result<Option<Opening>, Error> {
+        let file = match fs::File::open(self.dir.join(key.file_name())) {
+            Ok(file) =>
+                file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                return Ok(None),
+            Err(e) =>
+                return Err(Error::OpenEntry(e)),
+        };
+        let entry: Entry = serde_json::from_reader(io::BufReader::new(file))
+            .map_err(Error::ReadEntry)?;
+        // guard against renamed files
+        Ok(if entry.key == *key { Some(entry.opening) } else { None })
+    }
+
+    pub fn store(&self, key: &BookKey, opening: Opening) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir)
+            .map_err(Error::CreateDir)?;
+        let path = self.dir.join(key.file_name());
+        let tmp_path = path.with_extension("tmp");
+        let entry = Entry { key: key.clone(), opening: opening, };
+        {
+            let file = fs::File::create(&tmp_path)
+                .map_err(Error::CreateEntry)?;
+            serde_json::to_writer(io::BufWriter::new(file), &entry)
+                .map_err(Error::WriteEntry)?;
+        }
+        fs::rename(&tmp_path, &path)
+            .map_err(Error::RenameEntry)
+    }
+}
+
+pub fn load_map<P>(path: P) -> Result<Map, Error> where P: AsRef<Path> {
+    let file = fs::File::open(path)
+        .map_err(Error::OpenMap)?;
+    serde_json::from_reader(io::BufReader::new(file))
+        .map_err(Error::ReadMap)
+}
+
+// Fills the book for every map in `maps_dir`, every punters count given and every seat, with futures
+// enabled and disabled. `make_openings` is invoked once per map and punters count, and returns an opening
+// with futures for each seat, its futures are dropped for entries with futures disabled.
+pub fn precompute<P, F>(book: &Book, maps_dir: P, punters_counts: &[usize], mut make_openings: F) -> Result<Vec<(PathBuf, BookKey)>, Error>
+    where P: AsRef<Path>,
+          F: FnMut(&Map, usize) -> Vec<Opening>,
+{
+    let mut maps_paths = Vec::new();
+    for dir_entry in fs::read_dir(maps_dir).map_err(Error::ReadDir)? {
+        let path = dir_entry.map_err(Error::ReadDir)?.path();
+        if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+            maps_paths.push(path);
+        }
+    }
+    maps_paths.sort();
+
+    let mut stored = Vec::new();
+    for map_path in maps_paths {
+        let map = load_map(&map_path)?;
+        for &punters in punters_counts.iter() {
+            info!("precomputing opening for {:?} with {} punters", map_path, punters);
+            let openings = make_openings(&map, punters);
+            for (punter, opening) in openings.into_iter().enumerate() {
+                for &futures in [false, true].iter() {
+                    let key = BookKey::new(&map, punters, punter as PunterId, futures);
+                    let entry_opening = Opening {
+                        futures: if futures { opening.futures.clone() } else { Vec::new() },
+                        ..opening.clone()
+                    };
+                    book.store(&key, entry_opening)?;
+                    stored.push((map_path.clone(), key));
+                }
+            }
+        }
+    }
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+    use super::super::types::SiteId;
+    use super::super::map::RiversIndex;
+    use super::super::proto::Future;
+    use super::super::test_common::*;
+    use super::{Book, BookKey, Opening, precompute};
+
+    #[test]
+    fn store_and_lookup() {
+        let dir = env::temp_dir().join(format!("lambda_punter_book_test_{}", process::id()));
+        let book = Book::new(&dir);
+        let setup = load_map_setup("sample");
+        let key = BookKey::from_setup(&setup);
+        assert!(book.lookup(&key).unwrap().is_none());
+        book.store(&key, Opening {
+            rivers_bw: RiversIndex::default(),
+            futures: vec![Future { source: 1, target: 6, }],
+        }).unwrap();
+        assert_eq!(book.lookup(&key).unwrap().unwrap().futures, vec![Future { source: 1, target: 6, }]);
+        let other_key = BookKey::new(&setup.map, setup.punters + 1, setup.punter, setup.settings.futures);
+        assert!(book.lookup(&other_key).unwrap().is_none());
+        let other_seat = BookKey::new(&setup.map, setup.punters, setup.punter + 1, setup.settings.futures);
+        assert!(book.lookup(&other_seat).unwrap().is_none());
+
+        // precompute over a maps directory
+        let maps_dir = dir.join("maps");
+        fs::create_dir_all(&maps_dir).unwrap();
+        fs::copy(format!("{}/../maps/sample.json", env!("CARGO_MANIFEST_DIR")), maps_dir.join("sample.json")).unwrap();
+        let mut calls = 0;
+        let stored = precompute(&book, &maps_dir, &[2, 3], |_map, punters| {
+            calls += 1;
+            (0 .. punters)
+                .map(|punter| Opening {
+                    rivers_bw: RiversIndex::default(),
+                    futures: vec![Future { source: 5, target: punter as SiteId, }],
+                })
+                .collect()
+        }).unwrap();
+        assert_eq!(calls, 2);
+        // (2 + 3) seats, futures enabled and disabled
+        assert_eq!(stored.len(), 10);
+        let opening = book.lookup(&BookKey::new(&setup.map, 3, 2, true)).unwrap().unwrap();
+        assert_eq!(opening.futures, vec![Future { source: 5, target: 2, }]);
+        assert_eq!(book.lookup(&BookKey::new(&setup.map, 2, 1, false)).unwrap().unwrap().futures, vec![]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,19 @@
-
-use super::proto::{Req, Rep, Score};
+use std::mem;
+use std::time::Instant;
+use super::proto::{Req, Rep, Move, Setup, Score};
 use super::game::{GameState, GameStateBuilder};
+use super::rng::Seed;
+use super::observer::Observer;
 
 #[derive(PartialEq, Debug)]
 pub enum Error<SR, RR, GE> {
     Send(SR),
     Recv(RR),
+    Session(SessionError<GE>),
+}
+
+#[derive(PartialEq, Debug)]
+pub enum SessionError<GE> {
     GameState(GE),
     UnexpectedHandshakeRep(Rep),
     UnexpectedSetupRep(Rep),
@@ -13,121 +21,301 @@ pub enum Error<SR, RR, GE> {
     UnexpectedSetupOrMoveRep(Rep),
     UnexpectedStateArrived,
     NoExpectedStateArrived,
+    // a rep arrived after the session is stopped or suspended
+    Finished,
 }
 
-pub fn run_online<S, FS, SR, FR, RR, GB>(
-    name: &str,
+impl<SR, RR, GE> From<SessionError<GE>> for Error<SR, RR, GE> {
+    fn from(e: SessionError<GE>) -> Error<SR, RR, GE> {
+        Error::Session(e)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    // a single connection for the whole game, game state is kept by the session
+    Online,
+    // a process per turn, game state travels with every message through the server
+    Offline,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    AwaitingHandshake,
+    AwaitingSetup,
+    Playing,
+    // offline only: the turn is made and the game state is handed over to the server
+    Suspended,
+    Stopped,
+}
+
+// What to do after a rep is fed into a session.
+#[derive(Debug)]
+pub enum Step<GS> {
+    // nothing to send, wait for the next rep
+    Wait,
+    // send the req (along with the state in offline mode)
+    Reply(Req, Option<GS>),
+    // game is over with these scores
+    Stop(Vec<Score>, GS),
+}
+
+enum Phase<GB> where GB: GameStateBuilder {
+    AwaitingHandshake(GB),
+    AwaitingSetup(GB),
+    Playing(GB::GameState),
+    Suspended,
+    Stopped,
+}
+
+// Protocol state machine: it is fed with server reps one by one and tells what to send back,
+// leaving the transport and the loop to the caller. Any error finishes the session.
+pub struct Session<GB> where GB: GameStateBuilder {
+    mode: Mode,
+    name: String,
+    seed: Seed,
+    phase: Phase<GB>,
+}
+
+impl<GB> Session<GB> where GB: GameStateBuilder {
+    pub fn new(mode: Mode, name: &str, gs_builder: GB, seed: Seed) -> Session<GB> {
+        Session {
+            mode: mode,
+            name: name.to_string(),
+            seed: seed,
+            phase: Phase::AwaitingHandshake(gs_builder),
+        }
+    }
+
+    // P → S {"me" : name}, the first req to send
+    pub fn handshake(&self) -> Req {
+        Req::Handshake { name: self.name.clone(), }
+    }
+
+    pub fn state(&self) -> State {
+        match self.phase {
+            Phase::AwaitingHandshake(..) =>
+                State::AwaitingHandshake,
+            Phase::AwaitingSetup(..) =>
+                State::AwaitingSetup,
+            Phase::Playing(..) =>
+                State::Playing,
+            Phase::Suspended =>
+                State::Suspended,
+            Phase::Stopped =>
+                State::Stopped,
+        }
+    }
+
+    pub fn step(&mut self, rep: Rep, maybe_state: Option<GB::GameState>)
+                -> Result<Step<GB::GameState>, SessionError<<GB::GameState as GameState>::Error>>
+    {
+        self.step_observed(rep, maybe_state, ())
+    }
+
+    pub fn step_observed<O>(&mut self, rep: Rep, maybe_state: Option<GB::GameState>, mut observer: O)
+                            -> Result<Step<GB::GameState>, SessionError<<GB::GameState as GameState>::Error>>
+        where O: Observer
+    {
+        let mode = self.mode;
+        match (mem::replace(&mut self.phase, Phase::Stopped), mode) {
+            // S → P {"you" : name}
+            (Phase::AwaitingHandshake(gs_builder), _) =>
+                match (rep, maybe_state) {
+                    (Rep::Handshake { .. }, Some(..)) if mode == Mode::Offline =>
+                        Err(SessionError::UnexpectedStateArrived),
+                    (Rep::Handshake { name: ref rep_name, }, _) if *rep_name == self.name => {
+                        observer.on_handshake(rep_name);
+                        self.phase = Phase::AwaitingSetup(gs_builder);
+                        Ok(Step::Wait)
+                    },
+                    (other, _) =>
+                        Err(SessionError::UnexpectedHandshakeRep(other)),
+                },
+            // S → P {"punter" : p, "punters" : n, "map" : map}
+            (Phase::AwaitingSetup(gs_builder), Mode::Online) =>
+                match rep {
+                    Rep::Setup(setup) => {
+                        let (game_state, req) = build_observed(gs_builder, setup, self.seed, &mut observer);
+                        // P → S {"ready" : p}
+                        self.phase = Phase::Playing(game_state);
+                        Ok(Step::Reply(req, None))
+                    },
+                    other =>
+                        Err(SessionError::UnexpectedSetupRep(other)),
+                },
+            // S → P {"move" : {"moves" : moves}}
+            // S → P {"stop" : {"moves" : moves,"scores" : scores}}
+            (Phase::Playing(game_state), _) =>
+                match rep {
+                    Rep::Move { moves, } => {
+                        let (move_, next_game_state) = play_observed(game_state, moves, &mut observer)?;
+                        self.phase = Phase::Playing(next_game_state);
+                        Ok(Step::Reply(Req::Move(move_), None))
+                    },
+                    // S → P {"timeout" : t}, the move is taken as a pass and the game goes on
+                    Rep::Timeout(timeout_secs) => {
+                        observer.on_timeout(timeout_secs);
+                        self.phase = Phase::Playing(game_state);
+                        Ok(Step::Wait)
+                    },
+                    Rep::Stop { scores, moves, } => {
+                        observer.on_stop(&moves, &scores);
+                        Ok(Step::Stop(scores, game_state.stop(moves).map_err(SessionError::GameState)?))
+                    },
+                    other =>
+                        Err(SessionError::UnexpectedMoveRep(other)),
+                },
+            // offline: a process is started for any of setup, move or stop
+            (Phase::AwaitingSetup(gs_builder), Mode::Offline) =>
+                match (rep, maybe_state) {
+                    // S → P {"punter" : p, "punters" : n, "map" : map}
+                    (Rep::Setup(setup), None) => {
+                        let (game_state, req) = build_observed(gs_builder, setup, self.seed, &mut observer);
+                        // P → S {"ready" : p, "state" : state}
+                        self.phase = Phase::Suspended;
+                        Ok(Step::Reply(req, Some(game_state)))
+                    },
+                    (Rep::Setup(..), Some(..)) =>
+                        Err(SessionError::UnexpectedStateArrived),
+                    // S → P {"timeout" : t}, the move or stop for the same process follows
+                    (Rep::Timeout(timeout_secs), _) => {
+                        observer.on_timeout(timeout_secs);
+                        self.phase = Phase::AwaitingSetup(gs_builder);
+                        Ok(Step::Wait)
+                    },
+                    // S → P {"move" : {"moves" : moves},"state" : state}
+                    (Rep::Move { moves, }, Some(mut game_state)) => {
+                        game_state.rebuild_derived();
+                        let (move_, next_game_state) = play_observed(game_state, moves, &mut observer)?;
+                        self.phase = Phase::Suspended;
+                        Ok(Step::Reply(Req::Move(move_), Some(next_game_state)))
+                    },
+                    (Rep::Move { .. }, None) =>
+                        Err(SessionError::NoExpectedStateArrived),
+                    // S → P {"stop" : {"moves" : moves,"scores" : scores},"state" : state}
+                    (Rep::Stop { scores, moves, }, Some(mut game_state)) => {
+                        game_state.rebuild_derived();
+                        observer.on_stop(&moves, &scores);
+                        Ok(Step::Stop(scores, game_state.stop(moves).map_err(SessionError::GameState)?))
+                    },
+                    (Rep::Stop { .. }, None) =>
+                        Err(SessionError::NoExpectedStateArrived),
+                    (other, _) =>
+                        Err(SessionError::UnexpectedSetupOrMoveRep(other)),
+                },
+            (Phase::Suspended, _) | (Phase::Stopped, _) =>
+                Err(SessionError::Finished),
+        }
+    }
+}
+
+fn build_observed<GB, O>(gs_builder: GB, setup: Setup, seed: Seed, observer: &mut O) -> (GB::GameState, Req)
+    where GB: GameStateBuilder, O: Observer
+{
+    observer.on_setup(&setup);
+    let started = Instant::now();
+    let mut game_state = gs_builder.build(setup, seed);
+    let req = Req::Ready { punter: game_state.get_punter(), futures: game_state.get_futures(), };
+    observer.on_ready(&req, started.elapsed());
+    (game_state, req)
+}
+
+fn play_observed<GS, O>(game_state: GS, moves: Vec<Move>, observer: &mut O) -> Result<(Move, GS), SessionError<GS::Error>>
+    where GS: GameState, O: Observer
+{
+    // moves are consumed by the game state, so the observer sees them beforehand
+    observer.on_moves(&moves);
+    let started = Instant::now();
+    let (move_, next_game_state) = game_state.play(moves)
+        .map_err(SessionError::GameState)?;
+    observer.on_round(&move_, started.elapsed());
+    Ok((move_, next_game_state))
+}
+
+// Drives a session until the game is over or, in offline mode, the turn is made.
+fn run<S, FS, SR, FR, RR, GB, O>(
+    mut session: Session<GB>,
     mut fn_state: S,
     mut send_fn: FS,
     mut recv_fn: FR,
-    gs_builder: GB)
-    -> Result<(Vec<Score>, GB::GameState), Error<SR, RR, <GB::GameState as GameState>::Error>>
+    mut observer: O)
+    -> Result<Option<(Vec<Score>, GB::GameState)>, Error<SR, RR, <GB::GameState as GameState>::Error>>
     where FS: FnMut(&mut S, Req, Option<GB::GameState>) -> Result<(), SR>,
           FR: FnMut(&mut S) -> Result<(Rep, Option<GB::GameState>), RR>,
-          GB: GameStateBuilder
+          GB: GameStateBuilder,
+          O: Observer,
 {
-    // P → S {"me" : name}
-    send_fn(&mut fn_state, Req::Handshake { name: name.to_string(), }, None)
+    send_fn(&mut fn_state, session.handshake(), None)
         .map_err(Error::Send)?;
-    // S → P {"you" : name}
-    match recv_fn(&mut fn_state).map_err(Error::Recv)? {
-        (Rep::Handshake { name: ref rep_name, }, _) if rep_name == name =>
-            (),
-        (other, _) =>
-            return Err(Error::UnexpectedHandshakeRep(other)),
-    }
-    // S → P {"punter" : p, "punters" : n, "map" : map}
-    let mut game_state =
-        match recv_fn(&mut fn_state).map_err(Error::Recv)? {
-            (Rep::Setup(setup), _) =>
-                gs_builder.build(setup),
-            (other, _) =>
-                return Err(Error::UnexpectedSetupRep(other)),
-        };
-    // P → S {"ready" : p}
-    send_fn(&mut fn_state, Req::Ready { punter: game_state.get_punter(), futures: game_state.get_futures(), }, None)
-        .map_err(Error::Send)?;
-    // gameplay
     loop {
-        // S → P {"move" : {"moves" : moves}}
-        // S → P {"stop" : {"moves" : moves,"scores" : scores}}
-        match recv_fn(&mut fn_state).map_err(Error::Recv)? {
-            (Rep::Move { moves, }, _) => {
-                let (move_, next_game_state) = game_state.play(moves)
-                    .map_err(Error::GameState)?;
-                game_state = next_game_state;
-                send_fn(&mut fn_state, Req::Move(move_), None).map_err(Error::Send)?;
+        let (rep, maybe_state) = recv_fn(&mut fn_state)
+            .map_err(Error::Recv)?;
+        match session.step_observed(rep, maybe_state, &mut observer)? {
+            Step::Wait =>
+                (),
+            Step::Reply(req, maybe_state) => {
+                send_fn(&mut fn_state, req, maybe_state)
+                    .map_err(Error::Send)?;
+                if session.state() == State::Suspended {
+                    return Ok(None);
+                }
             },
-            (Rep::Stop { scores, moves, }, _) =>
-                return Ok((scores, game_state.stop(moves).map_err(Error::GameState)?)),
-            (other, _) =>
-                return Err(Error::UnexpectedMoveRep(other)),
+            Step::Stop(scores, game_state) =>
+                return Ok(Some((scores, game_state))),
         }
+    }
+}
 
+pub fn run_online<S, FS, SR, FR, RR, GB, O>(
+    name: &str,
+    fn_state: S,
+    send_fn: FS,
+    recv_fn: FR,
+    gs_builder: GB,
+    seed: Seed,
+    observer: O)
+    -> Result<(Vec<Score>, GB::GameState), Error<SR, RR, <GB::GameState as GameState>::Error>>
+    where FS: FnMut(&mut S, Req, Option<GB::GameState>) -> Result<(), SR>,
+          FR: FnMut(&mut S) -> Result<(Rep, Option<GB::GameState>), RR>,
+          GB: GameStateBuilder,
+          O: Observer,
+{
+    match run(Session::new(Mode::Online, name, gs_builder, seed), fn_state, send_fn, recv_fn, observer)? {
+        Some(result) =>
+            Ok(result),
+        None =>
+            unreachable!("online session is never suspended"),
     }
 }
 
-pub fn run_offline<S, FS, SR, FR, RR, GB>(
+pub fn run_offline<S, FS, SR, FR, RR, GB, O>(
     name: &str,
-    mut fn_state: S,
-    mut send_fn: FS,
-    mut recv_fn: FR,
-    gs_builder: GB)
+    fn_state: S,
+    send_fn: FS,
+    recv_fn: FR,
+    gs_builder: GB,
+    seed: Seed,
+    observer: O)
     -> Result<Option<(Vec<Score>, GB::GameState)>, Error<SR, RR, <GB::GameState as GameState>::Error>>
     where FS: FnMut(&mut S, Req, Option<GB::GameState>) -> Result<(), SR>,
           FR: FnMut(&mut S) -> Result<(Rep, Option<GB::GameState>), RR>,
-          GB: GameStateBuilder
+          GB: GameStateBuilder,
+          O: Observer,
 {
-    // P → S {"me" : name}
-    send_fn(&mut fn_state, Req::Handshake { name: name.to_string(), }, None)
-        .map_err(Error::Send)?;
-    // S → P {"you" : name}
-    match recv_fn(&mut fn_state).map_err(Error::Recv)? {
-        (Rep::Handshake { name: ref rep_name, }, None) if rep_name == name =>
-            (),
-        (Rep::Handshake { .. }, Some(..)) =>
-            return Err(Error::UnexpectedStateArrived),
-        (other, _) =>
-            return Err(Error::UnexpectedHandshakeRep(other)),
-    }
-    match recv_fn(&mut fn_state).map_err(Error::Recv)? {
-        // S → P {"punter" : p, "punters" : n, "map" : map}
-        (Rep::Setup(setup), None) => {
-            let mut game_state = gs_builder.build(setup);
-            // P → S {"ready" : p, "state" : state}
-            send_fn(&mut fn_state, Req::Ready { punter: game_state.get_punter(), futures: game_state.get_futures(), }, Some(game_state))
-                .map_err(Error::Send)
-                .map(|()| None)
-        },
-        (Rep::Setup(..), Some(..)) =>
-            Err(Error::UnexpectedStateArrived),
-        // S → P {"move" : {"moves" : moves},"state" : state}
-        (Rep::Move { moves, }, Some(game_state)) => {
-            let (move_, next_game_state) = game_state.play(moves)
-                .map_err(Error::GameState)?;
-            send_fn(&mut fn_state, Req::Move(move_), Some(next_game_state))
-                .map_err(Error::Send)
-                .map(|()| None)
-        },
-        (Rep::Move { .. }, None) =>
-            Err(Error::NoExpectedStateArrived),
-        // S → P {"stop" : {"moves" : moves,"scores" : scores},"state" : state}
-        (Rep::Stop { scores, moves, }, Some(game_state)) =>
-            Ok(Some((scores, game_state.stop(moves).map_err(Error::GameState)?))),
-        (Rep::Stop { .. }, None) =>
-            Err(Error::NoExpectedStateArrived),
-        (other, _) =>
-            return Err(Error::UnexpectedSetupOrMoveRep(other)),
-    }
+    run(Session::new(Mode::Offline, name, gs_builder, seed), fn_state, send_fn, recv_fn, observer)
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Error, run_online};
+    use std::time::Duration;
+    use super::{Error, Session, SessionError, Mode, State, Step, run_online};
+    use super::super::observer::{Observer, Log};
     use super::super::types::PunterId;
-    use super::super::map::{Map, River};
+    use super::super::map::{Map, River, Site};
     use super::super::proto::{Req, Rep, Move, Setup, Score};
     use super::super::game::{GameStateBuilder, GameState};
+    use super::super::rng::Seed;
     use super::super::solvers::always_pass::AlwaysPassGameStateBuilder;
 
     #[test]
@@ -138,14 +326,165 @@ mod test {
                 (),
                 |_, _req, _| Ok::<_, ()>(()),
                 |_| Ok::<_, ()>((Rep::Handshake { name: "bob".to_string(), }, None)),
-                AlwaysPassGameStateBuilder)
+                AlwaysPassGameStateBuilder,
+                0,
+                ())
                 .map(|v| v.0),
-            Err(Error::UnexpectedHandshakeRep(Rep::Handshake { name: "bob".to_string(), })));
+            Err(Error::Session(SessionError::UnexpectedHandshakeRep(Rep::Handshake { name: "bob".to_string(), }))));
+    }
+
+    fn default_setup(punter: PunterId) -> Setup {
+        Setup { punter: punter, punters: 2, map: default_map(), settings: Default::default(), }
+    }
+
+    #[test]
+    fn online_session_steps() {
+        let mut session = Session::new(Mode::Online, "alice", AlwaysPassGameStateBuilder, 0);
+        assert_eq!(session.handshake(), Req::Handshake { name: "alice".to_string(), });
+        assert_eq!(session.state(), State::AwaitingHandshake);
+        match session.step(Rep::Handshake { name: "alice".to_string(), }, None).unwrap() {
+            Step::Wait => (),
+            other => panic!("unexpected step: {:?}", other),
+        }
+        assert_eq!(session.state(), State::AwaitingSetup);
+        match session.step(Rep::Setup(default_setup(1)), None).unwrap() {
+            Step::Reply(Req::Ready { punter: 1, futures: None, }, None) => (),
+            other => panic!("unexpected step: {:?}", other),
+        }
+        assert_eq!(session.state(), State::Playing);
+        for _ in 0 .. 2 {
+            match session.step(Rep::Move { moves: vec![Move::Pass { punter: 0, }], }, None).unwrap() {
+                Step::Reply(Req::Move(Move::Pass { punter: 1, }), None) => (),
+                other => panic!("unexpected step: {:?}", other),
+            }
+        }
+        let scores = vec![Score { punter: 0, score: 1, }, Score { punter: 1, score: 0, }];
+        match session.step(Rep::Stop { moves: vec![], scores: scores, }, None).unwrap() {
+            Step::Stop(ref scores, _) if scores[0].score == 1 => (),
+            other => panic!("unexpected step: {:?}", other),
+        }
+        assert_eq!(session.state(), State::Stopped);
+        match session.step(Rep::Move { moves: vec![], }, None) {
+            Err(SessionError::Finished) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn offline_session_steps() {
+        let handshake = || Rep::Handshake { name: "bob".to_string(), };
+        // setup turn hands the state over
+        let mut session = Session::new(Mode::Offline, "bob", AlwaysPassGameStateBuilder, 0);
+        assert!(session.step(handshake(), None).is_ok());
+        let game_state = match session.step(Rep::Setup(default_setup(0)), None).unwrap() {
+            Step::Reply(Req::Ready { punter: 0, .. }, Some(game_state)) => game_state,
+            other => panic!("unexpected step: {:?}", other),
+        };
+        assert_eq!(session.state(), State::Suspended);
+        // move turn starts with the state handed back
+        let mut session = Session::new(Mode::Offline, "bob", AlwaysPassGameStateBuilder, 0);
+        assert!(session.step(handshake(), None).is_ok());
+        let game_state = match session.step(Rep::Move { moves: vec![], }, Some(game_state)).unwrap() {
+            Step::Reply(Req::Move(Move::Pass { punter: 0, }), Some(game_state)) => game_state,
+            other => panic!("unexpected step: {:?}", other),
+        };
+        assert_eq!(session.state(), State::Suspended);
+        // timeout notice goes before the move of the same turn
+        let mut events = Events::default();
+        let mut session = Session::new(Mode::Offline, "bob", AlwaysPassGameStateBuilder, 0);
+        assert!(session.step(handshake(), None).is_ok());
+        match session.step_observed(Rep::Timeout(1.0), None, &mut events) {
+            Ok(Step::Wait) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match session.step_observed(Rep::Move { moves: vec![], }, Some(game_state), &mut events).unwrap() {
+            Step::Reply(Req::Move(Move::Pass { punter: 0, }), Some(..)) => (),
+            other => panic!("unexpected step: {:?}", other),
+        }
+        assert_eq!(events.0, vec!["timeout 1".to_string(), "moves 0".to_string(), "round Pass { punter: 0 }".to_string()]);
+        // move without a state
+        let mut session = Session::new(Mode::Offline, "bob", AlwaysPassGameStateBuilder, 0);
+        assert!(session.step(handshake(), None).is_ok());
+        match session.step(Rep::Move { moves: vec![], }, None) {
+            Err(SessionError::NoExpectedStateArrived) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(session.state(), State::Stopped);
+    }
+
+    #[derive(Default)]
+    struct Events(Vec<String>);
+
+    impl Observer for Events {
+        fn on_handshake(&mut self, name: &str) {
+            self.0.push(format!("handshake {}", name));
+        }
+
+        fn on_setup(&mut self, setup: &Setup) {
+            self.0.push(format!("setup {}", setup.punter));
+        }
+
+        fn on_ready(&mut self, ready: &Req, _build_time: Duration) {
+            self.0.push(format!("ready {:?}", ready));
+        }
+
+        fn on_moves(&mut self, moves: &[Move]) {
+            self.0.push(format!("moves {}", moves.len()));
+        }
+
+        fn on_round(&mut self, reply: &Move, _play_time: Duration) {
+            self.0.push(format!("round {:?}", reply));
+        }
+
+        fn on_timeout(&mut self, timeout_secs: f64) {
+            self.0.push(format!("timeout {}", timeout_secs));
+        }
+
+        fn on_stop(&mut self, _moves: &[Move], scores: &[Score]) {
+            self.0.push(format!("stop {}", scores.len()));
+        }
+    }
+
+    #[test]
+    fn observed_game() {
+        let mut reps = vec![
+            Rep::Handshake { name: "alice".to_string(), },
+            Rep::Setup(default_setup(1)),
+            Rep::Move { moves: vec![Move::Pass { punter: 0, }, Move::Pass { punter: 1, }], },
+            Rep::Timeout(1.0),
+            Rep::Move { moves: vec![Move::Pass { punter: 0, }, Move::Pass { punter: 1, }], },
+            Rep::Stop { moves: vec![], scores: vec![Score { punter: 0, score: 0, }, Score { punter: 1, score: 0, }], },
+        ];
+        reps.reverse();
+        let mut events = Events::default();
+        let mut log = Log::default();
+        let mut sent = 0;
+        run_online(
+            "alice",
+            (),
+            |_, _req, _| { sent += 1; Ok::<_, ()>(()) },
+            |_| Ok::<_, ()>((reps.pop().unwrap(), None)),
+            AlwaysPassGameStateBuilder,
+            0,
+            (&mut events, &mut log))
+            .unwrap();
+        assert_eq!(sent, 4);
+        assert_eq!(events.0, vec![
+            "handshake alice".to_string(),
+            "setup 1".to_string(),
+            "ready Ready { punter: 1, futures: None }".to_string(),
+            "moves 2".to_string(),
+            "round Pass { punter: 1 }".to_string(),
+            "timeout 1".to_string(),
+            "moves 2".to_string(),
+            "round Pass { punter: 1 }".to_string(),
+            "stop 2".to_string(),
+        ]);
     }
 
     fn default_map() -> Map {
         let mut map = Map {
-            sites: vec![4, 1, 3, 6, 5, 0, 7, 2],
+            sites: vec![4, 1, 3, 6, 5, 0, 7, 2].into_iter().map(Site::new).collect(),
             mines: vec![1, 5],
             ..Default::default()
         };
@@ -314,7 +653,7 @@ mod test {
         impl GameStateBuilder for ScriptGameStateBuilder {
             type GameState = ScriptGameState;
 
-            fn build(self, setup: Setup) -> Self::GameState {
+            fn build(self, setup: Setup, _seed: Seed) -> Self::GameState {
                 ScriptGameState {
                     punter: setup.punter,
                     script: self.0,
@@ -362,7 +701,9 @@ mod test {
             } else {
                 Err(RepsStackIsEmpty)
             },
-            ScriptGameStateBuilder(gs_script))
+            ScriptGameStateBuilder(gs_script),
+            0,
+            ())
             .unwrap();
         assert_eq!(final_score, expected_score);
         assert_eq!(final_state.script, vec![]);
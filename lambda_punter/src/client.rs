@@ -1,25 +1,61 @@
-use std::{io, net, str, iter, num};
-use std::io::{Read, Write};
-use std::net::ToSocketAddrs;
+use std::{io, net, str, cmp, fmt, thread};
+use std::borrow::Cow;
+use std::io::{Read, Write, BufRead, BufReader};
+use std::net::{ToSocketAddrs, SocketAddr};
+use std::time::Duration;
 use serde::ser::Serialize;
-use serde::de::DeserializeOwned;
+use serde::de::{self, Deserialize, Deserializer, DeserializeOwned};
+use serde_json::{self, Value};
 use super::game::{GameStateBuilder, GameState};
-use super::proto::{self, Score, Req, Rep};
+use super::rng::Seed;
+use super::proto::{self, Score, Req, Rep, DecodeMode};
+use super::codec;
 use super::chat;
+use super::observer::Observer;
+use super::transport::{self, Transport};
 
 type ChatError<GE> = chat::Error<SendError, RecvError, GE>;
 
 #[derive(Debug)]
 pub enum Error<GE> {
     Chat(ChatError<GE>),
+    Resolve(io::Error),
     Connect(io::Error),
+    ConnectTimeout,
+    ReadTimeout,
+    IdleTimeout,
+    // connection is dropped before anything arrived from the server
+    HandshakeUnanswered(RecvError),
+}
+
+impl<GE> Error<GE> {
+    // Server is unreachable or stalled, so it makes sense to try another one.
+    pub fn is_network_failure(&self) -> bool {
+        matches!(*self,
+                 Error::Resolve(..) | Error::Connect(..) | Error::ConnectTimeout | Error::ReadTimeout | Error::IdleTimeout |
+                 Error::HandshakeUnanswered(..))
+    }
+}
+
+impl<GE> From<ChatError<GE>> for Error<GE> {
+    fn from(e: ChatError<GE>) -> Error<GE> {
+        match e {
+            chat::Error::Recv(RecvError::ReadTimeout) =>
+                Error::ReadTimeout,
+            chat::Error::Recv(RecvError::IdleTimeout) =>
+                Error::IdleTimeout,
+            other =>
+                Error::Chat(other),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum SendError {
-    WriteLen(io::Error),
     WritePacket(io::Error),
+    Flush(io::Error),
     PacketEncode(proto::Error),
+    StateEncode(codec::Error),
 }
 
 #[derive(Debug)]
@@ -28,142 +64,973 @@ pub enum RecvError {
     ReadLenTooBig(usize),
     ReadUnexpectedClose,
     LenEmpty,
-    LenString(str::Utf8Error),
-    LenParse(num::ParseIntError),
+    LenInvalidByte(u8),
+    LenOverflow,
     ReadPacket(io::Error),
     ReadPacketNotEnough { want_bytes: usize, received_bytes: usize, },
     PacketString(str::Utf8Error),
     PacketDecode(proto::Error),
+    StateDecode(codec::Error),
     UnexpectedStateArrived,
+    SetTimeout(io::Error),
+    ReadTimeout,
+    IdleTimeout,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectParams {
+    // per connection attempt, `None` means system default
+    pub timeout: Option<Duration>,
+    pub attempts: usize,
+    // pause after the first failed attempt, doubled after every next one up to `backoff_max`
+    pub backoff_initial: Duration,
+    pub backoff_max: Duration,
+}
+
+impl Default for ConnectParams {
+    fn default() -> ConnectParams {
+        ConnectParams {
+            timeout: Some(Duration::from_secs(10)),
+            attempts: 5,
+            backoff_initial: Duration::from_millis(500),
+            backoff_max: Duration::from_secs(8),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Timeouts {
+    // how long a single read may block while a packet is arriving
+    pub read: Option<Duration>,
+    // how long to wait for the first byte of the next server packet
+    pub idle: Option<Duration>,
 }
 
-pub fn run_network<A, GB>(addr: A, name: &str, gs_builder: GB) -> Result<(Vec<Score>, GB::GameState), Error<<GB::GameState as GameState>::Error>>
-    where A: ToSocketAddrs,
+// How server packets are read, see `Framed` for details.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ReadParams {
+    // should be left unset for transports not supporting read timeouts (like stdio)
+    pub timeouts: Timeouts,
+    pub max_frame_len: usize,
+    pub decode_mode: DecodeMode,
+}
+
+impl Default for ReadParams {
+    fn default() -> ReadParams {
+        ReadParams {
+            timeouts: Timeouts::default(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            decode_mode: DecodeMode::Lenient,
+        }
+    }
+}
+
+// Connects to the first reachable address given, retrying with a backoff as configured in `params`.
+pub fn connect_tcp<A, GE>(addr: A, params: &ConnectParams) -> Result<net::TcpStream, Error<GE>> where A: ToSocketAddrs {
+    let addrs: Vec<_> = addr.to_socket_addrs()
+        .map_err(Error::Resolve)?
+        .collect();
+    let mut backoff = params.backoff_initial;
+    let mut attempt = 1;
+    loop {
+        match connect_any(&addrs, params.timeout) {
+            Ok(stream) =>
+                return Ok(stream),
+            Err(ref e) if attempt >= params.attempts && transport::is_timeout(e) =>
+                return Err(Error::ConnectTimeout),
+            Err(e) if attempt >= params.attempts =>
+                return Err(Error::Connect(e)),
+            Err(e) =>
+                warn!("connect attempt {} of {} to {:?} failed: {:?}, retrying in {:?}", attempt, params.attempts, addrs, e, backoff),
+        }
+        thread::sleep(backoff);
+        backoff = cmp::min(backoff * 2, params.backoff_max);
+        attempt += 1;
+    }
+}
+
+fn connect_any(addrs: &[SocketAddr], timeout: Option<Duration>) -> io::Result<net::TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+    for addr in addrs {
+        let connect_result = match timeout {
+            Some(timeout) =>
+                net::TcpStream::connect_timeout(addr, timeout),
+            None =>
+                net::TcpStream::connect(addr),
+        };
+        match connect_result {
+            Ok(stream) =>
+                return Ok(stream),
+            Err(e) =>
+                last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+pub fn run_network<T, GB, O>(transport: T, params: ReadParams, name: &str, gs_builder: GB, seed: Seed, observer: O) -> Result<(Vec<Score>, GB::GameState), Error<<GB::GameState as GameState>::Error>>
+    where T: Transport,
           GB: GameStateBuilder,
+          O: Observer,
 {
-    let tcp = net::TcpStream::connect(addr)
-        .map_err(Error::Connect)?;
+    debug!("playing online over {} with {:?}", transport.describe(), params);
+    let mut framed = Framed::new(transport).params(params);
+    let result = chat::run_online(
+        name,
+        &mut framed,
+        generic_write,
+        |framed: &mut &mut Framed<T>| generic_read(framed),
+        gs_builder,
+        seed,
+        observer);
+    match result {
+        Ok(result) =>
+            Ok(result),
+        // a server accepting connections it is not ready to serve is as good as unreachable
+        Err(chat::Error::Recv(e @ RecvError::ReadUnexpectedClose)) | Err(chat::Error::Recv(e @ RecvError::ReadLen(..))) if framed.frames_read == 0 =>
+            Err(Error::HandshakeUnanswered(e)),
+        Err(e) =>
+            Err(Error::from(e)),
+    }
+}
 
-    chat::run_online(name, tcp, generic_write, generic_read, gs_builder)
+pub fn run_offline<T, GB, O>(transport: T, params: ReadParams, name: &str, gs_builder: GB, seed: Seed, observer: O) -> Result<Option<(Vec<Score>, GB::GameState)>, Error<<GB::GameState as GameState>::Error>>
+    where T: Transport,
+          GB: GameStateBuilder,
+          O: Observer,
+{
+    debug!("playing offline over {} with {:?}", transport.describe(), params);
+    let framed = Framed::new(transport).params(params);
+    chat::run_offline(name, framed, generic_write, generic_read, gs_builder, seed, observer)
         .map_err(Error::Chat)
 }
 
-pub fn run_offline<GB>(name: &str, gs_builder: GB) -> Result<Option<(Vec<Score>, GB::GameState)>, Error<<GB::GameState as GameState>::Error>>
-    where GB: GameStateBuilder,
-{
-    struct Stdio;
+// large enough for most of setup messages to arrive in a few reads
+const FRAMED_BUFFER_SIZE: usize = 64 * 1024;
+// the largest setup known is about 150 kb, so this leaves a lot of room
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
 
-    impl Read for Stdio {
-        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            let s = io::stdin();
-            let mut l = s.lock();
-            l.read(buf)
-        }
+// Framing layer over a duplex stream: reads are buffered, writes go directly to the stream.
+pub struct Framed<S> {
+    stream: BufReader<S>,
+    max_frame_len: usize,
+    decode_mode: DecodeMode,
+    deadlines: Option<Deadlines<S>>,
+    frames_read: usize,
+}
 
-        fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
-            let s = io::stdin();
-            let mut l = s.lock();
-            l.read_to_end(buf)
-        }
+struct Deadlines<S> {
+    timeouts: Timeouts,
+    set_read_timeout: fn(&mut S, Option<Duration>) -> io::Result<()>,
+    current: Option<Duration>,
+}
+
+impl<S> Framed<S> where S: Read {
+    pub fn new(stream: S) -> Framed<S> {
+        Framed::with_capacity(FRAMED_BUFFER_SIZE, stream)
     }
 
-    impl Write for Stdio {
-        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-            let s = io::stdout();
-            let mut l = s.lock();
-            let b = l.write(buf)?;
-            l.flush()?;
-            Ok(b)
+    pub fn with_capacity(capacity: usize, stream: S) -> Framed<S> {
+        Framed {
+            stream: BufReader::with_capacity(capacity, stream),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            decode_mode: DecodeMode::Lenient,
+            deadlines: None,
+            frames_read: 0,
         }
+    }
 
-        fn flush(&mut self) -> io::Result<()> {
-            let s = io::stdout();
-            let mut l = s.lock();
-            l.flush()
+    // Received packets announcing a larger length are rejected before anything is allocated for them.
+    pub fn max_frame_len(mut self, max_frame_len: usize) -> Framed<S> {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    pub fn decode_mode(mut self, decode_mode: DecodeMode) -> Framed<S> {
+        self.decode_mode = decode_mode;
+        self
+    }
+
+    // Packets are awaited for no longer than `timeouts.idle`, and then read with no single read blocking
+    // longer than `timeouts.read`.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Framed<S> where S: Transport {
+        self.deadlines = Some(Deadlines {
+            timeouts: timeouts,
+            set_read_timeout: S::set_read_timeout,
+            current: None,
+        });
+        self
+    }
+
+    pub fn params(self, params: ReadParams) -> Framed<S> where S: Transport {
+        self.max_frame_len(params.max_frame_len)
+            .decode_mode(params.decode_mode)
+            .timeouts(params.timeouts)
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.stream.get_ref()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        if let Some(ref mut deadlines) = self.deadlines {
+            if deadlines.current != timeout {
+                (deadlines.set_read_timeout)(self.stream.get_mut(), timeout)?;
+                deadlines.current = timeout;
+            }
         }
+        Ok(())
     }
+}
 
-    chat::run_offline(name, Stdio, generic_write, generic_read, gs_builder)
-        .map_err(Error::Chat)
+impl<S> Read for Framed<S> where S: Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S> BufRead for Framed<S> where S: Read {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.stream.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.stream.consume(amt)
+    }
+}
+
+impl<S> Write for Framed<S> where S: Read + Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.get_mut().flush()
+    }
 }
 
 fn generic_write<W, S>(writer: &mut W, req: Req, maybe_state: Option<S>) -> Result<(), SendError>
     where W: Write, S: Serialize
 {
-    let encoded_req = req.to_json(maybe_state)
+    // state goes as compact binary blob in place of a plain json
+    let maybe_encoded_state = if let Some(state) = maybe_state {
+        let (encoded_state, stats) = codec::encode_state(&state)
+            .map_err(SendError::StateEncode)?;
+        info!("state size: {} bytes binary, {} bytes compressed, {} bytes encoded",
+              stats.binary_bytes, stats.compressed_bytes, stats.encoded_bytes);
+        Some(encoded_state)
+    } else {
+        None
+    };
+    let encoded_req = req.to_json(maybe_encoded_state)
         .map_err(SendError::PacketEncode)?;
-    let length_req = format!("{}:", encoded_req.as_bytes().len());
-    debug!("P -> S | {}{}", length_req, encoded_req);
-    writer.write_all(length_req.as_bytes())
-        .map_err(SendError::WriteLen)?;
-    writer.write_all(encoded_req.as_bytes())
-        .map_err(SendError::WritePacket)
+    let packet = format!("{}:{}", encoded_req.len(), encoded_req);
+    debug!("P -> S | {}", packet);
+    // length and payload go within a single write
+    writer.write_all(packet.as_bytes())
+        .map_err(SendError::WritePacket)?;
+    writer.flush()
+        .map_err(SendError::Flush)
 }
 
-fn generic_read<R, S>(reader: &mut R) -> Result<(Rep, Option<S>), RecvError>
-    where R: Read, S: DeserializeOwned
-{
-    let mut packet = Vec::with_capacity(9);
+// Waits for the next packet to start arriving within the idle timeout, then switches to the read timeout.
+fn await_frame<R>(reader: &mut Framed<R>) -> Result<(), RecvError> where R: Read {
+    let timeouts = match reader.deadlines {
+        Some(ref deadlines) =>
+            deadlines.timeouts,
+        None =>
+            return Ok(()),
+    };
+    reader.set_read_timeout(timeouts.idle)
+        .map_err(RecvError::SetTimeout)?;
     loop {
-        let mut byte = [0; 1];
-        match reader.read(&mut byte) {
-            Ok(0) =>
-                return Err(RecvError::ReadUnexpectedClose),
-            Ok(1) if byte[0] == b':' =>
+        match reader.fill_buf() {
+            Ok(..) =>
                 break,
-            Ok(1) =>
-                packet.push(byte[0]),
-            Ok(_) =>
-                unreachable!(),
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted =>
                 continue,
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
-                continue,
+            Err(ref e) if transport::is_timeout(e) =>
+                return Err(RecvError::IdleTimeout),
             Err(e) =>
                 return Err(RecvError::ReadLen(e)),
         }
     }
-    if packet.is_empty() {
-        Err(RecvError::LenEmpty)
-    } else {
-        let len: usize = str::from_utf8(&packet)
-            .map_err(RecvError::LenString)?
-        .parse()
-            .map_err(RecvError::LenParse)?;
-        packet.clear();
-        packet.extend(iter::repeat(0).take(len));
-        let received_bytes = {
-            let mut buf: &mut [_] = &mut packet;
-            while !buf.is_empty() {
-                match reader.read(buf) {
-                    Ok(0) =>
-                        break,
-                    Ok(n) => {
-                        let tmp = buf;
-                        buf = &mut tmp[n..];
+    reader.set_read_timeout(timeouts.read)
+        .map_err(RecvError::SetTimeout)
+}
+
+// Length prefix is a plain decimal number followed by a colon, anything else is rejected as soon as it arrives.
+fn read_frame_len<R>(reader: &mut R, max_frame_len: usize) -> Result<usize, RecvError> where R: BufRead {
+    let mut len: usize = 0;
+    let mut digits = 0;
+    loop {
+        let (consumed, complete) = {
+            let buf = match reader.fill_buf() {
+                Ok(buf) =>
+                    buf,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted =>
+                    continue,
+                Err(ref e) if transport::is_timeout(e) =>
+                    return Err(RecvError::ReadTimeout),
+                Err(e) =>
+                    return Err(RecvError::ReadLen(e)),
+            };
+            if buf.is_empty() {
+                return Err(RecvError::ReadUnexpectedClose);
+            }
+            let mut consumed = 0;
+            let mut complete = false;
+            for &byte in buf.iter() {
+                consumed += 1;
+                match byte {
+                    b':' if digits == 0 =>
+                        return Err(RecvError::LenEmpty),
+                    b':' => {
+                        complete = true;
+                        break;
+                    },
+                    b'0' ..= b'9' => {
+                        len = len.checked_mul(10)
+                            .and_then(|len| len.checked_add((byte - b'0') as usize))
+                            .ok_or(RecvError::LenOverflow)?;
+                        if len > max_frame_len {
+                            return Err(RecvError::ReadLenTooBig(len));
+                        }
+                        digits += 1;
                     },
-                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted =>
-                        (),
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
-                        (),
-                    Err(e) =>
-                        return Err(RecvError::ReadPacket(e)),
+                    other =>
+                        return Err(RecvError::LenInvalidByte(other)),
                 }
             }
-            len - buf.len()
+            (consumed, complete)
         };
+        reader.consume(consumed);
+        if complete {
+            return Ok(len);
+        }
+    }
+}
+
+fn generic_read<R, S>(reader: &mut Framed<R>) -> Result<(Rep, Option<S>), RecvError>
+    where R: Read, S: DeserializeOwned
+{
+    await_frame(reader)?;
+    let max_frame_len = reader.max_frame_len;
+    let len = read_frame_len(reader, max_frame_len)?;
+    let mut packet = Vec::new();
+    {
+        let received_bytes = reader.by_ref().take(len as u64).read_to_end(&mut packet)
+            .map_err(|e| if transport::is_timeout(&e) { RecvError::ReadTimeout } else { RecvError::ReadPacket(e) })?;
         if received_bytes != len {
-            Err(RecvError::ReadPacketNotEnough {
+            return Err(RecvError::ReadPacketNotEnough {
                 want_bytes: len,
                 received_bytes: received_bytes,
-            })
-        } else {
-            let packet_str = str::from_utf8(&packet)
-                .map_err(RecvError::PacketString)?;
-            debug!("S -> P | {}:{}", len, packet_str);
-            let (rep, maybe_state) = Rep::from_json(&packet_str)
-                .map_err(RecvError::PacketDecode)?;
-            Ok((rep, maybe_state))
+            });
+        }
+    }
+    let packet_str = str::from_utf8(&packet)
+        .map_err(RecvError::PacketString)?;
+    debug!("S -> P | {}:{}", len, packet_str);
+    let decoded = Rep::decode::<WireState>(packet_str, reader.decode_mode)
+        .map_err(RecvError::PacketDecode)?;
+    decoded.log_warnings();
+    reader.frames_read += 1;
+    let rep = decoded.rep;
+    let maybe_state = match decoded.state {
+        None =>
+            None,
+        Some(WireState::Text(ref encoded)) if codec::is_encoded_state(encoded) =>
+            // decoded state is no larger than the max frame, as it would be if sent as plain json
+            Some(codec::decode_state(encoded, reader.max_frame_len).map_err(RecvError::StateDecode)?),
+        // plain json state is still accepted
+        Some(WireState::Text(text)) =>
+            Some(serde_json::from_value(Value::String(text.into_owned())).map_err(|e| RecvError::PacketDecode(proto::Error::Json(e)))?),
+        Some(WireState::Json(value)) =>
+            Some(serde_json::from_value(value).map_err(|e| RecvError::PacketDecode(proto::Error::Json(e)))?),
+    };
+    Ok((rep, maybe_state))
+}
+
+// Game state as it arrives: a string (normally an encoded state) is borrowed from the packet,
+// anything else is kept as a json tree.
+enum WireState<'a> {
+    Text(Cow<'a, str>),
+    Json(Value),
+}
+
+impl<'de> Deserialize<'de> for WireState<'de> {
+    fn deserialize<D>(deserializer: D) -> Result<WireState<'de>, D::Error> where D: Deserializer<'de> {
+        struct WireStateVisitor;
+
+        impl<'de> de::Visitor<'de> for WireStateVisitor {
+            type Value = WireState<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a game state")
+            }
+
+            fn visit_borrowed_str<E>(self, s: &'de str) -> Result<WireState<'de>, E> {
+                Ok(WireState::Text(Cow::Borrowed(s)))
+            }
+
+            fn visit_str<E>(self, s: &str) -> Result<WireState<'de>, E> {
+                Ok(WireState::Text(Cow::Owned(s.to_string())))
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<WireState<'de>, E> {
+                Ok(WireState::Json(Value::from(v)))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<WireState<'de>, E> {
+                Ok(WireState::Json(Value::from(v)))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<WireState<'de>, E> {
+                Ok(WireState::Json(Value::from(v)))
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<WireState<'de>, E> {
+                Ok(WireState::Json(Value::from(v)))
+            }
+
+            fn visit_unit<E>(self) -> Result<WireState<'de>, E> {
+                Ok(WireState::Json(Value::Null))
+            }
+
+            fn visit_seq<A>(self, seq: A) -> Result<WireState<'de>, A::Error> where A: de::SeqAccess<'de> {
+                Value::deserialize(de::value::SeqAccessDeserializer::new(seq)).map(WireState::Json)
+            }
+
+            fn visit_map<A>(self, map: A) -> Result<WireState<'de>, A::Error> where A: de::MapAccess<'de> {
+                Value::deserialize(de::value::MapAccessDeserializer::new(map)).map(WireState::Json)
+            }
+        }
+
+        deserializer.deserialize_any(WireStateVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net, thread, usize};
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::io::{self, Read, Write, Cursor};
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+    use std::fs::File;
+    use rand::Rng;
+    use super::super::rng;
+    use super::super::chat;
+    use super::super::map::RiversIndex;
+    use super::super::codec;
+    use super::super::proto::{self, Req, Rep, Move, DecodeMode};
+    use super::super::solvers::always_pass::AlwaysPassGameStateBuilder;
+    use super::super::transport::{self, Transport};
+    use super::{Framed, RecvError, Error, Timeouts, ReadParams, ConnectParams, DEFAULT_MAX_FRAME_LEN};
+    use super::{generic_write, generic_read, read_frame_len, connect_tcp, run_network, run_offline};
+
+    fn frame(body: &str) -> Vec<u8> {
+        format!("{}:{}", body.len(), body).into_bytes()
+    }
+
+    fn packet(body: &str) -> Framed<Cursor<Vec<u8>>> {
+        Framed::new(Cursor::new(frame(body)))
+    }
+
+    fn raw(bytes: &[u8]) -> Framed<Cursor<Vec<u8>>> {
+        Framed::new(Cursor::new(bytes.to_owned()))
+    }
+
+    fn setup_packet(map_name: &str) -> Vec<u8> {
+        let mut map_json = String::new();
+        File::open(format!("{}/../maps/{}.json", env!("CARGO_MANIFEST_DIR"), map_name))
+            .and_then(|mut f| f.read_to_string(&mut map_json))
+            .unwrap();
+        let body = format!(r#"{{"punter":0,"punters":2,"map":{}}}"#, map_json);
+        format!("{}:{}", body.len(), body).into_bytes()
+    }
+
+    // counts calls to the underlying stream, which are syscalls for sockets and stdio
+    struct Counting<S> {
+        stream: S,
+        reads: usize,
+        writes: usize,
+    }
+
+    impl<S> Counting<S> {
+        fn new(stream: S) -> Counting<S> {
+            Counting { stream: stream, reads: 0, writes: 0, }
+        }
+    }
+
+    impl<S> Read for Counting<S> where S: Read {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.stream.read(buf)
+        }
+    }
+
+    impl<S> Write for Counting<S> where S: Write {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.stream.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    #[test]
+    fn single_write() {
+        let mut framed = Framed::new(Counting::new(Cursor::new(Vec::new())));
+        generic_write(&mut framed, Req::Move(Move::Pass { punter: 1, }), Some(vec![0; 4096])).unwrap();
+        assert_eq!(framed.get_ref().writes, 1);
+    }
+
+    #[test]
+    fn buffered_read() {
+        let packet = setup_packet("nara-sparse");
+        let packet_len = packet.len();
+        let mut framed = Framed::new(Counting::new(Cursor::new(packet)));
+        let (rep, state) = generic_read::<_, ()>(&mut framed).unwrap();
+        match rep {
+            Rep::Setup(..) =>
+                (),
+            other =>
+                panic!("unexpected rep: {:?}", other),
+        }
+        assert_eq!(state, None);
+        // whole buffers except the tail and the final eof check
+        assert!(framed.get_ref().reads <= packet_len / (64 * 1024) + 2);
+    }
+
+    #[test]
+    fn sequential_packets() {
+        let mut stream = Vec::new();
+        stream.extend(frame(r#"{"you":"alice"}"#));
+        stream.extend(frame(r#"{"move":{"moves":[{"pass":{"punter":1}}]}}"#));
+        let mut framed = Framed::with_capacity(7, Cursor::new(stream));
+        assert_eq!(generic_read::<_, ()>(&mut framed).unwrap().0, Rep::Handshake { name: "alice".to_string(), });
+        assert_eq!(generic_read::<_, ()>(&mut framed).unwrap().0, Rep::Move { moves: vec![Move::Pass { punter: 1, }], });
+        assert!(generic_read::<_, ()>(&mut framed).is_err());
+    }
+
+    // makes every read return no more than `chunk` bytes, like a socket with data arriving gradually
+    struct Chunked<S> {
+        stream: S,
+        chunk: usize,
+    }
+
+    impl<S> Read for Chunked<S> where S: Read {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.chunk);
+            self.stream.read(&mut buf[.. len])
+        }
+    }
+
+    // framing as it was before buffering: length prefix is read byte by byte, then the payload in whole
+    fn read_frame_unbuffered<R>(reader: &mut R) -> Vec<u8> where R: Read {
+        let mut len = 0;
+        loop {
+            let mut byte = [0; 1];
+            reader.read_exact(&mut byte).unwrap();
+            if byte[0] == b':' {
+                break;
+            }
+            len = len * 10 + (byte[0] - b'0') as usize;
+        }
+        let mut packet = vec![0; len];
+        reader.read_exact(&mut packet).unwrap();
+        packet
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_buffered_read() {
+        use std::time::Instant;
+        const ROUNDS: u32 = 100;
+        // Only framing is measured, as decoding is the same for both paths. Buffering saves a read per
+        // length prefix digit (6 reads for the maps here), while payload reads stay about the same. In-memory
+        // reads are nearly free, so the time shows no gain (the buffer setup costs more than the reads saved),
+        // it is the read count that matters for sockets and stdio.
+        for map_name in ["randomMedium", "tube", "edinburgh-sparse", "nara-sparse"].iter() {
+            let packet = setup_packet(map_name);
+            for &chunk in [usize::MAX, 4096].iter() {
+                let (mut unbuffered_reads, mut buffered_reads) = (0, 0);
+                let now = Instant::now();
+                for _ in 0 .. ROUNDS {
+                    let mut reader = Counting::new(Chunked { stream: Cursor::new(&packet[..]), chunk: chunk, });
+                    read_frame_unbuffered(&mut reader);
+                    unbuffered_reads = reader.reads;
+                }
+                let unbuffered_time = now.elapsed() / ROUNDS;
+                let now = Instant::now();
+                for _ in 0 .. ROUNDS {
+                    let mut framed = Framed::new(Counting::new(Chunked { stream: Cursor::new(&packet[..]), chunk: chunk, }));
+                    let len = read_frame_len(&mut framed, DEFAULT_MAX_FRAME_LEN).unwrap();
+                    let mut body = Vec::new();
+                    framed.by_ref().take(len as u64).read_to_end(&mut body).unwrap();
+                    buffered_reads = framed.get_ref().reads;
+                }
+                let buffered_time = now.elapsed() / ROUNDS;
+                println!("{}: {} bytes in chunks of {}: unbuffered {} reads in {:?}, buffered {} reads in {:?}",
+                         map_name, packet.len(), if chunk == usize::MAX { "any size".to_string() } else { chunk.to_string() },
+                         unbuffered_reads, unbuffered_time, buffered_reads, buffered_time);
+            }
+        }
+    }
+
+    #[test]
+    fn write_binary_state() {
+        let mut buf = Vec::new();
+        generic_write(&mut buf, Req::Move(Move::Pass { punter: 1, }), Some(vec![1, 2, 3])).unwrap();
+        let (encoded, _) = codec::encode_state(&vec![1, 2, 3]).unwrap();
+        let body = format!(r#"{{"pass":{{"punter":1}},"state":"{}"}}"#, encoded);
+        assert_eq!(String::from_utf8(buf).unwrap(), format!("{}:{}", body.len(), body));
+    }
+
+    #[test]
+    fn read_binary_and_json_state() {
+        let (encoded, _) = codec::encode_state(&vec![1, 2, 3]).unwrap();
+        let (rep, state) = generic_read::<_, Vec<u8>>(&mut packet(&format!(r#"{{"move":{{"moves":[]}},"state":"{}"}}"#, encoded))).unwrap();
+        assert_eq!(rep, Rep::Move { moves: vec![], });
+        assert_eq!(state, Some(vec![1, 2, 3]));
+        let (_, state) = generic_read::<_, Vec<u8>>(&mut packet(r#"{"move":{"moves":[]},"state":[1,2,3]}"#)).unwrap();
+        assert_eq!(state, Some(vec![1, 2, 3]));
+        assert!(generic_read::<_, RiversIndex<usize>>(&mut packet(r#"{"move":{"moves":[]},"state":"cbor+deflate:AAAA"}"#)).is_err());
+    }
+
+    #[test]
+    fn strict_decode_mode() {
+        let body = r#"{"you":"alice","motd":"welcome"}"#;
+        assert_eq!(generic_read::<_, ()>(&mut packet(body)).unwrap().0, Rep::Handshake { name: "alice".to_string(), });
+        match generic_read::<_, ()>(&mut packet(body).decode_mode(DecodeMode::Strict)) {
+            Err(RecvError::PacketDecode(proto::Error::Decode(ref e))) if e.path == "$.motd" =>
+                (),
+            other =>
+                panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn strict_frame_len() {
+        fn read_err(framed: &mut Framed<Cursor<Vec<u8>>>) -> RecvError {
+            match generic_read::<_, ()>(framed) {
+                Ok((rep, _)) =>
+                    panic!("unexpected rep: {:?}", rep),
+                Err(e) =>
+                    e,
+            }
+        }
+        match read_err(&mut raw(b"")) { RecvError::ReadUnexpectedClose => (), e => panic!("unexpected error: {:?}", e), }
+        match read_err(&mut raw(b"15")) { RecvError::ReadUnexpectedClose => (), e => panic!("unexpected error: {:?}", e), }
+        match read_err(&mut raw(b":{}")) { RecvError::LenEmpty => (), e => panic!("unexpected error: {:?}", e), }
+        match read_err(&mut raw(b" 2:{}")) { RecvError::LenInvalidByte(b' ') => (), e => panic!("unexpected error: {:?}", e), }
+        match read_err(&mut raw(b"+2:{}")) { RecvError::LenInvalidByte(b'+') => (), e => panic!("unexpected error: {:?}", e), }
+        match read_err(&mut raw(b"2x:{}")) { RecvError::LenInvalidByte(b'x') => (), e => panic!("unexpected error: {:?}", e), }
+        match read_err(&mut raw(b"\xd0\xb4:{}")) { RecvError::LenInvalidByte(0xd0) => (), e => panic!("unexpected error: {:?}", e), }
+        match read_err(&mut raw(b"10:{}")) {
+            RecvError::ReadPacketNotEnough { want_bytes: 10, received_bytes: 2, } => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        // a huge length is rejected before the payload is read
+        match read_err(&mut raw(b"99999999999:{}")) { RecvError::ReadLenTooBig(..) => (), e => panic!("unexpected error: {:?}", e), }
+        match read_err(&mut raw(b"15:{\"you\":\"alice\"}").max_frame_len(14)) { RecvError::ReadLenTooBig(15) => (), e => panic!("unexpected error: {:?}", e), }
+        let overflow = format!("{}0:{{}}", usize::MAX);
+        match read_err(&mut raw(overflow.as_bytes()).max_frame_len(usize::MAX)) { RecvError::LenOverflow => (), e => panic!("unexpected error: {:?}", e), }
+        // leading zeros are fine though
+        assert_eq!(generic_read::<_, ()>(&mut raw(b"0015:{\"you\":\"alice\"}").max_frame_len(15)).unwrap().0,
+                   Rep::Handshake { name: "alice".to_string(), });
+    }
+
+    // randomly damages a valid stream: flips, inserts or drops bytes, or cuts it
+    fn mutate<R>(stream: &[u8], rng: &mut R) -> Vec<u8> where R: Rng {
+        let mut mutated = stream.to_owned();
+        for _ in 0 .. rng.gen_range(1, 4) {
+            if mutated.is_empty() {
+                break;
+            }
+            let at = rng.gen_range(0, mutated.len());
+            match rng.gen_range(0, 4) {
+                0 =>
+                    mutated[at] = rng.gen(),
+                1 =>
+                    mutated.insert(at, *rng.choose(b"0123456789:{}\"").unwrap()),
+                2 => {
+                    mutated.remove(at);
+                },
+                _ =>
+                    mutated.truncate(at),
+            }
+        }
+        mutated
+    }
+
+    #[test]
+    fn fuzz_framing() {
+        let mut valid = Vec::new();
+        valid.extend(frame(r#"{"you":"alice"}"#));
+        valid.extend(setup_packet("sample"));
+        valid.extend(frame(r#"{"move":{"moves":[{"claim":{"punter":1,"source":0,"target":1}},{"pass":{"punter":0}}]},"state":{"punter":0}}"#));
+        for i in 0 .. 2000 {
+            let mut rng = rng::make_rng(43, i);
+            let stream = if i % 10 == 0 {
+                // pure garbage
+                let len = rng.gen_range(0, 64);
+                rng.gen_iter().take(len).collect()
+            } else {
+                mutate(&valid, &mut rng)
+            };
+            let mut framed = Framed::with_capacity(rng.gen_range(1, 64), Cursor::new(stream)).max_frame_len(4096);
+            // every frame is either decoded or rejected, and reading stops at the first error
+            for _ in 0 .. 4 {
+                if generic_read::<_, ::serde_json::Value>(&mut framed).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    // server side is scripted, player output is collected
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn duplex(input: Vec<u8>) -> Framed<Duplex> {
+        Framed::new(Duplex { input: Cursor::new(input), output: Vec::new(), }).max_frame_len(4096)
+    }
+
+    #[test]
+    fn fuzz_online_and_offline_chat() {
+        let name = "skobochka";
+        let mut online = Vec::new();
+        online.extend(frame(r#"{"you":"skobochka"}"#));
+        online.extend(setup_packet("sample"));
+        online.extend(frame(r#"{"move":{"moves":[{"pass":{"punter":0}},{"pass":{"punter":1}}]}}"#));
+        online.extend(frame(r#"{"stop":{"moves":[{"pass":{"punter":0}},{"pass":{"punter":1}}],"scores":[{"punter":0,"score":0},{"punter":1,"score":0}]}}"#));
+        let mut offline_setup = Vec::new();
+        offline_setup.extend(frame(r#"{"you":"skobochka"}"#));
+        offline_setup.extend(setup_packet("sample"));
+        let mut offline_move = Vec::new();
+        offline_move.extend(frame(r#"{"you":"skobochka"}"#));
+        offline_move.extend(frame(r#"{"move":{"moves":[{"pass":{"punter":0}},{"pass":{"punter":1}}]},"state":{"punter":0}}"#));
+
+        // valid scripts pass
+        assert!(chat::run_online(name, duplex(online.clone()), generic_write, generic_read, AlwaysPassGameStateBuilder, 0, ()).is_ok());
+        assert!(chat::run_offline(name, duplex(offline_setup.clone()), generic_write, generic_read, AlwaysPassGameStateBuilder, 0, ()).is_ok());
+        assert!(chat::run_offline(name, duplex(offline_move.clone()), generic_write, generic_read, AlwaysPassGameStateBuilder, 0, ()).is_ok());
+
+        // damaged ones never panic
+        for i in 0 .. 500 {
+            let mut rng = rng::make_rng(44, i);
+            let _ = chat::run_online(name, duplex(mutate(&online, &mut rng)), generic_write, generic_read, AlwaysPassGameStateBuilder, 0, ());
+            let _ = chat::run_offline(name, duplex(mutate(&offline_setup, &mut rng)), generic_write, generic_read, AlwaysPassGameStateBuilder, 0, ());
+            let _ = chat::run_offline(name, duplex(mutate(&offline_move, &mut rng)), generic_write, generic_read, AlwaysPassGameStateBuilder, 0, ());
+        }
+    }
+
+    fn read_frame<T>(framed: &mut Framed<T>) -> String where T: Read {
+        let len = read_frame_len(framed, 4096).unwrap();
+        let mut body = vec![0; len];
+        framed.read_exact(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    // plays a server side of a two turns online game, returns what the player has sent
+    fn serve_online<T>(transport: T) -> Vec<String> where T: Transport {
+        let mut server = Framed::new(transport);
+        let mut received = Vec::new();
+        received.push(read_frame(&mut server));
+        server.write_all(&frame(r#"{"you":"skobochka"}"#)).unwrap();
+        server.write_all(&setup_packet("sample")).unwrap();
+        received.push(read_frame(&mut server));
+        server.write_all(&frame(r#"{"move":{"moves":[{"pass":{"punter":0}},{"pass":{"punter":1}}]}}"#)).unwrap();
+        received.push(read_frame(&mut server));
+        server.write_all(&frame(r#"{"stop":{"moves":[{"pass":{"punter":0}},{"pass":{"punter":1}}],"scores":[{"punter":0,"score":3},{"punter":1,"score":5}]}}"#)).unwrap();
+        received
+    }
+
+    fn run_network_over<T, S>(player: T, server: S) where T: Transport, S: Transport + Send + 'static {
+        let server_thread = thread::spawn(move || serve_online(server));
+        let (scores, _) = run_network(player, ReadParams::default(), "skobochka", AlwaysPassGameStateBuilder, 0, ()).unwrap();
+        assert_eq!(scores.iter().map(|s| (s.punter, s.score)).collect::<Vec<_>>(), vec![(0, 3), (1, 5)]);
+        assert_eq!(server_thread.join().unwrap(), vec![
+            r#"{"me":"skobochka"}"#.to_string(),
+            r#"{"ready":0}"#.to_string(),
+            r#"{"pass":{"punter":0}}"#.to_string(),
+        ]);
+    }
+
+    #[test]
+    fn network_over_pipe() {
+        let (player, server) = transport::pipe();
+        run_network_over(player, server);
+    }
+
+    #[test]
+    fn network_over_tcp() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let player = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        run_network_over(player, server);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn network_over_unix_socket() {
+        let (player, server) = UnixStream::pair().unwrap();
+        run_network_over(player, server);
+    }
+
+    #[test]
+    fn offline_over_pipe() {
+        let (player, server) = transport::pipe();
+        let server_thread = thread::spawn(move || {
+            let mut server = Framed::new(server);
+            let handshake = read_frame(&mut server);
+            server.write_all(&frame(r#"{"you":"skobochka"}"#)).unwrap();
+            server.write_all(&setup_packet("sample")).unwrap();
+            (handshake, read_frame(&mut server))
+        });
+        assert!(run_offline(player, ReadParams::default(), "skobochka", AlwaysPassGameStateBuilder, 0, ()).unwrap().is_none());
+        let (handshake, ready) = server_thread.join().unwrap();
+        assert_eq!(handshake, r#"{"me":"skobochka"}"#);
+        assert!(ready.starts_with(r#"{"ready":0,"state":"cbor+deflate:"#));
+    }
+
+    #[test]
+    fn offline_max_frame_len() {
+        let (player, server) = transport::pipe();
+        let server_thread = thread::spawn(move || {
+            let mut server = Framed::new(server);
+            read_frame(&mut server);
+            server.write_all(&frame(r#"{"you":"skobochka"}"#)).unwrap();
+            // the player might be gone before the setup is written
+            server.write_all(&setup_packet("sample")).ok();
+        });
+        let params = ReadParams { max_frame_len: 64, ..Default::default() };
+        match run_offline(player, params, "skobochka", AlwaysPassGameStateBuilder, 0, ()) {
+            Err(Error::Chat(chat::Error::Recv(RecvError::ReadLenTooBig(..)))) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        server_thread.join().unwrap();
+    }
+
+    // server answers the handshake with `reply` and then stalls until the player gives up
+    fn run_network_stalled(reply: Vec<u8>, timeouts: Timeouts) -> Error<()> {
+        let params = ReadParams { timeouts: timeouts, ..Default::default() };
+        let (player, server) = transport::pipe();
+        let (done_tx, done_rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let mut server = Framed::new(server);
+            read_frame(&mut server);
+            server.write_all(&reply).unwrap();
+            done_rx.recv().unwrap();
+        });
+        let result = run_network(player, params, "skobochka", AlwaysPassGameStateBuilder, 0, ());
+        done_tx.send(()).unwrap();
+        server_thread.join().unwrap();
+        match result {
+            Ok(..) =>
+                panic!("game is unexpectedly over"),
+            Err(e) =>
+                e,
+        }
+    }
+
+    #[test]
+    fn network_timeouts() {
+        let timeouts = Timeouts { read: Some(Duration::from_millis(20)), idle: Some(Duration::from_millis(100)), };
+        // no setup ever arrives
+        match run_network_stalled(frame(r#"{"you":"skobochka"}"#), timeouts) {
+            Error::IdleTimeout => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        // setup stalls halfway, which is noticed by the read timeout
+        let mut reply = frame(r#"{"you":"skobochka"}"#);
+        reply.extend(&setup_packet("sample")[.. 100]);
+        match run_network_stalled(reply, timeouts) {
+            Error::ReadTimeout => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        // idle timeout is not applied to the packet already arriving
+        let slow = Timeouts { read: Some(Duration::from_millis(20)), idle: Some(Duration::from_secs(60)), };
+        match run_network_stalled(frame(r#"{"you":"skobochka"}"#)[.. 5].to_owned(), slow) {
+            Error::ReadTimeout => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    // server reads the handshake, replies with `reply` and closes the connection
+    fn run_network_dropped(reply: Vec<u8>) -> Error<()> {
+        let (player, server) = transport::pipe();
+        let server_thread = thread::spawn(move || {
+            let mut server = Framed::new(server);
+            read_frame(&mut server);
+            server.write_all(&reply).unwrap();
+        });
+        let result = run_network(player, ReadParams::default(), "skobochka", AlwaysPassGameStateBuilder, 0, ());
+        server_thread.join().unwrap();
+        match result {
+            Ok(..) =>
+                panic!("game is unexpectedly over"),
+            Err(e) =>
+                e,
+        }
+    }
+
+    #[test]
+    fn network_dropped_connection() {
+        match run_network_dropped(vec![]) {
+            ref e @ Error::HandshakeUnanswered(RecvError::ReadUnexpectedClose) =>
+                assert!(e.is_network_failure()),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        // the game has started, so the server is there
+        match run_network_dropped(frame(r#"{"you":"skobochka"}"#)) {
+            ref e @ Error::Chat(chat::Error::Recv(RecvError::ReadUnexpectedClose)) =>
+                assert!(!e.is_network_failure()),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn connect_with_retries() {
+        let params = ConnectParams {
+            timeout: Some(Duration::from_secs(1)),
+            attempts: 3,
+            backoff_initial: Duration::from_millis(1),
+            backoff_max: Duration::from_millis(2),
+        };
+        // nobody listens there after the listener is dropped
+        let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        match connect_tcp::<_, ()>(addr, &params) {
+            Err(ref e @ Error::Connect(..)) =>
+                assert!(e.is_network_failure()),
+            other =>
+                panic!("unexpected result: {:?}", other),
         }
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = connect_tcp::<_, ()>(listener.local_addr().unwrap(), &params).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
     }
 }
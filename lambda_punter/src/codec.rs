@@ -0,0 +1,123 @@
+use std::io::{self, Read, Write};
+use serde::ser::Serialize;
+use serde::de::DeserializeOwned;
+use ciborium;
+use base64::{self, Engine};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use flate2::Compression;
+use flate2::write::DeflateEncoder;
+use flate2::read::DeflateDecoder;
+
+// encoded states are tagged, so the format can be recognized (and changed) later
+const BINARY_STATE_TAG: &str = "cbor+deflate:";
+
+#[derive(Debug)]
+pub enum Error {
+    CborEncode(ciborium::ser::Error<io::Error>),
+    CborDecode(ciborium::de::Error<io::Error>),
+    Compress(io::Error),
+    Decompress(io::Error),
+    DecompressTooLarge { max_len: usize, },
+    Base64(base64::DecodeError),
+    UnknownFormat,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct StateStats {
+    pub binary_bytes: usize,
+    pub compressed_bytes: usize,
+    pub encoded_bytes: usize,
+}
+
+// Packs state into a string suitable for the json `state` field: cbor, deflated, base64 wrapped.
+pub fn encode_state<S>(state: &S) -> Result<(String, StateStats), Error> where S: Serialize {
+    let mut binary = Vec::new();
+    ciborium::into_writer(state, &mut binary)
+        .map_err(Error::CborEncode)?;
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&binary)
+        .map_err(Error::Compress)?;
+    let compressed = encoder.finish()
+        .map_err(Error::Compress)?;
+    let encoded = format!("{}{}", BINARY_STATE_TAG, BASE64.encode(&compressed));
+    let stats = StateStats {
+        binary_bytes: binary.len(),
+        compressed_bytes: compressed.len(),
+        encoded_bytes: encoded.len(),
+    };
+    Ok((encoded, stats))
+}
+
+pub fn is_encoded_state(s: &str) -> bool {
+    s.starts_with(BINARY_STATE_TAG)
+}
+
+// State comes from the peer, so it is never inflated beyond `max_len` bytes.
+pub fn decode_state<S>(s: &str, max_len: usize) -> Result<S, Error> where S: DeserializeOwned {
+    if !is_encoded_state(s) {
+        return Err(Error::UnknownFormat);
+    }
+    let compressed = BASE64.decode(&s[BINARY_STATE_TAG.len() ..])
+        .map_err(Error::Base64)?;
+    let mut binary = Vec::new();
+    DeflateDecoder::new(&compressed[..])
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut binary)
+        .map_err(Error::Decompress)?;
+    if binary.len() > max_len {
+        return Err(Error::DecompressTooLarge { max_len: max_len, });
+    }
+    ciborium::from_reader(&binary[..])
+        .map_err(Error::CborDecode)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use super::super::map::{Map, RiversIndex};
+    use super::super::test_common::*;
+    use super::super::graph::Graph;
+    use super::{encode_state, decode_state, is_encoded_state, Error};
+
+    const LIMIT: usize = 16 * 1024 * 1024;
+
+    #[test]
+    fn graph_roundtrip() {
+        let graph = random_medium_map_graph();
+        let (encoded, stats) = encode_state(&graph).unwrap();
+        assert!(is_encoded_state(&encoded));
+        assert_eq!(stats.encoded_bytes, encoded.len());
+        assert!(stats.compressed_bytes < stats.binary_bytes);
+        assert!(stats.encoded_bytes < serde_json::to_string(&graph).unwrap().len());
+        let decoded: Graph = decode_state(&encoded, LIMIT).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), serde_json::to_string(&graph).unwrap());
+    }
+
+    #[test]
+    fn map_roundtrip() {
+        // sites coords are optional and skipped when absent
+        let map = load_map_setup("sample").map;
+        let (encoded, _) = encode_state(&map).unwrap();
+        assert_eq!(decode_state::<Map>(&encoded, LIMIT).unwrap(), map);
+    }
+
+    #[test]
+    fn bad_input() {
+        match decode_state::<RiversIndex<usize>>("{}", LIMIT) {
+            Err(Error::UnknownFormat) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        match decode_state::<RiversIndex<usize>>("cbor+deflate:###", LIMIT) {
+            Err(Error::Base64(..)) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        // highly compressible payload is not inflated past the limit
+        let (encoded, stats) = encode_state(&vec![0u8; 1024 * 1024]).unwrap();
+        assert!(stats.encoded_bytes < 16 * 1024);
+        match decode_state::<Vec<u8>>(&encoded, 64 * 1024) {
+            Err(Error::DecompressTooLarge { max_len: 65536, }) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert_eq!(decode_state::<Vec<u8>>(&encoded, stats.binary_bytes).unwrap().len(), 1024 * 1024);
+    }
+}
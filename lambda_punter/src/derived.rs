@@ -0,0 +1,186 @@
+use std::{io, fs, env, process};
+use std::sync::{Arc, OnceLock};
+use std::path::{Path, PathBuf};
+use serde::{ser, de};
+use ciborium;
+
+// where memoized derived data is kept between offline turns (every turn is a separate process)
+#[cfg(not(test))]
+const CACHE_DIR_ENV: &str = "LAMBDA_PUNTER_CACHE_DIR";
+// subdirectory of the user cache dir used if the variable above is not set
+#[cfg(not(test))]
+const CACHE_DIR_DEFAULT: &str = "lambda_punter";
+// bumped on every change of cache files encoding, so files written by other versions are never read
+const CACHE_FORMAT_VERSION: u32 = 2;
+
+// A game state field which is not shipped with the state: it is serialized as nothing
+// and is rebuilt on the first access after the state is deserialized.
+pub struct Derived<T>(OnceLock<Arc<T>>);
+
+impl<T> Derived<T> {
+    pub fn new(value: Arc<T>) -> Derived<T> {
+        Derived(OnceLock::from(value))
+    }
+
+    pub fn is_built(&self) -> bool {
+        self.0.get().is_some()
+    }
+
+    // `make` is called only if the value is not built yet
+    pub fn get_or_build<F>(&self, make: F) -> &T where F: FnOnce() -> T {
+        self.0.get_or_init(|| Arc::new(make()))
+    }
+}
+
+impl<T> Default for Derived<T> {
+    fn default() -> Derived<T> {
+        Derived(OnceLock::new())
+    }
+}
+
+impl<T> ser::Serialize for Derived<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: ser::Serializer {
+        serializer.serialize_unit()
+    }
+}
+
+impl<'de, T> de::Deserialize<'de> for Derived<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: de::Deserializer<'de> {
+        let _: de::IgnoredAny = de::Deserialize::deserialize(deserializer)?;
+        Ok(Derived::default())
+    }
+}
+
+// Cache is private to the user, as its files are trusted. There is no cache if there is no user cache dir.
+#[cfg(not(test))]
+pub fn cache_dir() -> Option<PathBuf> {
+    env::var_os(CACHE_DIR_ENV)
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("XDG_CACHE_HOME").map(|dir| PathBuf::from(dir).join(CACHE_DIR_DEFAULT)))
+        .or_else(|| env::var_os("HOME").map(|dir| PathBuf::from(dir).join(".cache").join(CACHE_DIR_DEFAULT)))
+}
+
+// Tests get a fresh cache of their own, so files left by anything else never affect them.
+#[cfg(test)]
+pub fn cache_dir() -> Option<PathBuf> {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    let dir = DIR.get_or_init(|| {
+        let dir = env::temp_dir().join(format!("lambda_punter_test_cache_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    });
+    Some(dir.clone())
+}
+
+fn cache_path(name: &str, version: u32, fingerprint: &str) -> Option<PathBuf> {
+    let maybe_dir = cache_dir();
+    if maybe_dir.is_none() {
+        debug!("no cache dir, derived {} is not memoized", name);
+    }
+    maybe_dir.map(|dir| dir.join(format!("{}-f{}v{}-{}.cbor", name, CACHE_FORMAT_VERSION, version, fingerprint)))
+}
+
+// Returns the value memoized in cache file for given `name`, `version` of the algorithm making it and map
+// `fingerprint`, making and storing it if there is no such file yet. Cache troubles are not fatal:
+// the value is just made again then.
+pub fn cached<T, F>(name: &str, version: u32, fingerprint: &str, make: F) -> T
+    where T: ser::Serialize + de::DeserializeOwned,
+          F: FnOnce() -> T,
+{
+    let path = match cache_path(name, version, fingerprint) {
+        Some(path) =>
+            path,
+        None =>
+            return make(),
+    };
+    match fs::File::open(&path) {
+        Ok(file) =>
+            match ciborium::from_reader::<T, _>(io::BufReader::new(file)) {
+                Ok(value) => {
+                    debug!("derived {} is loaded from cache {:?}", name, path);
+                    return value;
+                },
+                Err(e) =>
+                    warn!("failed to load derived {} from cache {:?}: {:?}", name, path, e),
+            },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+            (),
+        Err(e) =>
+            warn!("failed to open cache {:?}: {:?}", path, e),
+    }
+    let value = make();
+    store_to(name, &path, &value);
+    value
+}
+
+// Memoizes a value made elsewhere, replacing whatever is cached for it.
+pub fn store<T>(name: &str, version: u32, fingerprint: &str, value: &T) where T: ser::Serialize {
+    if let Some(path) = cache_path(name, version, fingerprint) {
+        store_to(name, &path, value);
+    }
+}
+
+fn store_to<T>(name: &str, path: &Path, value: &T) where T: ser::Serialize {
+    // write to a temporary file first, so concurrent processes never see a partial one
+    let tmp_path = path.with_extension(format!("{}.tmp", process::id()));
+    let store = path.parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::File::create(&tmp_path))
+        .and_then(|file| ciborium::into_writer(value, io::BufWriter::new(file))
+                  .map_err(io::Error::other))
+        .and_then(|()| fs::rename(&tmp_path, path));
+    match store {
+        Ok(()) =>
+            debug!("derived {} is stored to cache {:?}", name, path),
+        Err(e) => {
+            warn!("failed to store derived {} to cache {:?}: {:?}", name, path, e);
+            let _ = fs::remove_file(&tmp_path);
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use serde_json;
+    use super::{Derived, cached, store};
+
+    #[derive(Serialize, Deserialize)]
+    struct State {
+        base: Vec<usize>,
+        sum: Derived<usize>,
+    }
+
+    #[test]
+    fn derived_not_shipped() {
+        let state = State { base: vec![1, 2, 3], sum: Derived::new(Arc::new(6)), };
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(json, r#"{"base":[1,2,3],"sum":null}"#);
+        let restored: State = serde_json::from_str(&json).unwrap();
+        assert!(!restored.sum.is_built());
+        assert_eq!(*restored.sum.get_or_build(|| restored.base.iter().sum()), 6);
+        assert!(restored.sum.is_built());
+        // already built fields are kept as is
+        assert_eq!(*restored.sum.get_or_build(|| 0), 6);
+    }
+
+    #[test]
+    fn cached_value() {
+        let calls = Cell::new(0);
+        let make = || { calls.set(calls.get() + 1); vec![1.5, 2.5] };
+        let value_a: Vec<f64> = cached("derived_test", 1, "test", &make);
+        let value_b: Vec<f64> = cached("derived_test", 1, "test", &make);
+        assert_eq!(value_a, value_b);
+        assert_eq!(calls.get(), 1);
+        // values made by another version are not reused
+        let value_c: Vec<f64> = cached("derived_test", 2, "test", &make);
+        assert_eq!(value_a, value_c);
+        assert_eq!(calls.get(), 2);
+        // stored values replace cached ones
+        store("derived_test", 1, "test", &vec![3.5]);
+        let value_d: Vec<f64> = cached("derived_test", 1, "test", &make);
+        assert_eq!(value_d, vec![3.5]);
+        assert_eq!(calls.get(), 2);
+    }
+}
@@ -1,12 +1,13 @@
 use serde::ser::Serialize;
 use serde::de::DeserializeOwned;
 use super::types::PunterId;
+use super::rng::Seed;
 use super::proto::{Move, Setup, Future};
 
 pub trait GameStateBuilder {
     type GameState: GameState;
 
-    fn build(self, setup: Setup) -> Self::GameState;
+    fn build(self, setup: Setup, seed: Seed) -> Self::GameState;
 }
 
 pub trait GameState: Sized + Serialize + DeserializeOwned {
@@ -18,4 +19,8 @@ pub trait GameState: Sized + Serialize + DeserializeOwned {
     fn get_futures(&mut self) -> Option<Vec<Future>> {
         None
     }
+    // called when the state is restored in offline mode, so data which is not shipped with it
+    // is rebuilt right away instead of on the first use
+    fn rebuild_derived(&mut self) {
+    }
 }
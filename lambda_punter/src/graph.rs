@@ -1,12 +1,37 @@
-use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet, BinaryHeap};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, HashSet, BTreeMap, BinaryHeap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::BuildHasherDefault;
+use serde::ser::{self, SerializeStruct};
 
 use super::types::SiteId;
 use super::map::{Map, River};
 
-#[derive(Serialize, Deserialize)]
+// unlike the default random state, fixed hashing keeps neighbours iteration order (and so search results)
+// the same from run to run
+type FixedState = BuildHasherDefault<DefaultHasher>;
+
+#[derive(Deserialize)]
 pub struct Graph {
-    neighs: HashMap<SiteId, HashSet<SiteId>>,
+    neighs: HashMap<SiteId, HashSet<SiteId, FixedState>, FixedState>,
+}
+
+// same layout as a derived one would produce, but with sites and neighbours sorted,
+// so identical graphs always serialize identically
+impl ser::Serialize for Graph {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: ser::Serializer {
+        let neighs: BTreeMap<_, _> = self.neighs
+            .iter()
+            .map(|(&site, neighs)| {
+                let mut neighs: Vec<_> = neighs.iter().cloned().collect();
+                neighs.sort();
+                (site, neighs)
+            })
+            .collect();
+        let mut state = serializer.serialize_struct("Graph", 1)?;
+        state.serialize_field("neighs", &neighs)?;
+        state.end()
+    }
 }
 
 enum Visit {
@@ -31,6 +56,69 @@ impl<S> GraphCache<S> {
     }
 }
 
+#[derive(Default)]
+pub struct FlowCache {
+    flows: HashMap<River, isize>,
+    parents: HashMap<SiteId, SiteId>,
+    queue: VecDeque<SiteId>,
+}
+
+impl FlowCache {
+    fn clear(&mut self) {
+        self.flows.clear();
+        self.parents.clear();
+        self.queue.clear();
+    }
+}
+
+#[derive(Default)]
+struct SearchFront {
+    pqueue: BinaryHeap<(Reverse<usize>, SiteId)>,
+    reached: HashMap<SiteId, (usize, Option<SiteId>)>,
+    settled: HashSet<SiteId>,
+}
+
+impl SearchFront {
+    fn clear(&mut self) {
+        self.pqueue.clear();
+        self.reached.clear();
+        self.settled.clear();
+    }
+
+    fn start(&mut self, site: SiteId) {
+        self.reached.insert(site, (0, None));
+        self.pqueue.push((Reverse(0), site));
+    }
+
+    fn top_cost(&mut self) -> Option<usize> {
+        while let Some(&(Reverse(cost), site)) = self.pqueue.peek() {
+            if self.settled.contains(&site) {
+                self.pqueue.pop();
+            } else {
+                return Some(cost);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Default)]
+pub struct BidirCache {
+    forward: SearchFront,
+    backward: SearchFront,
+    path: Vec<SiteId>,
+}
+
+impl BidirCache {
+    fn clear(&mut self) {
+        self.forward.clear();
+        self.backward.clear();
+        self.path.clear();
+    }
+}
+
+const UNBOUNDED_CAPACITY: isize = isize::MAX / 4;
+
 pub enum EdgeAttr {
     Blocked,
     Accessible { edge_cost: usize, },
@@ -54,11 +142,11 @@ impl Graph {
     }
 
     pub fn from_iter<I>(iter: I) -> Graph where I: Iterator<Item = (SiteId, SiteId)> {
-        let mut neighs = HashMap::new();
+        let mut neighs = HashMap::default();
         {
             let mut add = |k, v| neighs
                 .entry(k)
-                .or_insert_with(HashSet::new)
+                .or_insert_with(HashSet::default)
                 .insert(v);
             for (src, dst) in iter {
                 add(src, dst);
@@ -100,6 +188,192 @@ impl Graph {
         }, probe_edge, cache)
     }
 
+    pub fn shortest_path_bidir_only<'a>(&self, source: SiteId, target: SiteId, cache: &'a mut BidirCache) -> Option<&'a [SiteId]> {
+        self.shortest_path_bidir(source, target, cache, EdgeAttr::standard)
+    }
+
+    // Bidirectional Dijkstra: search fronts grow from both ends until the cheapest meeting is proven.
+    // Among equal cost routes the one passing through the greatest meeting site is choosen.
+    pub fn shortest_path_bidir<'a, E>(
+        &self,
+        source: SiteId,
+        target: SiteId,
+        cache: &'a mut BidirCache,
+        probe_edge: E
+    )
+        -> Option<&'a [SiteId]>
+        where E: Fn((SiteId, SiteId)) -> EdgeAttr,
+    {
+        cache.clear();
+        cache.forward.start(source);
+        cache.backward.start(target);
+        let mut best: Option<(usize, SiteId)> = if source == target { Some((0, source)) } else { None };
+        {
+            let BidirCache { ref mut forward, ref mut backward, .. } = *cache;
+            loop {
+                // choose a front to expand or check if we are done
+                let expired = |top_cost: usize| best.map(|(best_cost, _)| top_cost > best_cost).unwrap_or(false);
+                let is_forward = match (forward.top_cost(), backward.top_cost()) {
+                    (Some(f), Some(b)) =>
+                        if expired(f + b) { break; } else { f <= b },
+                    (Some(f), None) =>
+                        if expired(f) { break; } else { true },
+                    (None, Some(b)) =>
+                        if expired(b) { break; } else { false },
+                    (None, None) =>
+                        break,
+                };
+                let (front, other): (&mut SearchFront, &SearchFront) =
+                    if is_forward { (forward, backward) } else { (backward, forward) };
+
+                let (Reverse(cost), site) = match front.pqueue.pop() {
+                    Some(node) => node,
+                    None => break,
+                };
+                front.settled.insert(site);
+
+                // proceed with neighbours
+                if let Some(neighs) = self.neighs.get(&site) {
+                    for &reachable_site in neighs.iter() {
+                        if front.settled.contains(&reachable_site) {
+                            continue;
+                        }
+                        // keep edges orientation as if it is walked from source to target
+                        let edge = if is_forward { (site, reachable_site) } else { (reachable_site, site) };
+                        let next_cost = match probe_edge(edge) {
+                            EdgeAttr::Blocked =>
+                                continue,
+                            EdgeAttr::Accessible { edge_cost, } =>
+                                cost + edge_cost,
+                        };
+                        match front.reached.get(&reachable_site) {
+                            Some(&(prev_cost, _)) if prev_cost <= next_cost =>
+                                continue,
+                            _ =>
+                                (),
+                        }
+                        front.reached.insert(reachable_site, (next_cost, Some(site)));
+                        front.pqueue.push((Reverse(next_cost), reachable_site));
+
+                        // check if fronts are met
+                        if let Some(&(other_cost, _)) = other.reached.get(&reachable_site) {
+                            let total_cost = next_cost + other_cost;
+                            let better = best
+                                .map(|(best_cost, best_site)| {
+                                    total_cost < best_cost || (total_cost == best_cost && reachable_site > best_site)
+                                })
+                                .unwrap_or(true);
+                            if better {
+                                best = Some((total_cost, reachable_site));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // restore full path
+        let (_, meet_site) = best?;
+        let mut site = meet_site;
+        cache.path.push(site);
+        while let Some(&(_, Some(parent))) = cache.forward.reached.get(&site) {
+            cache.path.push(parent);
+            site = parent;
+        }
+        cache.path.reverse();
+        site = meet_site;
+        while let Some(&(_, Some(parent))) = cache.backward.reached.get(&site) {
+            cache.path.push(parent);
+            site = parent;
+        }
+        Some(&cache.path)
+    }
+
+    pub fn k_shortest_paths_only<S>(&self, source: SiteId, target: SiteId, k: usize, cache: &mut GraphCache<S>) -> Vec<(usize, Vec<SiteId>)>
+        where S: Default + Clone
+    {
+        self.k_shortest_paths(source, target, k, cache, EdgeAttr::standard)
+    }
+
+    // Yen's algorithm: up to `k` loopless paths ordered by cost
+    pub fn k_shortest_paths<E, S>(
+        &self,
+        source: SiteId,
+        target: SiteId,
+        k: usize,
+        cache: &mut GraphCache<S>,
+        probe_edge: E
+    )
+        -> Vec<(usize, Vec<SiteId>)>
+        where E: Fn((SiteId, SiteId)) -> EdgeAttr,
+              S: Default + Clone,
+    {
+        let mut found: Vec<(usize, Vec<SiteId>)> = Vec::new();
+        if k == 0 {
+            return found;
+        }
+        let first_path = if let Some(path) = self.shortest_path(source, target, cache, &probe_edge) {
+            path.to_owned()
+        } else {
+            return found;
+        };
+        if let Some(cost) = path_cost(&first_path, &probe_edge) {
+            found.push((cost, first_path));
+        } else {
+            return found;
+        }
+
+        let mut candidates = BinaryHeap::new();
+        let mut known = HashSet::new();
+        known.insert(found[0].1.clone());
+        let mut removed_rivers = HashSet::new();
+        let mut removed_sites = HashSet::new();
+        while found.len() < k {
+            {
+                let last_path = &found[found.len() - 1].1;
+                for spur_index in 0 .. last_path.len() - 1 {
+                    let spur_site = last_path[spur_index];
+                    let root_path = &last_path[.. spur_index + 1];
+
+                    // hide rivers already used by found paths sharing the same root
+                    removed_rivers.clear();
+                    for (_, path) in found.iter() {
+                        if path.len() > spur_index + 1 && &path[.. spur_index + 1] == root_path {
+                            removed_rivers.insert(River::new(path[spur_index], path[spur_index + 1]));
+                        }
+                    }
+                    // hide root path sites to keep paths loopless
+                    removed_sites.clear();
+                    removed_sites.extend(root_path[.. spur_index].iter().cloned());
+
+                    let maybe_spur_path = self.shortest_path(spur_site, target, cache, |(s, t)| {
+                        if removed_sites.contains(&s) || removed_sites.contains(&t) || removed_rivers.contains(&River::new(s, t)) {
+                            EdgeAttr::Blocked
+                        } else {
+                            probe_edge((s, t))
+                        }
+                    });
+                    if let Some(spur_path) = maybe_spur_path {
+                        let mut total_path = root_path[.. spur_index].to_owned();
+                        total_path.extend(spur_path.iter().cloned());
+                        if !known.contains(&total_path) {
+                            if let Some(cost) = path_cost(&total_path, &probe_edge) {
+                                known.insert(total_path.clone());
+                                candidates.push(Reverse((cost, total_path)));
+                            }
+                        }
+                    }
+                }
+            }
+            if let Some(Reverse(best_candidate)) = candidates.pop() {
+                found.push(best_candidate);
+            } else {
+                break;
+            }
+        }
+        found
+    }
+
     pub fn longest_jouney_from<S>(&self, source: SiteId, cache: &mut GraphCache<S>) -> Option<Vec<SiteId>> where S: Default + Clone {
         let mut best = None;
         self.generic_bfs(source, Default::default(), |path, cost, _seed| {
@@ -195,6 +469,176 @@ impl Graph {
         None
     }
 
+    // Approximate Steiner tree (Takahashi-Matsuyama): starting from the first terminal, repeatedly attach
+    // the terminal which is the cheapest to reach from the tree built so far. Terminals which cannot
+    // be reached are skipped. Returns total cost (according to probe) and tree rivers.
+    pub fn steiner_tree<E, S>(&self, terminals: &[SiteId], cache: &mut GraphCache<S>, probe_edge: E) -> (usize, Vec<River>)
+        where E: Fn((SiteId, SiteId)) -> EdgeAttr,
+              S: Default + Clone,
+    {
+        let mut tree = HashSet::new();
+        let mut total_cost = 0;
+        let root = if let Some(&site) = terminals.first() {
+            site
+        } else {
+            return (0, Vec::new());
+        };
+        let mut pending: Vec<_> = terminals[1 ..].iter().cloned().filter(|&t| t != root).collect();
+        pending.sort();
+        pending.dedup();
+
+        while !pending.is_empty() {
+            let mut best: Option<(usize, usize, Vec<SiteId>)> = None;
+            {
+                let probe_tree = |(s, t)| if tree.contains(&River::new(s, t)) {
+                    EdgeAttr::Accessible { edge_cost: 0, }
+                } else {
+                    probe_edge((s, t))
+                };
+                for (index, &terminal) in pending.iter().enumerate() {
+                    if let Some(path) = self.shortest_path(root, terminal, cache, probe_tree) {
+                        if let Some(cost) = path_cost(path, probe_tree) {
+                            if best.as_ref().map(|b| cost < b.0).unwrap_or(true) {
+                                best = Some((cost, index, path.to_owned()));
+                            }
+                        }
+                    }
+                }
+            }
+            if let Some((cost, index, path)) = best {
+                pending.swap_remove(index);
+                total_cost += cost;
+                tree.extend(path.windows(2).map(|w| River::new(w[0], w[1])));
+            } else {
+                debug!("steiner tree: terminals {:?} are unreachable from {}", pending, root);
+                break;
+            }
+        }
+
+        let mut rivers: Vec<_> = tree.into_iter().collect();
+        rivers.sort_by_key(|r| (r.source, r.target));
+        (total_cost, rivers)
+    }
+
+    // Rivers capacities are taken from probe: `edge_cost` of accessible river is a number of claims
+    // required to cut it, and zero cost rivers (already owned ones) cannot be cut at all.
+    // Returns None if there is no way to disconnect sites, otherwise cut size and cut rivers.
+    pub fn min_cut<E>(&self, source: SiteId, target: SiteId, cache: &mut FlowCache, probe_edge: E) -> Option<(usize, Vec<River>)>
+        where E: Fn((SiteId, SiteId)) -> EdgeAttr
+    {
+        let flow = self.max_flow(source, target, UNBOUNDED_CAPACITY, cache, &probe_edge);
+        if flow >= UNBOUNDED_CAPACITY {
+            return None;
+        }
+
+        // collect sites reachable from source in residual network
+        cache.parents.clear();
+        cache.queue.clear();
+        cache.parents.insert(source, source);
+        cache.queue.push_back(source);
+        while let Some(site) = cache.queue.pop_front() {
+            if let Some(neighs) = self.neighs.get(&site) {
+                for &reachable_site in neighs.iter() {
+                    if cache.parents.contains_key(&reachable_site) {
+                        continue;
+                    }
+                    if residual_capacity(site, reachable_site, &cache.flows, &probe_edge) > 0 {
+                        cache.parents.insert(reachable_site, site);
+                        cache.queue.push_back(reachable_site);
+                    }
+                }
+            }
+        }
+
+        // rivers from reachable part to the rest are the cut
+        let mut cut = Vec::new();
+        for (&site, _) in cache.parents.iter() {
+            if let Some(neighs) = self.neighs.get(&site) {
+                for &neigh in neighs.iter() {
+                    if !cache.parents.contains_key(&neigh) {
+                        if let EdgeAttr::Accessible { .. } = probe_edge((site, neigh)) {
+                            cut.push(River::new(site, neigh));
+                        }
+                    }
+                }
+            }
+        }
+        cut.sort_by_key(|r| (r.source, r.target));
+        Some((flow as usize, cut))
+    }
+
+    // Number of claims required to disconnect sites, counted up to `limit` only.
+    pub fn rivers_connectivity<E>(&self, source: SiteId, target: SiteId, limit: usize, cache: &mut FlowCache, probe_edge: E) -> usize
+        where E: Fn((SiteId, SiteId)) -> EdgeAttr
+    {
+        let limit = if limit as isize >= UNBOUNDED_CAPACITY { UNBOUNDED_CAPACITY } else { limit as isize };
+        self.max_flow(source, target, limit, cache, &probe_edge) as usize
+    }
+
+    // Edmonds-Karp
+    fn max_flow<E>(&self, source: SiteId, target: SiteId, limit: isize, cache: &mut FlowCache, probe_edge: &E) -> isize
+        where E: Fn((SiteId, SiteId)) -> EdgeAttr
+    {
+        cache.clear();
+        if source == target {
+            return limit;
+        }
+        let mut total_flow = 0;
+        while total_flow < limit {
+            // look for an augmenting path
+            cache.parents.clear();
+            cache.queue.clear();
+            cache.parents.insert(source, source);
+            cache.queue.push_back(source);
+            while let Some(site) = cache.queue.pop_front() {
+                if site == target {
+                    break;
+                }
+                if let Some(neighs) = self.neighs.get(&site) {
+                    for &reachable_site in neighs.iter() {
+                        if cache.parents.contains_key(&reachable_site) {
+                            continue;
+                        }
+                        if residual_capacity(site, reachable_site, &cache.flows, probe_edge) > 0 {
+                            cache.parents.insert(reachable_site, site);
+                            cache.queue.push_back(reachable_site);
+                        }
+                    }
+                }
+            }
+            if !cache.parents.contains_key(&target) {
+                break;
+            }
+
+            // find path bottleneck
+            let mut bottleneck = limit - total_flow;
+            let mut site = target;
+            while site != source {
+                let parent = cache.parents[&site];
+                let capacity = residual_capacity(parent, site, &cache.flows, probe_edge);
+                if capacity < bottleneck {
+                    bottleneck = capacity;
+                }
+                site = parent;
+            }
+
+            // push the flow
+            let mut site = target;
+            while site != source {
+                let parent = cache.parents[&site];
+                let flow = cache.flows.entry(River::new(parent, site)).or_insert(0);
+                if parent < site {
+                    *flow += bottleneck;
+                } else {
+                    *flow -= bottleneck;
+                }
+                site = parent;
+            }
+            total_flow += bottleneck;
+        }
+        total_flow
+    }
+
     // The Girvan-Newman Algorithm
     pub fn rivers_betweenness<S>(&self, cache: &mut GraphCache<S>) -> HashMap<River, f64> where S: Default {
         let mut rivers = HashMap::new();
@@ -303,6 +747,56 @@ impl Graph {
     }
 }
 
+pub fn path_cost<E>(path: &[SiteId], probe_edge: E) -> Option<usize> where E: Fn((SiteId, SiteId)) -> EdgeAttr {
+    let mut cost = 0;
+    let mut offset = 0;
+    while let (Some(&ps), Some(&pt)) = (path.get(offset), path.get(offset + 1)) {
+        match probe_edge((ps, pt)) {
+            EdgeAttr::Blocked =>
+                return None,
+            EdgeAttr::Accessible { edge_cost, } =>
+                cost += edge_cost,
+        }
+        offset += 1;
+    }
+    Some(cost)
+}
+
+fn residual_capacity<E>(source: SiteId, target: SiteId, flows: &HashMap<River, isize>, probe_edge: &E) -> isize
+    where E: Fn((SiteId, SiteId)) -> EdgeAttr
+{
+    let capacity = match probe_edge((source, target)) {
+        EdgeAttr::Blocked =>
+            return 0,
+        EdgeAttr::Accessible { edge_cost: 0, } =>
+            UNBOUNDED_CAPACITY,
+        EdgeAttr::Accessible { edge_cost, } =>
+            edge_cost as isize,
+    };
+    let flow = flows.get(&River::new(source, target)).cloned().unwrap_or(0);
+    if source < target {
+        capacity - flow
+    } else {
+        capacity + flow
+    }
+}
+
+// rivers which are shared by every given path: losing any of them breaks all the alternatives
+pub fn common_rivers(paths: &[(usize, Vec<SiteId>)]) -> Vec<River> {
+    let mut common: Option<HashSet<River>> = None;
+    for (_, path) in paths.iter() {
+        let rivers: HashSet<_> = path.windows(2).map(|w| River::new(w[0], w[1])).collect();
+        common = Some(if let Some(prev) = common.take() {
+            prev.intersection(&rivers).cloned().collect()
+        } else {
+            rivers
+        });
+    }
+    let mut rivers: Vec<_> = common.map(|c| c.into_iter().collect()).unwrap_or_default();
+    rivers.sort_by_key(|r| (r.source, r.target));
+    rivers
+}
+
 #[derive(Default)]
 struct PQNode<S = ()> {
     site: SiteId,
@@ -343,9 +837,11 @@ struct BssVisit {
 
 #[cfg(test)]
 mod test {
+    use serde_json;
     use super::super::types::SiteId;
     use super::super::test_common::*;
-    use super::{Graph, EdgeAttr};
+    use super::super::map::River;
+    use super::{Graph, EdgeAttr, common_rivers};
 
     #[test]
     fn shortest_path() {
@@ -378,6 +874,174 @@ mod test {
         let path04: &[_] = &[0, 7, 5, 4]; assert_eq!(graph.shortest_path::<_, ()>(0, 4, &mut cache, edge_probe), Some(path04));
     }
 
+    #[test]
+    fn shortest_path_bidir() {
+        let mut cache = Default::default();
+        let graph = sample_map_graph();
+        let path14: &[_] = &[1, 3, 4]; assert_eq!(graph.shortest_path_bidir_only(1, 4, &mut cache), Some(path14));
+        let path15: &[_] = &[1, 7, 5]; assert_eq!(graph.shortest_path_bidir_only(1, 5, &mut cache), Some(path15));
+        let path04: &[_] = &[0, 7, 5, 4]; assert_eq!(graph.shortest_path_bidir_only(0, 4, &mut cache), Some(path04));
+        let path11: &[_] = &[1]; assert_eq!(graph.shortest_path_bidir_only(1, 1, &mut cache), Some(path11));
+    }
+
+    #[test]
+    fn shortest_path_bidir_with_custom_costs() {
+        let mut cache = Default::default();
+        let graph = sample_map_graph();
+        fn edge_probe((s, t): (SiteId, SiteId)) -> EdgeAttr {
+            if ((s == 1) && (t == 3)) || ((s == 3) && (t == 1)) {
+                EdgeAttr::Accessible { edge_cost: 3, }
+            } else if ((s == 0) && (t == 1)) || ((s == 1) && (t == 0)) {
+                EdgeAttr::Blocked
+            } else {
+                EdgeAttr::Accessible { edge_cost: 1, }
+            }
+        }
+
+        let path14: &[_] = &[1, 7, 5, 4]; assert_eq!(graph.shortest_path_bidir(1, 4, &mut cache, edge_probe), Some(path14));
+        let path15: &[_] = &[1, 7, 5]; assert_eq!(graph.shortest_path_bidir(1, 5, &mut cache, edge_probe), Some(path15));
+        let path04: &[_] = &[0, 7, 5, 4]; assert_eq!(graph.shortest_path_bidir(0, 4, &mut cache, edge_probe), Some(path04));
+
+        let disconnected = Graph::from_iter([(0, 1), (2, 3)].iter().cloned());
+        assert_eq!(disconnected.shortest_path_bidir_only(0, 3, &mut cache), None);
+    }
+
+    #[test]
+    fn shortest_path_bidir_random_medium_map() {
+        let mut gcache = Default::default();
+        let mut bcache = Default::default();
+        let graph = random_medium_map_graph();
+        for &source in [0, 13, 58, 96].iter() {
+            for target in 0 .. 97 {
+                let expected = graph.shortest_path_only::<()>(source, target, &mut gcache).map(|p| p.len());
+                let path = graph.shortest_path_bidir_only(source, target, &mut bcache).map(|p| p.to_owned());
+                assert_eq!(path.as_ref().map(|p| p.len()), expected);
+                if let Some(path) = path {
+                    assert_eq!(path.first(), Some(&source));
+                    assert_eq!(path.last(), Some(&target));
+                    assert!(path.windows(2).all(|w| graph.neighs[&w[0]].contains(&w[1])));
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_shortest_path_bidir() {
+        use std::time::Instant;
+        for map_name in ["randomMedium", "tube", "edinburgh-sparse", "nara-sparse"].iter() {
+            let graph = load_map_graph(map_name);
+            let mut sites: Vec<_> = graph.neighs.keys().cloned().collect();
+            sites.sort();
+            let pairs: Vec<_> = sites.iter().step_by(sites.len() / 16 + 1)
+                .flat_map(|&s| sites.iter().rev().step_by(sites.len() / 16 + 1).map(move |&t| (s, t)))
+                .collect();
+
+            let mut gcache = Default::default();
+            let now = Instant::now();
+            for &(s, t) in pairs.iter() {
+                graph.shortest_path_only::<()>(s, t, &mut gcache);
+            }
+            let uni = now.elapsed();
+
+            let mut bcache = Default::default();
+            let now = Instant::now();
+            for &(s, t) in pairs.iter() {
+                graph.shortest_path_bidir_only(s, t, &mut bcache);
+            }
+            let bidir = now.elapsed();
+            println!("{}: {} queries, unidirectional {:?}, bidirectional {:?}", map_name, pairs.len(), uni, bidir);
+        }
+    }
+
+    #[test]
+    fn k_shortest_paths() {
+        let mut cache = Default::default();
+        let graph = sample_map_graph();
+        let paths = graph.k_shortest_paths_only::<()>(1, 4, 4, &mut cache);
+        assert_eq!(paths.len(), 4);
+        assert_eq!(paths[0], (2, vec![1, 3, 4]));
+        for &(cost, ref path) in paths[1 ..].iter() {
+            assert_eq!(cost, 3);
+            assert_eq!(path.first(), Some(&1));
+            assert_eq!(path.last(), Some(&4));
+        }
+        let mut distinct: Vec<_> = paths.iter().map(|p| p.1.clone()).collect();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), 4);
+        assert_eq!(common_rivers(&paths), vec![]);
+        assert_eq!(common_rivers(&paths[.. 1]), vec![River::new(1, 3), River::new(3, 4)]);
+    }
+
+    #[test]
+    fn k_shortest_paths_with_custom_costs() {
+        let mut cache = Default::default();
+        let graph = Graph::from_iter([(0, 1), (1, 2), (0, 3), (3, 2), (2, 4)].iter().cloned());
+        let edge_probe = |(s, t): (SiteId, SiteId)| if (s == 0 && t == 1) || (s == 1 && t == 0) {
+            EdgeAttr::Blocked
+        } else {
+            EdgeAttr::Accessible { edge_cost: 1, }
+        };
+        let paths = graph.k_shortest_paths::<_, ()>(0, 4, 3, &mut cache, edge_probe);
+        assert_eq!(paths, vec![(3, vec![0, 3, 2, 4])]);
+        assert_eq!(common_rivers(&paths), vec![River::new(0, 3), River::new(2, 3), River::new(2, 4)]);
+        assert_eq!(graph.k_shortest_paths_only::<()>(0, 4, 0, &mut cache), vec![]);
+    }
+
+    #[test]
+    fn steiner_tree() {
+        let mut cache = Default::default();
+        let graph = Graph::from_iter([(0, 1), (0, 2), (0, 3), (1, 4), (4, 5), (5, 2), (6, 9)].iter().cloned());
+        assert_eq!(graph.steiner_tree::<_, ()>(&[1, 2, 3], &mut cache, EdgeAttr::standard),
+                   (3, vec![River::new(0, 1), River::new(0, 2), River::new(0, 3)]));
+        assert_eq!(graph.steiner_tree::<_, ()>(&[1, 2, 3, 9], &mut cache, EdgeAttr::standard),
+                   (3, vec![River::new(0, 1), River::new(0, 2), River::new(0, 3)]));
+        assert_eq!(graph.steiner_tree::<_, ()>(&[1], &mut cache, EdgeAttr::standard), (0, vec![]));
+
+        let probe_owned = |(s, t): (SiteId, SiteId)| if (s, t) == (0, 1) || (s, t) == (1, 0) {
+            EdgeAttr::Accessible { edge_cost: 0, }
+        } else if (s, t) == (0, 3) || (s, t) == (3, 0) {
+            EdgeAttr::Blocked
+        } else {
+            EdgeAttr::Accessible { edge_cost: 1, }
+        };
+        assert_eq!(graph.steiner_tree::<_, ()>(&[1, 2, 3], &mut cache, probe_owned),
+                   (1, vec![River::new(0, 1), River::new(0, 2)]));
+    }
+
+    #[test]
+    fn min_cut() {
+        let mut cache = Default::default();
+        let graph = sample_map_graph();
+        assert_eq!(graph.min_cut(1, 5, &mut cache, EdgeAttr::standard).map(|c| c.0), Some(4));
+        assert_eq!(graph.rivers_connectivity(1, 5, 2, &mut cache, EdgeAttr::standard), 2);
+        assert_eq!(graph.rivers_connectivity(1, 5, 10, &mut cache, EdgeAttr::standard), 4);
+
+        let graph = Graph::from_iter([(0, 1), (1, 2), (0, 2), (2, 3), (3, 4), (3, 5), (4, 5)].iter().cloned());
+        assert_eq!(graph.min_cut(0, 4, &mut cache, EdgeAttr::standard), Some((1, vec![River::new(2, 3)])));
+
+        let probe_owned = |owned: Vec<(SiteId, SiteId)>| move |(s, t): (SiteId, SiteId)| {
+            if owned.iter().any(|&r| r == (s, t) || r == (t, s)) {
+                EdgeAttr::Accessible { edge_cost: 0, }
+            } else {
+                EdgeAttr::Accessible { edge_cost: 1, }
+            }
+        };
+        let (cut_size, cut) = graph.min_cut(0, 4, &mut cache, probe_owned(vec![(2, 3)])).unwrap();
+        assert_eq!(cut_size, 2);
+        assert_eq!(cut.len(), 2);
+        assert!(!cut.contains(&River::new(2, 3)));
+        assert_eq!(graph.min_cut(0, 4, &mut cache, probe_owned(vec![(0, 2), (2, 3), (3, 4)])), None);
+
+        let probe_blocked = |(s, t): (SiteId, SiteId)| if (s, t) == (2, 3) || (s, t) == (3, 2) {
+            EdgeAttr::Blocked
+        } else {
+            EdgeAttr::Accessible { edge_cost: 1, }
+        };
+        assert_eq!(graph.min_cut(0, 4, &mut cache, probe_blocked), Some((0, vec![])));
+    }
+
     #[test]
     fn betweenness_mmds() {
         let mut cache = Default::default();
@@ -632,4 +1296,15 @@ mod test {
             ((88, 89), 36375),
             ((90, 91), 12545)]);
     }
+
+    #[test]
+    fn serialize_sorted() {
+        let graph_a = Graph::from_iter([(3, 1), (1, 2), (0, 1)].iter().cloned());
+        let graph_b = Graph::from_iter([(0, 1), (2, 1), (1, 3)].iter().cloned());
+        let json_a = serde_json::to_string(&graph_a).unwrap();
+        assert_eq!(json_a, r#"{"neighs":{"0":[1],"1":[0,2,3],"2":[1],"3":[1]}}"#);
+        assert_eq!(serde_json::to_string(&graph_b).unwrap(), json_a);
+        let restored: Graph = serde_json::from_str(&json_a).unwrap();
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json_a);
+    }
 }
@@ -1,16 +1,28 @@
 extern crate rand;
 extern crate serde;
 extern crate serde_json;
+extern crate serde_path_to_error;
+extern crate serde_ignored;
+extern crate ciborium;
+extern crate flate2;
+extern crate base64;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate log;
 
 pub mod types;
+pub mod rng;
 pub mod map;
 pub mod proto;
+pub mod codec;
+pub mod derived;
+pub mod book;
 pub mod game;
+pub mod observer;
 pub mod chat;
+pub mod transport;
+pub mod record;
 pub mod client;
 pub mod graph;
 pub mod prob;
@@ -1,15 +1,195 @@
+use std::fmt;
 use std::cmp::{min, max};
 use std::collections::HashMap;
 use super::types::SiteId;
 
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Map {
-    pub sites: Vec<SiteId>,
+    pub sites: Vec<Site>,
     pub rivers: Vec<River>,
     pub mines: Vec<SiteId>,
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+impl Map {
+    // Stable identity of the map: it does not depend on sites, rivers and mines order, rivers direction or sites coords.
+    pub fn fingerprint(&self) -> Fingerprint {
+        let mut digest = Digest::new(FINGERPRINT_VERSION);
+        let sites = self.site_ids();
+        digest.write(sites.len() as u64);
+        for &site in sites.iter() {
+            digest.write(site);
+        }
+        let rivers = self.canonical_rivers();
+        digest.write(rivers.len() as u64);
+        for river in rivers.iter() {
+            digest.write(river.source);
+            digest.write(river.target);
+        }
+        let mut mines = self.mines.clone();
+        mines.sort();
+        mines.dedup();
+        digest.write(mines.len() as u64);
+        for &mine in mines.iter() {
+            digest.write(mine);
+        }
+        Fingerprint(digest.finish())
+    }
+
+    // Same as `fingerprint` but also does not depend on sites ids, so the same topology with relabeled sites is
+    // recognized. It is based on colors refinement (Weisfeiler-Lehman), so very symmetric maps which are not
+    // isomorphic could still collide.
+    pub fn topology_fingerprint(&self) -> Fingerprint {
+        let sites = self.site_ids();
+        let index: HashMap<_, _> = sites.iter().enumerate().map(|(i, &site)| (site, i)).collect();
+        let rivers = self.canonical_rivers();
+        let mut neighs = vec![Vec::new(); sites.len()];
+        for river in rivers.iter() {
+            let (s, t) = (index[&river.source], index[&river.target]);
+            neighs[s].push(t);
+            neighs[t].push(s);
+        }
+
+        let mut colors: Vec<_> = sites
+            .iter()
+            .zip(neighs.iter())
+            .map(|(site, site_neighs)| {
+                let mut digest = Digest::new(FINGERPRINT_VERSION);
+                digest.write(if self.mines.contains(site) { 1 } else { 0 });
+                digest.write(site_neighs.len() as u64);
+                digest.finish()
+            })
+            .collect();
+        let mut classes = count_classes(&colors);
+        let mut neigh_colors = Vec::new();
+        // every round either splits some class or nothing changes anymore
+        for _ in 0 .. sites.len() {
+            let next_colors: Vec<_> = colors
+                .iter()
+                .zip(neighs.iter())
+                .map(|(&color, site_neighs)| {
+                    neigh_colors.clear();
+                    neigh_colors.extend(site_neighs.iter().map(|&n| colors[n]));
+                    neigh_colors.sort();
+                    let mut digest = Digest::new(color);
+                    for &neigh_color in neigh_colors.iter() {
+                        digest.write(neigh_color);
+                    }
+                    digest.finish()
+                })
+                .collect();
+            colors = next_colors;
+            let next_classes = count_classes(&colors);
+            if next_classes == classes {
+                break;
+            }
+            classes = next_classes;
+        }
+
+        colors.sort();
+        let mut digest = Digest::new(FINGERPRINT_VERSION);
+        digest.write(sites.len() as u64);
+        digest.write(rivers.len() as u64);
+        for &color in colors.iter() {
+            digest.write(color);
+        }
+        Fingerprint(digest.finish())
+    }
+
+    // sites mentioned anywhere in the map, sorted
+    fn site_ids(&self) -> Vec<SiteId> {
+        let mut sites: Vec<_> = self.sites
+            .iter()
+            .map(|site| site.id)
+            .chain(self.rivers.iter().flat_map(|river| vec![river.source, river.target]))
+            .chain(self.mines.iter().cloned())
+            .collect();
+        sites.sort();
+        sites.dedup();
+        sites
+    }
+
+    fn canonical_rivers(&self) -> Vec<River> {
+        let mut rivers: Vec<_> = self.rivers.iter().map(|r| River::new(r.source, r.target)).collect();
+        rivers.sort();
+        rivers.dedup();
+        rivers
+    }
+}
+
+fn count_classes(colors: &[u64]) -> usize {
+    let mut sorted = colors.to_owned();
+    sorted.sort();
+    sorted.dedup();
+    sorted.len()
+}
+
+// bump it whenever fingerprint algorithm is changed
+const FINGERPRINT_VERSION: u64 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
+pub struct Fingerprint(pub u64);
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+// A tiny hash which is guaranteed to stay the same between runs, platforms and compiler versions
+// (unlike std hashers). Not a cryptographic one.
+struct Digest(u64);
+
+impl Digest {
+    fn new(seed: u64) -> Digest {
+        Digest(mix(seed))
+    }
+
+    fn write(&mut self, value: u64) {
+        self.0 = mix(self.0.rotate_left(23) ^ mix(value));
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// splitmix64 finalizer
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Site {
+    pub id: SiteId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<f64>,
+}
+
+impl Site {
+    pub fn new(id: SiteId) -> Site {
+        Site { id: id, x: None, y: None, }
+    }
+
+    pub fn with_coords(id: SiteId, x: f64, y: f64) -> Site {
+        Site { id: id, x: Some(x), y: Some(y), }
+    }
+
+    pub fn coords(&self) -> Option<(f64, f64)> {
+        match (self.x, self.y) {
+            (Some(x), Some(y)) =>
+                Some((x, y)),
+            _ =>
+                None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 pub struct River {
     pub source: SiteId,
     pub target: SiteId,
@@ -24,7 +204,7 @@ impl River {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct RiversIndex<T>(HashMap<River, T>);
 
 impl<T> RiversIndex<T> {
@@ -53,7 +233,9 @@ use serde::{ser, de};
 
 impl<T> ser::Serialize for RiversIndex<T> where T: ser::Serialize {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: ser::Serializer {
-        let vec: Vec<_> = self.iter().collect();
+        // sorted by river, so identical indices always serialize identically
+        let mut vec: Vec<_> = self.iter().collect();
+        vec.sort_by_key(|&(river, _)| river);
         vec.serialize(serializer)
     }
 }
@@ -64,3 +246,86 @@ impl<'de, T> de::Deserialize<'de> for RiversIndex<T> where T: de::Deserialize<'d
         Ok(RiversIndex(vec.into_iter().collect()))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use super::super::test_common::*;
+    use super::{Map, Site, River, RiversIndex};
+
+    fn make_map(sites: &[u64], rivers: &[(u64, u64)], mines: &[u64]) -> Map {
+        Map {
+            sites: sites.iter().map(|&id| Site::new(id)).collect(),
+            rivers: rivers.iter().map(|&(s, t)| River { source: s, target: t, }).collect(),
+            mines: mines.to_owned(),
+        }
+    }
+
+    #[test]
+    fn fingerprint_canonical() {
+        let map_a = make_map(&[0, 1, 2, 3], &[(0, 1), (1, 2), (2, 3)], &[0, 3]);
+        let map_b = make_map(&[3, 2, 1, 0], &[(3, 2), (1, 0), (2, 1)], &[3, 0]);
+        assert_eq!(map_a.fingerprint(), map_b.fingerprint());
+        let mut map_c = make_map(&[0, 1, 2, 3], &[(0, 1), (1, 2), (2, 3)], &[0, 3]);
+        map_c.sites[1] = Site::with_coords(1, 0.5, 1.5);
+        assert_eq!(map_a.fingerprint(), map_c.fingerprint());
+        assert_eq!(format!("{}", map_a.fingerprint()).len(), 16);
+
+        let other_mines = make_map(&[0, 1, 2, 3], &[(0, 1), (1, 2), (2, 3)], &[0, 2]);
+        let other_rivers = make_map(&[0, 1, 2, 3], &[(0, 1), (1, 2), (1, 3)], &[0, 3]);
+        let other_sites = make_map(&[0, 1, 2, 3, 4], &[(0, 1), (1, 2), (2, 3)], &[0, 3]);
+        assert!(map_a.fingerprint() != other_mines.fingerprint());
+        assert!(map_a.fingerprint() != other_rivers.fingerprint());
+        assert!(map_a.fingerprint() != other_sites.fingerprint());
+    }
+
+    #[test]
+    fn topology_fingerprint_relabeled() {
+        let map_a = make_map(&[0, 1, 2, 3], &[(0, 1), (1, 2), (2, 3), (1, 3)], &[0]);
+        let map_b = make_map(&[10, 11, 12, 13], &[(13, 12), (12, 11), (11, 10), (12, 10)], &[13]);
+        assert!(map_a.fingerprint() != map_b.fingerprint());
+        assert_eq!(map_a.topology_fingerprint(), map_b.topology_fingerprint());
+        // same graph but mine at the other place
+        let map_c = make_map(&[0, 1, 2, 3], &[(0, 1), (1, 2), (2, 3), (1, 3)], &[2]);
+        assert!(map_a.topology_fingerprint() != map_c.topology_fingerprint());
+    }
+
+    #[test]
+    fn fingerprint_real_maps() {
+        let names = ["sample", "lambda", "circle", "randomMedium", "Sierpinski-triangle"];
+        let maps: Vec<_> = names.iter().map(|name| load_map_setup(name).map).collect();
+        for (i, map_a) in maps.iter().enumerate() {
+            for map_b in maps[i + 1 ..].iter() {
+                assert!(map_a.fingerprint() != map_b.fingerprint());
+                assert!(map_a.topology_fingerprint() != map_b.topology_fingerprint());
+            }
+        }
+        // relabel sample map sites
+        let sample = &maps[0];
+        let relabel = |id| 100 - id;
+        let relabeled = Map {
+            sites: sample.sites.iter().map(|s| Site::new(relabel(s.id))).collect(),
+            rivers: sample.rivers.iter().map(|r| River::new(relabel(r.source), relabel(r.target))).collect(),
+            mines: sample.mines.iter().map(|&m| relabel(m)).collect(),
+        };
+        assert!(sample.fingerprint() != relabeled.fingerprint());
+        assert_eq!(sample.topology_fingerprint(), relabeled.topology_fingerprint());
+    }
+
+    #[test]
+    fn rivers_index_serialize_sorted() {
+        let mut index_a = RiversIndex::default();
+        let mut index_b = RiversIndex::default();
+        for &(s, t, v) in &[(3, 4, 1), (0, 1, 0), (1, 3, 1), (0, 7, 0)] {
+            index_a.insert(River::new(s, t), v);
+        }
+        for &(s, t, v) in &[(0, 7, 0), (4, 3, 1), (1, 0, 0), (3, 1, 1)] {
+            index_b.insert(River::new(s, t), v);
+        }
+        let json_a = serde_json::to_string(&index_a).unwrap();
+        assert_eq!(json_a, r#"[[{"source":0,"target":1},0],[{"source":0,"target":7},0],[{"source":1,"target":3},1],[{"source":3,"target":4},1]]"#);
+        assert_eq!(serde_json::to_string(&index_b).unwrap(), json_a);
+        let restored: RiversIndex<usize> = serde_json::from_str(&json_a).unwrap();
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json_a);
+    }
+}
@@ -0,0 +1,142 @@
+use std::time::Duration;
+use super::proto::{Req, Move, Setup, Score};
+
+// Hooks invoked by the chat loop as the game goes, so anything interested in a game
+// (metrics, visualizers, checkers) can watch it regardless of the solver playing.
+pub trait Observer {
+    // handshake is confirmed by the server
+    fn on_handshake(&mut self, _name: &str) {
+    }
+    fn on_setup(&mut self, _setup: &Setup) {
+    }
+    // game state is built for the setup within `build_time`, and `ready` is going to be sent
+    fn on_ready(&mut self, _ready: &Req, _build_time: Duration) {
+    }
+    // moves of the previous round arrived, before the game state plays them
+    fn on_moves(&mut self, _moves: &[Move]) {
+    }
+    // `reply` is made for the moves within `play_time`
+    fn on_round(&mut self, _reply: &Move, _play_time: Duration) {
+    }
+    // server reports the punter has been too slow and its move is taken as a pass
+    fn on_timeout(&mut self, _timeout_secs: f64) {
+    }
+    fn on_stop(&mut self, _moves: &[Move], _scores: &[Score]) {
+    }
+}
+
+impl Observer for () {
+}
+
+impl<O> Observer for &mut O where O: Observer {
+    fn on_handshake(&mut self, name: &str) {
+        (**self).on_handshake(name)
+    }
+
+    fn on_setup(&mut self, setup: &Setup) {
+        (**self).on_setup(setup)
+    }
+
+    fn on_ready(&mut self, ready: &Req, build_time: Duration) {
+        (**self).on_ready(ready, build_time)
+    }
+
+    fn on_moves(&mut self, moves: &[Move]) {
+        (**self).on_moves(moves)
+    }
+
+    fn on_round(&mut self, reply: &Move, play_time: Duration) {
+        (**self).on_round(reply, play_time)
+    }
+
+    fn on_timeout(&mut self, timeout_secs: f64) {
+        (**self).on_timeout(timeout_secs)
+    }
+
+    fn on_stop(&mut self, moves: &[Move], scores: &[Score]) {
+        (**self).on_stop(moves, scores)
+    }
+}
+
+// Both observers are invoked, first one goes first.
+impl<A, B> Observer for (A, B) where A: Observer, B: Observer {
+    fn on_handshake(&mut self, name: &str) {
+        self.0.on_handshake(name);
+        self.1.on_handshake(name);
+    }
+
+    fn on_setup(&mut self, setup: &Setup) {
+        self.0.on_setup(setup);
+        self.1.on_setup(setup);
+    }
+
+    fn on_ready(&mut self, ready: &Req, build_time: Duration) {
+        self.0.on_ready(ready, build_time);
+        self.1.on_ready(ready, build_time);
+    }
+
+    fn on_moves(&mut self, moves: &[Move]) {
+        self.0.on_moves(moves);
+        self.1.on_moves(moves);
+    }
+
+    fn on_round(&mut self, reply: &Move, play_time: Duration) {
+        self.0.on_round(reply, play_time);
+        self.1.on_round(reply, play_time);
+    }
+
+    fn on_timeout(&mut self, timeout_secs: f64) {
+        self.0.on_timeout(timeout_secs);
+        self.1.on_timeout(timeout_secs);
+    }
+
+    fn on_stop(&mut self, moves: &[Move], scores: &[Score]) {
+        self.0.on_stop(moves, scores);
+        self.1.on_stop(moves, scores);
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+// Logs the game progress.
+#[derive(Default)]
+pub struct Log {
+    rounds: usize,
+    play_time: Duration,
+}
+
+impl Observer for Log {
+    fn on_handshake(&mut self, name: &str) {
+        debug!("handshake done as {}", name);
+    }
+
+    fn on_setup(&mut self, setup: &Setup) {
+        info!("setup: punter {} of {}, {} sites, {} rivers, {} mines, {:?}",
+              setup.punter, setup.punters, setup.map.sites.len(), setup.map.rivers.len(), setup.map.mines.len(), setup.settings);
+    }
+
+    fn on_ready(&mut self, ready: &Req, build_time: Duration) {
+        info!("{:?} is prepared in {:.1} ms", ready, millis(build_time));
+    }
+
+    fn on_moves(&mut self, moves: &[Move]) {
+        debug!("round {} moves: {:?}", self.rounds + 1, moves);
+    }
+
+    fn on_round(&mut self, reply: &Move, play_time: Duration) {
+        self.rounds += 1;
+        self.play_time += play_time;
+        debug!("round {}: {:?} in {:.1} ms", self.rounds, reply, millis(play_time));
+    }
+
+    fn on_timeout(&mut self, timeout_secs: f64) {
+        warn!("timed out at round {}, the limit is {} s", self.rounds, timeout_secs);
+    }
+
+    fn on_stop(&mut self, _moves: &[Move], scores: &[Score]) {
+        let avg_play_time = if self.rounds == 0 { 0.0 } else { millis(self.play_time) / self.rounds as f64 };
+        info!("game over after {} rounds ({:.1} ms per move on average), scores: {:?}", self.rounds, avg_play_time, scores);
+    }
+}
@@ -1,220 +1,1243 @@
-use std::time;
-use rand;
+use std::{time, thread, panic};
+use std::ops::Range;
+use std::sync::{mpsc, Arc, Mutex};
+use std::cmp::{min, max, Ordering};
+use std::collections::HashMap;
+use rand::Rng;
 use rand::distributions::{Weighted, WeightedChoice, IndependentSample};
 
 use super::types::{SiteId, PunterId};
+use super::rng::{self, Seed};
 use super::map::{River, RiversIndex};
-use super::graph::{Graph, GraphCache, EdgeAttr, StepCommand};
+use super::proto::Move;
+use super::graph::{Graph, GraphCache, FlowCache, BidirCache, EdgeAttr, StepCommand};
 
 #[derive(Default)]
 pub struct MonteCarloCache {
     claimed_rivers: RiversIndex<PunterId>,
     weighted: Vec<Weighted<River>>,
-    route_rivers: Vec<River>,
+    route_outcomes: Vec<Option<Outcome>>,
 }
 
 impl MonteCarloCache {
     pub fn clear(&mut self) {
         self.claimed_rivers.clear();
         self.weighted.clear();
-        self.route_rivers.clear();
+        self.route_outcomes.clear();
     }
 }
 
-pub fn journey_success_simulate<F>(
-    journey: &[SiteId],
-    rivers_bw: &RiversIndex<f64>,
+pub fn available_workers() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+impl MonteCarloCache {
+    pub fn pool(workers: usize) -> Vec<MonteCarloCache> {
+        (0 .. workers).map(|_| Default::default()).collect()
+    }
+}
+
+// Predicts enemy claims in playouts.
+pub trait OpponentModel {
+    // Picks a non-claimed river for `enemy_punter`, `weighted` is a scratch buffer.
+    fn choose_river<R>(
+        &self,
+        enemy_punter: PunterId,
+        claimed_rivers: &RiversIndex<PunterId>,
+        weighted: &mut Vec<Weighted<River>>,
+        rng: &mut R,
+    )
+        -> Option<River>
+        where R: Rng;
+}
+
+// Fixed weight of each river, scaled to fit `WeightedChoice` total limit.
+struct RiverWeights {
+    weights: Vec<(River, u32)>,
+}
+
+impl RiverWeights {
+    fn new<I>(iter: I) -> RiverWeights where I: Iterator<Item = (River, f64)> {
+        let mut weights: Vec<_> = iter.collect();
+        // keep the choice reproducible no matter of the source iteration order
+        weights.sort_by_key(|(river, _)| (river.source, river.target));
+        let total: f64 = weights.iter().map(|&(_, w)| w).sum();
+        let scale = if total > 0.0 { (u32::MAX as f64) / total } else { 0.0 };
+        RiverWeights {
+            weights: weights
+                .into_iter()
+                .map(|(river, w)| (river, (w * scale) as u32))
+                .collect(),
+        }
+    }
+
+    fn choose<R>(&self, claimed_rivers: &RiversIndex<PunterId>, weighted: &mut Vec<Weighted<River>>, rng: &mut R) -> Option<River>
+        where R: Rng
+    {
+        weighted.clear();
+        weighted.extend(
+            self.weights
+                .iter()
+                .filter(|&(river, _)| !claimed_rivers.contains_key(river))
+                .map(|&(ref river, weight)| Weighted { weight: weight, item: river.clone(), }));
+        choose_weighted(weighted, rng)
+    }
+}
+
+fn choose_weighted<R>(weighted: &mut [Weighted<River>], rng: &mut R) -> Option<River> where R: Rng {
+    if weighted.is_empty() {
+        return None;
+    }
+    if weighted.iter().all(|w| w.weight == 0) {
+        // all the rivers left are negligible, pick any of them
+        for w in weighted.iter_mut() {
+            w.weight = 1;
+        }
+    }
+    Some(WeightedChoice::new(weighted).ind_sample(rng))
+}
+
+// Every free river is equally likely.
+pub struct UniformOpponent {
+    weights: RiverWeights,
+}
+
+impl UniformOpponent {
+    pub fn new(rivers: &[River]) -> UniformOpponent {
+        UniformOpponent {
+            weights: RiverWeights::new(rivers.iter().map(|river| (river.clone(), 1.0))),
+        }
+    }
+}
+
+impl OpponentModel for UniformOpponent {
+    fn choose_river<R>(&self, _: PunterId, claimed_rivers: &RiversIndex<PunterId>, weighted: &mut Vec<Weighted<River>>, rng: &mut R) -> Option<River>
+        where R: Rng
+    {
+        self.weights.choose(claimed_rivers, weighted, rng)
+    }
+}
+
+// Free rivers are picked proportionally to their betweenness.
+pub struct BetweennessOpponent {
+    weights: RiverWeights,
+}
+
+impl BetweennessOpponent {
+    pub fn new(rivers_bw: &RiversIndex<f64>) -> BetweennessOpponent {
+        BetweennessOpponent {
+            weights: RiverWeights::new(rivers_bw.iter().map(|(river, &bw)| (river.clone(), bw))),
+        }
+    }
+}
+
+impl OpponentModel for BetweennessOpponent {
+    fn choose_river<R>(&self, _: PunterId, claimed_rivers: &RiversIndex<PunterId>, weighted: &mut Vec<Weighted<River>>, rng: &mut R) -> Option<River>
+        where R: Rng
+    {
+        self.weights.choose(claimed_rivers, weighted, rng)
+    }
+}
+
+// Always claims one of the free rivers closest to any mine.
+pub struct GreedyNearestOpponent {
+    by_distance: Vec<(usize, River)>,
+}
+
+impl GreedyNearestOpponent {
+    pub fn new(graph: &Graph, rivers: &[River], mines: &[SiteId]) -> GreedyNearestOpponent {
+        let mut by_distance: Vec<_> = rivers_mine_distance(graph, rivers, mines)
+            .into_iter()
+            .map(|(river, distance)| (distance, river))
+            .collect();
+        by_distance.sort_by_key(|&(distance, ref river)| (distance, river.source, river.target));
+        GreedyNearestOpponent {
+            by_distance: by_distance,
+        }
+    }
+}
+
+impl OpponentModel for GreedyNearestOpponent {
+    fn choose_river<R>(&self, _: PunterId, claimed_rivers: &RiversIndex<PunterId>, weighted: &mut Vec<Weighted<River>>, rng: &mut R) -> Option<River>
+        where R: Rng
+    {
+        weighted.clear();
+        let mut nearest = None;
+        for &(distance, ref river) in self.by_distance.iter() {
+            if nearest.map(|d| d < distance).unwrap_or(false) {
+                break;
+            }
+            if !claimed_rivers.contains_key(river) {
+                nearest = Some(distance);
+                weighted.push(Weighted { weight: 1, item: river.clone(), });
+            }
+        }
+        choose_weighted(weighted, rng)
+    }
+}
+
+// rivers farther from mines than this share the same replay stats bucket
+const REPLAY_STATS_MAX_DISTANCE: usize = 16;
+
+// How often rivers are claimed depending on their distance to the nearest mine, gathered from replayed games.
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ReplayStats {
+    claimed: Vec<u64>,
+    available: Vec<u64>,
+}
+
+impl ReplayStats {
+    // Accounts a replayed game: `moves` are all the moves made during the game, claims of `skip_punter` are ignored.
+    pub fn learn(&mut self, graph: &Graph, rivers: &[River], mines: &[SiteId], moves: &[Move], skip_punter: Option<PunterId>) {
+        let buckets = REPLAY_STATS_MAX_DISTANCE + 1;
+        self.claimed.resize(buckets, 0);
+        self.available.resize(buckets, 0);
+
+        let distances: HashMap<_, _> = rivers_mine_distance(graph, rivers, mines).into_iter().collect();
+        for &distance in distances.values() {
+            self.available[min(distance, REPLAY_STATS_MAX_DISTANCE)] += 1;
+        }
+        let mut account = |punter, source, target| if Some(punter) != skip_punter {
+            if let Some(&distance) = distances.get(&River::new(source, target)) {
+                self.claimed[min(distance, REPLAY_STATS_MAX_DISTANCE)] += 1;
+            }
+        };
+        for move_ in moves.iter() {
+            match *move_ {
+                Move::Claim { punter, source, target, } | Move::Option { punter, source, target, } =>
+                    account(punter, source, target),
+                Move::Splurge { punter, ref route, } =>
+                    for hop in route.windows(2) {
+                        account(punter, hop[0], hop[1]);
+                    },
+                Move::Pass { .. } =>
+                    (),
+            }
+        }
+    }
+
+    // Chance of a river at `distance` to be claimed, with add-one smoothing.
+    pub fn claim_rate(&self, distance: usize) -> f64 {
+        let bucket = min(distance, REPLAY_STATS_MAX_DISTANCE);
+        let claimed = self.claimed.get(bucket).cloned().unwrap_or(0);
+        let available = self.available.get(bucket).cloned().unwrap_or(0);
+        (claimed + 1) as f64 / (available + 1) as f64
+    }
+}
+
+// Free rivers are picked proportionally to the claim rate learned from replays.
+pub struct ReplayStatsOpponent {
+    weights: RiverWeights,
+}
+
+impl ReplayStatsOpponent {
+    pub fn new(stats: &ReplayStats, graph: &Graph, rivers: &[River], mines: &[SiteId]) -> ReplayStatsOpponent {
+        ReplayStatsOpponent {
+            weights: RiverWeights::new(
+                rivers_mine_distance(graph, rivers, mines)
+                    .into_iter()
+                    .map(|(river, distance)| (river, stats.claim_rate(distance)))),
+        }
+    }
+}
+
+impl OpponentModel for ReplayStatsOpponent {
+    fn choose_river<R>(&self, _: PunterId, claimed_rivers: &RiversIndex<PunterId>, weighted: &mut Vec<Weighted<River>>, rng: &mut R) -> Option<River>
+        where R: Rng
+    {
+        self.weights.choose(claimed_rivers, weighted, rng)
+    }
+}
+
+// Distance of each river to the nearest mine in hops, `usize::MAX` for rivers unreachable from mines.
+fn rivers_mine_distance(graph: &Graph, rivers: &[River], mines: &[SiteId]) -> Vec<(River, usize)> {
+    let mut sites_distance = HashMap::new();
+    let mut gcache: GraphCache<()> = Default::default();
+    for &mine in mines.iter() {
+        graph.generic_bfs(mine, (), |path, cost, _| {
+            if let Some(&site) = path.last() {
+                let distance = sites_distance.entry(site).or_insert(cost);
+                if *distance > cost {
+                    *distance = cost;
+                }
+            }
+            StepCommand::Continue(())
+        }, EdgeAttr::standard, &mut gcache);
+    }
+    rivers
+        .iter()
+        .map(|river| {
+            let distance = min(
+                sites_distance.get(&river.source).cloned().unwrap_or(usize::MAX),
+                sites_distance.get(&river.target).cloned().unwrap_or(usize::MAX));
+            (river.clone(), distance)
+        })
+        .collect()
+}
+
+// Monte Carlo setup shared by estimations: in every game `make_move` plays for `my_punter` and `opponent`
+// for everybody else. Games are split among workers, one per each cache in `caches`.
+pub struct Playouts<'a, O: 'a, F> {
+    opponent: &'a O,
     my_punter: PunterId,
     punters_count: usize,
-    start_turn: usize,
     make_move: F,
+    // games simulated for each estimation (at most, if it stops early)
+    games_count: usize,
+    // each game uses its own generator made from this seed and the game index
+    seed: Seed,
+    caches: &'a mut [MonteCarloCache],
+}
+
+impl<'a, O, F> Playouts<'a, O, F> where F: for<'b> Fn(&'b [River], &RiversIndex<PunterId>) -> Option<&'b River> {
+    pub fn new(
+        opponent: &'a O,
+        my_punter: PunterId,
+        punters_count: usize,
+        make_move: F,
+        games_count: usize,
+        caches: &'a mut [MonteCarloCache],
+    )
+        -> Playouts<'a, O, F>
+    {
+        Playouts {
+            opponent: opponent,
+            my_punter: my_punter,
+            punters_count: punters_count,
+            make_move: make_move,
+            games_count: games_count,
+            seed: 0,
+            caches: caches,
+        }
+    }
+
+    pub fn seed(mut self, seed: Seed) -> Playouts<'a, O, F> {
+        self.seed = seed;
+        self
+    }
+}
+
+// Read only part of `Playouts` shared by workers.
+struct Sim<'a, O: 'a, F: 'a> {
+    opponent: &'a O,
+    my_punter: PunterId,
+    punters_count: usize,
+    make_move: &'a F,
     games_count: usize,
-    cache: &mut MonteCarloCache,
+    seed: Seed,
+}
+
+// Runs `body` with workers for `playouts`: a thread per each cache, started once and kept until `body` returns,
+// or no threads at all when there is a single cache.
+fn with_workers<'p, O, F, T, B>(playouts: &mut Playouts<O, F>, body: B) -> T
+    where O: OpponentModel + Sync,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River> + Sync,
+          B: for<'w> FnOnce(&mut Workers<'w, 'p, O, F>) -> T,
+{
+    let sim = Sim {
+        opponent: playouts.opponent,
+        my_punter: playouts.my_punter,
+        punters_count: playouts.punters_count,
+        make_move: &playouts.make_move,
+        games_count: playouts.games_count,
+        seed: playouts.seed,
+    };
+    let caches = &mut *playouts.caches;
+    if caches.len() < 2 {
+        let mut default_cache = MonteCarloCache::default();
+        let cache = caches.first_mut().unwrap_or(&mut default_cache);
+        return body(&mut Workers { sim: &sim, pool: Pool::Inline(cache), evaluations: 0, });
+    }
+
+    let count = caches.len();
+    let (jobs_tx, jobs_rx) = mpsc::channel::<(usize, Job)>();
+    let (results_tx, results_rx) = mpsc::channel();
+    let jobs_rx = Mutex::new(jobs_rx);
+    thread::scope(|scope| {
+        for cache in caches.iter_mut() {
+            let (sim, jobs_rx, results_tx) = (&sim, &jobs_rx, results_tx.clone());
+            scope.spawn(move || loop {
+                let next = jobs_rx.lock().unwrap().recv();
+                let (index, job) = match next {
+                    Ok(next) =>
+                        next,
+                    Err(mpsc::RecvError) =>
+                        break,
+                };
+                let result = panic::catch_unwind(panic::AssertUnwindSafe(|| sim.run(job, cache)));
+                if results_tx.send((index, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        // workers are gone as soon as jobs sender is dropped along with `Workers`
+        let pool = Pool::Threads { count: count, jobs: jobs_tx, results: results_rx, };
+        body(&mut Workers { sim: &sim, pool: pool, evaluations: 0, })
+    })
+}
+
+struct Workers<'w, 'p, O: 'w, F: 'w> {
+    sim: &'w Sim<'w, O, F>,
+    pool: Pool<'w, 'p>,
+    // estimations made so far, see `Budget::Evaluations`
+    evaluations: usize,
+}
+
+enum Pool<'w, 'p> {
+    Inline(&'w mut MonteCarloCache),
+    Threads {
+        count: usize,
+        jobs: mpsc::Sender<(usize, Job<'p>)>,
+        results: mpsc::Receiver<(usize, thread::Result<i64>)>,
+    },
+}
+
+impl<'w, 'p, O, F> Workers<'w, 'p, O, F>
+    where O: OpponentModel,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>,
+{
+    fn count(&self) -> usize {
+        match self.pool {
+            Pool::Inline(..) =>
+                1,
+            Pool::Threads { count, .. } =>
+                count,
+        }
+    }
+
+    // Runs `jobs` and returns their results in the same order. A panic in any job is resumed here.
+    fn run(&mut self, jobs: Vec<Job<'p>>) -> Vec<i64> {
+        let sim = self.sim;
+        match self.pool {
+            Pool::Inline(ref mut cache) =>
+                jobs.into_iter().map(|job| sim.run(job, cache)).collect(),
+            Pool::Threads { jobs: ref jobs_tx, ref results, .. } => {
+                let jobs_count = jobs.len();
+                for (index, job) in jobs.into_iter().enumerate() {
+                    jobs_tx.send((index, job)).expect("monte carlo workers are gone");
+                }
+                let mut values = vec![0; jobs_count];
+                for _ in 0 .. jobs_count {
+                    match results.recv().expect("monte carlo workers are gone") {
+                        (index, Ok(value)) =>
+                            values[index] = value,
+                        (_, Err(panic)) =>
+                            panic::resume_unwind(panic),
+                    }
+                }
+                values
+            },
+        }
+    }
+
+    // Splits `games` of `route` among workers: every game is seeded by its index, so results do not depend
+    // on the workers count.
+    fn simulate_games(&mut self, route: &Arc<Vec<River>>, position: Position<'p>, games: Range<usize>) -> usize {
+        let jobs = split_games(games, self.count())
+            .map(|games| Job::Route { route: route.clone(), position: position, games: games, })
+            .collect();
+        self.run(jobs).into_iter().sum::<i64>() as usize
+    }
+}
+
+fn split_games(games: Range<usize>, parts: usize) -> impl Iterator<Item = Range<usize>> {
+    let games_count = games.end - games.start;
+    (0 .. parts)
+        .map(move |part| games.start + games_count * part / parts .. games.start + games_count * (part + 1) / parts)
+        .filter(|part_games| part_games.start < part_games.end)
+}
+
+// A piece of work for a worker, evaluated to a number.
+enum Job<'p> {
+    // successes count among `games` of `route` played from `position`
+    Route { route: Arc<Vec<River>>, position: Position<'p>, games: Range<usize>, },
+    // total joint score of `routes` over `games`, see `joint_futures_value`
+    Joint { routes: Arc<Routes>, games: Range<usize>, },
+}
+
+// Rivers of several futures one after another.
+struct Routes {
+    rivers: Vec<River>,
+    bounds: Vec<(usize, usize)>,
+}
+
+impl Routes {
+    fn new(futures: &[FutureCandidate]) -> Routes {
+        let mut routes = Routes { rivers: Vec::new(), bounds: Vec::new(), };
+        for fut in futures.iter() {
+            let start = routes.rivers.len();
+            routes.rivers.extend(journey_rivers(&fut.path));
+            routes.bounds.push((start, routes.rivers.len()));
+        }
+        routes
+    }
+}
+
+fn journey_rivers(journey: &[SiteId]) -> Vec<River> {
+    journey.windows(2).map(|hop| River::new(hop[0], hop[1])).collect()
+}
+
+pub fn journey_success_simulate<O, F>(journey: &[SiteId], start_turn: usize, playouts: &mut Playouts<O, F>) -> Option<f64>
+    where O: OpponentModel + Sync,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River> + Sync,
+{
+    let journey_len = journey.len();
+    if (journey_len < 2) || (playouts.punters_count < 2) {
+        return None;
+    }
+
+    let games_count = playouts.games_count;
+    let route = Arc::new(journey_rivers(journey));
+    let success_count = with_workers(playouts, |workers| {
+        workers.simulate_games(&route, Position::initial(start_turn), 0 .. games_count)
+    });
+    Some(success_count as f64 / games_count as f64)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SuccessEstimate {
+    pub games: usize,
+    pub successes: usize,
+}
+
+impl SuccessEstimate {
+    pub fn probability(&self) -> f64 {
+        if self.games == 0 {
+            0.0
+        } else {
+            self.successes as f64 / self.games as f64
+        }
+    }
+
+    // Wilson score interval for the given `z` quantile (1.96 for 95%)
+    pub fn confidence_interval(&self, z: f64) -> (f64, f64) {
+        if self.games == 0 {
+            return (0.0, 1.0);
+        }
+        let n = self.games as f64;
+        let p = self.probability();
+        let z2 = z * z;
+        let denom = 1.0 + z2 / n;
+        let center = (p + z2 / (2.0 * n)) / denom;
+        let half = z * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt() / denom;
+        ((center - half).max(0.0), (center + half).min(1.0))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EarlyStop {
+    pub break_even: f64,
+    pub z: f64,
+    pub batch_games: usize,
+}
+
+impl EarlyStop {
+    pub fn at(break_even: f64) -> EarlyStop {
+        EarlyStop {
+            break_even: break_even,
+            z: 1.96,
+            batch_games: 64,
+        }
+    }
+
+    // future reward `cost³·p` beats regular reward `cost²` only when `p > 1 / cost`
+    pub fn for_future_cost(cost: usize) -> EarlyStop {
+        EarlyStop::at(1.0 / cost.max(1) as f64)
+    }
+
+    fn is_decided(&self, estimate: &SuccessEstimate) -> bool {
+        let (lower, upper) = estimate.confidence_interval(self.z);
+        upper < self.break_even || lower > self.break_even
+    }
+}
+
+// Simulates up to `playouts.games_count` games in batches, stopping as soon as the confidence interval
+// of the success estimate does not contain `early_stop.break_even`.
+pub fn journey_success_estimate<O, F>(
+    journey: &[SiteId],
+    start_turn: usize,
+    early_stop: Option<EarlyStop>,
+    playouts: &mut Playouts<O, F>,
 )
-    -> Option<f64>
-    where F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>
+    -> Option<SuccessEstimate>
+    where O: OpponentModel + Sync,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River> + Sync,
 {
     let journey_len = journey.len();
-    if (journey_len < 2) || (punters_count < 2) {
+    if (journey_len < 2) || (playouts.punters_count < 2) {
         return None;
     }
 
-    // collect journey rivers
-    cache.route_rivers.clear();
-    let mut offset = 0;
-    while let (Some(&ps), Some(&pt)) = (journey.get(offset), journey.get(offset + 1)) {
-        cache.route_rivers.push(River::new(ps, pt));
-        offset += 1;
-    }
-
-    // calculate scale coeff for bw values
-    let bw_sum: f64 = rivers_bw.values().sum();
-    let bw_scale = (u32::max_value() as f64) / bw_sum;
-
-    // play `games_count` times and gather stats
-    let mut success_count = 0;
-    for _ in 0 .. games_count {
-        enum Outcome { Success, Fail, };
-
-        fn play<F>(
-            rivers_bw: &RiversIndex<f64>,
-            my_punter: PunterId,
-            punters_count: usize,
-            start_turn: usize,
-            make_move: F,
-            bw_scale: f64,
-            cache: &mut MonteCarloCache,
-        )
-            -> Outcome
-            where F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>
-        {
-            cache.claimed_rivers.clear();
-            let mut rng = rand::thread_rng();
-            let mut turn_counter = 0;
-            loop {
-                // check if journey is finished or blocked
-                let mut finished = true;
-                for river in cache.route_rivers.iter() {
-                    match cache.claimed_rivers.get(river) {
-                        Some(&river_owner) if river_owner == my_punter =>
-                            continue,
-                        Some(..) =>
-                            return Outcome::Fail,
-                        None => {
-                            finished = false;
-                            break;
-                        }
-                    }
+    Some(with_workers(playouts, |workers| estimate_success(journey, Position::initial(start_turn), early_stop, workers)))
+}
+
+fn estimate_success<'w, 'p, O, F>(
+    journey: &[SiteId],
+    position: Position<'p>,
+    early_stop: Option<EarlyStop>,
+    workers: &mut Workers<'w, 'p, O, F>,
+)
+    -> SuccessEstimate
+    where O: OpponentModel,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>,
+{
+    workers.evaluations += 1;
+    let route = Arc::new(journey_rivers(journey));
+    let max_games = workers.sim.games_count;
+    let batch_games = match early_stop {
+        Some(stop) =>
+            stop.batch_games.max(1),
+        None =>
+            max_games.div_ceil(workers.count()).max(1),
+    };
+    let mut estimate = SuccessEstimate { games: 0, successes: 0, };
+    while estimate.games < max_games {
+        // every worker gets a whole batch, and batches are accounted in order, so the estimate
+        // does not depend on the workers count
+        let batches: Vec<_> = (0 .. workers.count())
+            .map(|batch| estimate.games + batch * batch_games)
+            .take_while(|&start| start < max_games)
+            .map(|start| start .. min(start + batch_games, max_games))
+            .collect();
+        let jobs = batches
+            .iter()
+            .map(|games| Job::Route { route: route.clone(), position: position, games: games.clone(), })
+            .collect();
+        for (games, successes) in batches.into_iter().zip(workers.run(jobs)) {
+            estimate.games += games.end - games.start;
+            estimate.successes += successes as usize;
+            if early_stop.map(|stop| stop.is_decided(&estimate)).unwrap_or(false) {
+                return estimate;
+            }
+        }
+    }
+    estimate
+}
+
+// Game state playouts start from.
+#[derive(Clone, Copy)]
+struct Position<'a> {
+    claimed_rivers: Option<&'a RiversIndex<PunterId>>,
+    // turns counter value for the first move of a playout
+    first_turn: usize,
+    // my moves are skipped until turns counter reaches this value
+    start_turn: usize,
+}
+
+impl<'a> Position<'a> {
+    fn initial(start_turn: usize) -> Position<'a> {
+        Position {
+            claimed_rivers: None,
+            first_turn: 0,
+            start_turn: start_turn,
+        }
+    }
+}
+
+enum Outcome { Success, Fail, }
+
+impl<'s, O, F> Sim<'s, O, F>
+    where O: OpponentModel,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>,
+{
+    fn run(&self, job: Job, cache: &mut MonteCarloCache) -> i64 {
+        match job {
+            Job::Route { route, position, games, } =>
+                games
+                    .filter(|&game| match self.play(&route, &position, &mut rng::make_rng(self.seed, game as u64), cache) {
+                        Outcome::Success =>
+                            true,
+                        Outcome::Fail =>
+                            false,
+                    })
+                    .count() as i64,
+            Job::Joint { routes, games, } =>
+                games
+                    .map(|game| self.play_joint(&routes, &mut rng::make_rng(self.seed, game as u64), cache))
+                    .sum(),
+        }
+    }
+
+    fn play<R>(&self, route: &[River], position: &Position, rng: &mut R, cache: &mut MonteCarloCache) -> Outcome where R: Rng {
+        let my_punter = self.my_punter;
+        cache.claimed_rivers.clear();
+        if let Some(claimed_rivers) = position.claimed_rivers {
+            cache.claimed_rivers.extend(claimed_rivers.iter().map(|(river, &punter)| (river.clone(), punter)));
+        }
+        let mut turn_counter = position.first_turn;
+        loop {
+            // check if journey is finished or blocked
+            if let Some(outcome) = route_outcome(route, &cache.claimed_rivers, my_punter) {
+                return outcome;
+            }
+
+            let turn = turn_counter % self.punters_count;
+            if (turn_counter >= position.start_turn) && (turn == my_punter as usize) {
+                // it's a my turn, perform a move
+                if let Some(river) = (self.make_move)(route, &cache.claimed_rivers) {
+                    cache.claimed_rivers.insert(river.clone(), my_punter);
                 }
-                if finished {
-                    return Outcome::Success;
+            } else {
+                // it's an enemy turn, simulate a move
+                let enemy_punter = turn as PunterId;
+                if let Some(river) = self.opponent.choose_river(enemy_punter, &cache.claimed_rivers, &mut cache.weighted, rng) {
+                    cache.claimed_rivers.insert(river, enemy_punter);
                 }
+            }
+
+            turn_counter += 1;
+        }
+    }
 
-                let turn = turn_counter % punters_count;
-                if (turn_counter >= start_turn) && (turn == my_punter as usize) {
-                    // it's a my turn, perform a move
-                    if let Some(river) = make_move(&cache.route_rivers, &cache.claimed_rivers) {
-                        cache.claimed_rivers.insert(river.clone(), my_punter);
+    fn play_joint<R>(&self, routes: &Routes, rng: &mut R, cache: &mut MonteCarloCache) -> i64 where R: Rng {
+        let my_punter = self.my_punter;
+        cache.claimed_rivers.clear();
+        cache.route_outcomes.clear();
+        cache.route_outcomes.extend(routes.bounds.iter().map(|_| None));
+        let mut turn_counter = 0;
+        loop {
+            // update futures outcomes and find the first one still in progress
+            let mut current = None;
+            for (index, &(start, end)) in routes.bounds.iter().enumerate() {
+                if cache.route_outcomes[index].is_none() {
+                    let outcome = route_outcome(&routes.rivers[start .. end], &cache.claimed_rivers, my_punter);
+                    if outcome.is_none() && current.is_none() {
+                        current = Some((start, end));
                     }
-                } else {
-                    // it's an enemy turn, simulate a move
-                    let enemy_punter = turn as PunterId;
-
-                    // prepare distribution set from non-claimed rivers
-                    let river = {
-                        cache.weighted.clear();
-                        let claimed_rivers = &cache.claimed_rivers;
-                        cache.weighted.extend(
-                            rivers_bw
-                                .iter()
-                                .filter(|&(river, _)| !claimed_rivers.contains_key(river))
-                                .map(|(river, bw_coeff)| Weighted {
-                                    weight: (bw_coeff * bw_scale) as u32,
-                                    item: river.clone(),
-                                }));
-                        let choice = WeightedChoice::new(&mut cache.weighted);
-                        // pick a river
-                        choice.ind_sample(&mut rng)
-                    };
-                    cache.claimed_rivers.insert(river, enemy_punter);
+                    cache.route_outcomes[index] = outcome;
                 }
+            }
+            let (start, end) = if let Some(bounds) = current {
+                bounds
+            } else {
+                return routes.bounds
+                    .iter()
+                    .zip(cache.route_outcomes.iter())
+                    .map(|(&(start, end), outcome)| {
+                        let cost = (end - start) as i64;
+                        match *outcome {
+                            Some(Outcome::Success) =>
+                                cost * cost * cost,
+                            _ =>
+                                -(cost * cost * cost),
+                        }
+                    })
+                    .sum();
+            };
 
-                turn_counter += 1;
+            let turn = turn_counter % self.punters_count;
+            if turn == my_punter as usize {
+                // it's a my turn, advance the current future
+                if let Some(river) = (self.make_move)(&routes.rivers[start .. end], &cache.claimed_rivers) {
+                    cache.claimed_rivers.insert(river.clone(), my_punter);
+                }
+            } else {
+                // it's an enemy turn, simulate a move
+                let enemy_punter = turn as PunterId;
+                if let Some(river) = self.opponent.choose_river(enemy_punter, &cache.claimed_rivers, &mut cache.weighted, rng) {
+                    cache.claimed_rivers.insert(river, enemy_punter);
+                }
             }
+
+            turn_counter += 1;
         }
+    }
+}
 
-        match play(rivers_bw, my_punter, punters_count, start_turn, &make_move, bw_scale, cache) {
-            Outcome::Success =>
-                success_count += 1,
-            Outcome::Fail =>
-                (),
+fn route_outcome(route_rivers: &[River], claimed_rivers: &RiversIndex<PunterId>, my_punter: PunterId) -> Option<Outcome> {
+    for river in route_rivers.iter() {
+        match claimed_rivers.get(river) {
+            Some(&river_owner) if river_owner == my_punter =>
+                continue,
+            Some(..) =>
+                return Some(Outcome::Fail),
+            None =>
+                return None,
         }
     }
-    Some(success_count as f64 / games_count as f64)
+    Some(Outcome::Success)
 }
 
-pub fn estimate_best_future<F>(
+#[derive(Clone, PartialEq, Debug)]
+pub struct FutureProgress {
+    pub source: SiteId,
+    pub target: SiteId,
+    // cheapest route through my and free rivers, `None` if the target is cut off
+    pub path: Option<Vec<SiteId>>,
+    pub rivers_left: usize,
+    // `None` if there is nothing to simulate, or the budget has run out before this future
+    pub estimate: Option<SuccessEstimate>,
+}
+
+impl FutureProgress {
+    // `None` when the chance is unknown, because the future has not been simulated
+    pub fn success_probability(&self) -> Option<f64> {
+        if self.path.is_none() {
+            Some(0.0)
+        } else if self.rivers_left == 0 {
+            Some(1.0)
+        } else {
+            self.estimate.map(|estimate| estimate.probability())
+        }
+    }
+}
+
+// Re-estimates completion chances of declared futures in the middle of a game: `claimed_rivers` are the rivers
+// claimed so far and it is `playouts.my_punter` turn to move now.
+pub fn futures_progress<O, F>(
+    graph: &Graph,
+    futures: &[(SiteId, SiteId)],
+    claimed_rivers: &RiversIndex<PunterId>,
+    early_stop: Option<EarlyStop>,
+    budget: Budget,
+    playouts: &mut Playouts<O, F>,
+    bcache: &mut BidirCache,
+)
+    -> Vec<FutureProgress>
+    where O: OpponentModel + Sync,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River> + Sync,
+{
+    let my_punter = playouts.my_punter;
+    let punters_count = playouts.punters_count;
+    let position = Position {
+        claimed_rivers: Some(claimed_rivers),
+        first_turn: my_punter as usize,
+        start_turn: 0,
+    };
+    with_workers(playouts, |workers| {
+        let allowance = Allowance::new(budget, workers);
+        futures
+            .iter()
+            .map(|&(source, target)| {
+                let path = graph.shortest_path_bidir(source, target, bcache, |(s, t)| {
+                    match claimed_rivers.get(&River::new(s, t)) {
+                        Some(&punter) if punter == my_punter =>
+                            EdgeAttr::Accessible { edge_cost: 0, },
+                        Some(..) =>
+                            EdgeAttr::Blocked,
+                        None =>
+                            EdgeAttr::Accessible { edge_cost: 1, },
+                    }
+                }).map(|path| path.to_owned());
+                let rivers_left = path
+                    .as_ref()
+                    .map(|path| path
+                         .windows(2)
+                         .filter(|hop| !claimed_rivers.contains_key(&River::new(hop[0], hop[1])))
+                         .count())
+                    .unwrap_or(0);
+                let estimate = match path {
+                    Some(ref path) if (rivers_left > 0) && (punters_count > 1) && allowance.left(workers).is_some() =>
+                        Some(estimate_success(path, position, early_stop, workers)),
+                    _ =>
+                        None,
+                };
+                FutureProgress {
+                    source: source,
+                    target: target,
+                    path: path,
+                    rivers_left: rivers_left,
+                    estimate: estimate,
+                }
+            })
+            .collect()
+    })
+}
+
+// How future candidates are looked for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Search {
+    // futures which fewer enemy claims could break are ranked lower, see `fragility_penalty`
+    pub min_connectivity: usize,
+    pub candidates_per_mine: usize,
+    pub budget: Budget,
+}
+
+// Limits a search either by wall-clock time, or by the number of estimations made (each one is a single
+// future or a set of futures simulated for up to `games_count` games). The latter does not depend
+// on the machine speed, so the search result is the same given the same seed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Budget {
+    Time(time::Duration),
+    Evaluations(usize),
+}
+
+impl Budget {
+    fn divide(self, parts: usize) -> Budget {
+        match self {
+            Budget::Time(limit) =>
+                Budget::Time(limit / parts.max(1) as u32),
+            Budget::Evaluations(count) =>
+                Budget::Evaluations(count / parts.max(1)),
+        }
+    }
+}
+
+// What is left of a `Budget` spent by workers since the moment it is made.
+struct Allowance {
+    budget: Budget,
+    time_start: time::Instant,
+    evaluations_start: usize,
+}
+
+impl Allowance {
+    fn new<O, F>(budget: Budget, workers: &Workers<O, F>) -> Allowance {
+        Allowance {
+            budget: budget,
+            time_start: time::Instant::now(),
+            evaluations_start: workers.evaluations,
+        }
+    }
+
+    // `None` when the budget is exhausted
+    fn left<O, F>(&self, workers: &Workers<O, F>) -> Option<Budget> {
+        match self.budget {
+            Budget::Time(limit) =>
+                limit.checked_sub(self.time_start.elapsed()).map(Budget::Time),
+            Budget::Evaluations(count) =>
+                count.checked_sub(workers.evaluations - self.evaluations_start)
+                    .and_then(|left| if left > 0 { Some(Budget::Evaluations(left)) } else { None }),
+        }
+    }
+}
+
+pub fn estimate_best_future<O, F>(
     graph: &Graph,
     mine: SiteId,
     mines: &[SiteId],
-    rivers_bw: &RiversIndex<f64>,
-    my_punter: PunterId,
-    punters_count: usize,
     start_turn: usize,
-    make_move: F,
-    games_count: usize,
-    time_limit: time::Duration,
-    mcache: &mut MonteCarloCache,
+    search: Search,
+    playouts: &mut Playouts<O, F>,
     gcache: &mut GraphCache<f64>,
 )
     -> Option<(SiteId, SiteId, usize)>
-    where F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>
+    where O: OpponentModel + Sync,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River> + Sync,
 {
-    let mut best = None;
-    let timeout_start = time::Instant::now();
+    let search = Search { candidates_per_mine: 1, ..search };
+    with_workers(playouts, |workers| future_candidates(graph, mine, mines, start_turn, search, workers, gcache))
+        .into_iter()
+        .next()
+        .map(|fut| (fut.source, fut.target, fut.path.len()))
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct FutureCandidate {
+    pub source: SiteId,
+    pub target: SiteId,
+    pub path: Vec<SiteId>,
+    pub estimate: SuccessEstimate,
+    // fragile futures get it scaled down, see `fragility_penalty`
+    pub expected_reward: f64,
+}
+
+impl FutureCandidate {
+    pub fn cost(&self) -> usize {
+        self.path.len().saturating_sub(1)
+    }
+}
+
+// Collects up to `search.candidates_per_mine` futures for `mine`, best expected reward first.
+pub fn estimate_future_candidates<O, F>(
+    graph: &Graph,
+    mine: SiteId,
+    mines: &[SiteId],
+    start_turn: usize,
+    search: Search,
+    playouts: &mut Playouts<O, F>,
+    gcache: &mut GraphCache<f64>,
+)
+    -> Vec<FutureCandidate>
+    where O: OpponentModel + Sync,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River> + Sync,
+{
+    with_workers(playouts, |workers| future_candidates(graph, mine, mines, start_turn, search, workers, gcache))
+}
+
+fn future_candidates<O, F>(
+    graph: &Graph,
+    mine: SiteId,
+    mines: &[SiteId],
+    start_turn: usize,
+    search: Search,
+    workers: &mut Workers<O, F>,
+    gcache: &mut GraphCache<f64>,
+)
+    -> Vec<FutureCandidate>
+    where O: OpponentModel,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>,
+{
+    let max_candidates = search.candidates_per_mine;
+    let max_games = workers.sim.games_count;
+    let mut candidates: Vec<FutureCandidate> = Vec::new();
+    let mut saved_games = 0;
+    let mut fcache = FlowCache::default();
+    let allowance = Allowance::new(search.budget, workers);
     graph.generic_bfs(mine, 0.0, |path, cost, prev_reward| {
-        if timeout_start.elapsed() > time_limit {
+        if allowance.left(workers).is_none() {
             return StepCommand::Terminate;
         }
         if let (Some(&source), Some(&target)) = (path.first(), path.last()) {
             if mines.iter().any(|&m| m == target) {
                 StepCommand::Continue(0.0)
-            } else {
-                let maybe_prob = journey_success_simulate(
-                    path,
-                    rivers_bw,
-                    my_punter,
-                    punters_count,
-                    start_turn,
-                    &make_move,
-                    games_count,
-                    mcache);
-                if let Some(prob) = maybe_prob {
-                    let regular_reward = cost * cost;
-                    let future_reward = cost * cost * cost;
-                    let expected_reward = future_reward as f64 * prob;
-                    // check if it is worth to take this future
-                    if expected_reward < regular_reward as f64 {
-                        StepCommand::Continue(0.0)
-                    } else {
-                        // track the best future candidate
-                        best = Some(if let Some((best_reward, best_fut)) = best.take() {
-                            if best_reward < expected_reward {
-                                (expected_reward, (source, target, path.len()))
-                            } else {
-                                (best_reward, best_fut)
-                            }
-                        } else {
-                            (expected_reward, (source, target, path.len()))
+            } else if (path.len() > 1) && (workers.sim.punters_count > 1) {
+                let estimate =
+                    estimate_success(path, Position::initial(start_turn), Some(EarlyStop::for_future_cost(cost)), workers);
+                // check if it is worth to take this future
+                if let Some(expected_reward) = expected_future_reward(cost, &estimate) {
+                    // track the best future candidates, fragile ones are ranked lower
+                    let penalty = fragility_penalty(graph, source, target, search.min_connectivity, &mut fcache);
+                    let ranked_reward = expected_reward * penalty;
+                    let place = candidates
+                        .iter()
+                        .position(|fut| fut.expected_reward < ranked_reward)
+                        .unwrap_or(candidates.len());
+                    if place < max_candidates {
+                        candidates.insert(place, FutureCandidate {
+                            source: source,
+                            target: target,
+                            path: path.to_owned(),
+                            estimate: estimate,
+                            expected_reward: ranked_reward,
                         });
-                        // check if there is no sense to move futher
-                        if &expected_reward > prev_reward {
-                            StepCommand::Continue(expected_reward)
-                        } else {
-                            StepCommand::Stop
-                        }
+                        candidates.truncate(max_candidates);
+                    }
+                    // check if there is no sense to move futher
+                    if &expected_reward > prev_reward {
+                        StepCommand::Continue(expected_reward)
+                    } else {
+                        StepCommand::Stop
                     }
                 } else {
-                    StepCommand::Stop
+                    saved_games += max_games - estimate.games;
+                    StepCommand::Continue(0.0)
                 }
+            } else {
+                StepCommand::Stop
             }
         } else {
             StepCommand::Stop
         }
     }, EdgeAttr::standard, gcache);
 
-    best.map(|v| v.1)
+    // games saved on rejected futures are shared among the kept ones, so the best are told apart more reliably
+    let extra_games = min(saved_games / max(candidates.len(), 1), max_games);
+    if extra_games > 0 {
+        for fut in candidates.iter_mut() {
+            if allowance.left(workers).is_none() {
+                break;
+            }
+            workers.evaluations += 1;
+            let route = Arc::new(journey_rivers(&fut.path));
+            let games = fut.estimate.games .. fut.estimate.games + extra_games;
+            fut.estimate.successes += workers.simulate_games(&route, Position::initial(start_turn), games);
+            fut.estimate.games += extra_games;
+            fut.expected_reward = expected_future_reward(fut.cost(), &fut.estimate)
+                .map(|expected_reward| {
+                    expected_reward * fragility_penalty(graph, fut.source, fut.target, search.min_connectivity, &mut fcache)
+                })
+                .unwrap_or(0.0);
+        }
+        candidates.retain(|fut| fut.expected_reward > 0.0);
+        candidates.sort_by(|a, b| b.expected_reward.partial_cmp(&a.expected_reward).unwrap_or(Ordering::Equal));
+    }
+
+    candidates
+}
+
+// Future reward `cost³·p` of completing a future with success `estimate`, `None` if it does not beat
+// the regular reward `cost²` of claiming its rivers.
+fn expected_future_reward(cost: usize, estimate: &SuccessEstimate) -> Option<f64> {
+    let regular_reward = cost * cost;
+    let future_reward = cost * cost * cost;
+    let expected_reward = future_reward as f64 * estimate.probability();
+    if expected_reward < regular_reward as f64 {
+        None
+    } else {
+        Some(expected_reward)
+    }
+}
+
+// Futures which fewer than `min_connectivity` enemy claims could break (like the ones behind a bridge) are
+// still worth taking, but their expected reward is scaled down in proportion to the claims required.
+fn fragility_penalty(graph: &Graph, source: SiteId, target: SiteId, min_connectivity: usize, fcache: &mut FlowCache) -> f64 {
+    if min_connectivity < 2 {
+        return 1.0;
+    }
+    let connectivity = graph.rivers_connectivity(source, target, min_connectivity, fcache, EdgeAttr::standard);
+    min(connectivity, min_connectivity) as f64 / min_connectivity as f64
+}
+
+// Picks a set of futures (at most one per mine) maximizing the combined expected bonus and penalty,
+// simulating them together so overlapping routes share rivers. Result is ordered for execution.
+pub fn select_joint_futures<O, F>(
+    graph: &Graph,
+    mines: &[SiteId],
+    search: Search,
+    playouts: &mut Playouts<O, F>,
+    gcache: &mut GraphCache<f64>,
+)
+    -> Vec<FutureCandidate>
+    where O: OpponentModel + Sync,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River> + Sync,
+{
+    with_workers(playouts, |workers| joint_futures(graph, mines, search, workers, gcache))
+}
+
+fn joint_futures<O, F>(graph: &Graph, mines: &[SiteId], search: Search, workers: &mut Workers<O, F>, gcache: &mut GraphCache<f64>)
+                       -> Vec<FutureCandidate>
+    where O: OpponentModel,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>,
+{
+    let allowance = Allowance::new(search.budget, workers);
+
+    // spend the first half of the budget on gathering candidates for each mine
+    let gathering = Allowance::new(search.budget.divide(2), workers);
+    let mut candidates = Vec::new();
+    for (index, &mine) in mines.iter().enumerate() {
+        if let Some(budget_left) = gathering.left(workers) {
+            let mine_budget = budget_left.divide(mines.len() - index);
+            debug!("gathering future candidates for mine {}, {:?} budget", mine, mine_budget);
+            let mine_search = Search { budget: mine_budget, ..search };
+            candidates.extend(future_candidates(graph, mine, mines, 0, mine_search, workers, gcache));
+        } else {
+            debug!("no more budget for gathering future candidates");
+            break;
+        }
+    }
+    debug!("gathered {} future candidates", candidates.len());
+
+    grow_joint_futures(candidates, &allowance, workers)
+}
+
+// Greedily grows the set of futures while the joint value increases. Candidates are tried best single
+// value first, and the best one is taken even before any joint evaluation, so running out of `allowance`
+// does not leave the set empty.
+fn grow_joint_futures<O, F>(mut candidates: Vec<FutureCandidate>, allowance: &Allowance, workers: &mut Workers<O, F>)
+                            -> Vec<FutureCandidate>
+    where O: OpponentModel,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>,
+{
+    candidates.sort_by(|a, b| single_future_value(b).partial_cmp(&single_future_value(a)).unwrap_or(Ordering::Equal));
+    let mut selected: Vec<FutureCandidate> = Vec::new();
+    let mut selected_value = 0.0;
+    if candidates.first().map(|fut| single_future_value(fut) > 0.0).unwrap_or(false) {
+        let candidate = candidates.remove(0);
+        selected_value = single_future_value(&candidate);
+        debug!("joint future from {} to {} selected first, expected value {}", candidate.source, candidate.target, selected_value);
+        selected.push(candidate);
+    }
+
+    loop {
+        let mut best: Option<(f64, usize)> = None;
+        let mut timed_out = false;
+        for (index, candidate) in candidates.iter().enumerate() {
+            if selected.iter().any(|fut| fut.source == candidate.source) {
+                continue;
+            }
+            if allowance.left(workers).is_none() {
+                debug!("joint futures selection is out of budget");
+                timed_out = true;
+                break;
+            }
+            let mut trial: Vec<_> = selected.iter().chain(Some(candidate)).cloned().collect();
+            order_for_execution(&mut trial);
+            let value = joint_value(&trial, workers);
+            if value > best.map(|b| b.0).unwrap_or(selected_value) {
+                best = Some((value, index));
+            }
+        }
+        // the best one of the round is kept even if the round is cut short
+        if let Some((value, index)) = best {
+            let candidate = candidates.swap_remove(index);
+            debug!("joint future from {} to {} selected, expected value {} -> {}",
+                   candidate.source, candidate.target, selected_value, value);
+            selected.push(candidate);
+            selected_value = value;
+        }
+        if best.is_none() || timed_out {
+            break;
+        }
+    }
+
+    order_for_execution(&mut selected);
+    selected
+}
+
+// Joint value of a future taken alone: `cost³·p - cost³·(1 - p)`, with the success chance of
+// a fragile future scaled down by its penalty.
+fn single_future_value(fut: &FutureCandidate) -> f64 {
+    let cost = fut.cost();
+    2.0 * fut.expected_reward - (cost * cost * cost) as f64
 }
 
+// Shorter futures go first: they are exposed to enemy claims for the least number of turns.
+fn order_for_execution(futures: &mut [FutureCandidate]) {
+    futures.sort_by_key(|fut| (fut.path.len(), fut.source, fut.target));
+}
+
+// Average over simulated games of the summed futures score: `cost³` for each completed future and `-cost³` for each failed one.
+pub fn joint_futures_value<O, F>(futures: &[FutureCandidate], playouts: &mut Playouts<O, F>) -> f64
+    where O: OpponentModel + Sync,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River> + Sync,
+{
+    with_workers(playouts, |workers| joint_value(futures, workers))
+}
+
+fn joint_value<O, F>(futures: &[FutureCandidate], workers: &mut Workers<O, F>) -> f64
+    where O: OpponentModel,
+          F: for<'a> Fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>,
+{
+    let games_count = workers.sim.games_count;
+    if futures.is_empty() || (workers.sim.punters_count < 2) || (games_count == 0) {
+        return 0.0;
+    }
+
+    workers.evaluations += 1;
+    let routes = Arc::new(Routes::new(futures));
+    let jobs = split_games(0 .. games_count, workers.count())
+        .map(|games| Job::Joint { routes: routes.clone(), games: games, })
+        .collect();
+    let score: i64 = workers.run(jobs).into_iter().sum();
+    score as f64 / games_count as f64
+}
+
+
 #[cfg(test)]
 mod test {
     use std::time::Duration;
     use std::collections::HashSet;
-    use super::super::types::PunterId;
+    use rand;
+    use super::super::types::{PunterId, SiteId};
+    use super::super::proto::Move;
     use super::super::graph::Graph;
     use super::super::map::{River, RiversIndex};
     use super::super::test_common::*;
-    use super::{journey_success_simulate, estimate_best_future};
+    use super::{
+        MonteCarloCache,
+        Playouts,
+        Search,
+        Budget,
+        Allowance,
+        SuccessEstimate,
+        EarlyStop,
+        journey_success_simulate,
+        journey_success_estimate,
+        estimate_best_future,
+        estimate_future_candidates,
+        select_joint_futures,
+        grow_joint_futures,
+        with_workers,
+        joint_futures_value,
+        FutureCandidate,
+        futures_progress,
+        OpponentModel,
+        UniformOpponent,
+        BetweennessOpponent,
+        GreedyNearestOpponent,
+        ReplayStats,
+        ReplayStatsOpponent,
+    };
 
     fn sample_map() -> (Graph, RiversIndex<f64>) {
         let graph = Graph::from_iter(
@@ -230,26 +1253,162 @@ mod test {
         route.iter().find(|river| !claimed_rivers.contains_key(river))
     }
 
+    type MakeMove = for<'a> fn(&'a [River], &RiversIndex<PunterId>) -> Option<&'a River>;
+
+    fn playouts<'a, O>(opponent: &'a O, my_punter: PunterId, punters_count: usize, games_count: usize, caches: &'a mut [MonteCarloCache])
+                       -> Playouts<'a, O, MakeMove>
+    {
+        Playouts::new(opponent, my_punter, punters_count, make_move, games_count, caches)
+    }
+
+    fn search(min_connectivity: usize, candidates_per_mine: usize) -> Search {
+        Search {
+            min_connectivity: min_connectivity,
+            candidates_per_mine: candidates_per_mine,
+            budget: Budget::Time(Duration::from_millis(5000)),
+        }
+    }
+
+    fn sample_map_rivers() -> Vec<River> {
+        [(3, 4), (0, 1), (2, 3), (1, 3), (5, 6), (4, 5), (3, 5), (6, 7), (5, 7), (1, 7), (0, 7), (1, 2)]
+            .iter()
+            .map(|&(s, t)| River::new(s, t))
+            .collect()
+    }
+
+    fn choose_all<O>(opponent: &O, claimed_rivers: &mut RiversIndex<PunterId>) -> Vec<River> where O: OpponentModel {
+        let mut rng = rand::thread_rng();
+        let mut weighted = Vec::new();
+        let mut chosen = Vec::new();
+        while let Some(river) = opponent.choose_river(1, claimed_rivers, &mut weighted, &mut rng) {
+            assert!(!claimed_rivers.contains_key(&river));
+            claimed_rivers.insert(river.clone(), 1);
+            chosen.push(river);
+        }
+        chosen
+    }
+
+    #[test]
+    fn uniform_opponent() {
+        let rivers = sample_map_rivers();
+        let opponent = UniformOpponent::new(&rivers);
+        let mut claimed_rivers = RiversIndex::default();
+        claimed_rivers.insert(River::new(1, 3), 0);
+        let chosen = choose_all(&opponent, &mut claimed_rivers);
+        assert_eq!(chosen.len(), rivers.len() - 1);
+        assert!(!chosen.contains(&River::new(1, 3)));
+    }
+
+    #[test]
+    fn greedy_nearest_opponent() {
+        let (graph, _) = sample_map();
+        let opponent = GreedyNearestOpponent::new(&graph, &sample_map_rivers(), &[1]);
+        let chosen = choose_all(&opponent, &mut RiversIndex::default());
+        let (near, far) = chosen.split_at(4);
+        assert!(near.iter().all(|river| river.source == 1 || river.target == 1));
+        assert!(far.iter().all(|river| river.source != 1 && river.target != 1));
+        let mut farthest = far[6 ..].to_owned();
+        farthest.sort_by_key(|river| (river.source, river.target));
+        assert_eq!(farthest, vec![River::new(4, 5), River::new(5, 6)]);
+    }
+
+    #[test]
+    fn replay_stats_opponent() {
+        let (graph, _) = sample_map();
+        let rivers = sample_map_rivers();
+        let mut stats = ReplayStats::default();
+        let moves = vec![
+            Move::Claim { punter: 0, source: 1, target: 0, },
+            Move::Claim { punter: 1, source: 1, target: 3, },
+            Move::Pass { punter: 0, },
+            Move::Splurge { punter: 1, route: vec![1, 2, 3], },
+            Move::Option { punter: 0, source: 1, target: 7, },
+        ];
+        stats.learn(&graph, &rivers, &[1], &moves, Some(1));
+        assert_eq!(stats.claim_rate(0), 3.0 / 5.0);
+        assert_eq!(stats.claim_rate(1), 1.0 / 7.0);
+        assert_eq!(stats.claim_rate(100), 1.0);
+        stats.learn(&graph, &rivers, &[1], &moves, None);
+        assert_eq!(stats.claim_rate(0), 7.0 / 9.0);
+        assert_eq!(stats.claim_rate(1), 2.0 / 13.0);
+
+        let opponent = ReplayStatsOpponent::new(&stats, &graph, &rivers, &[1]);
+        assert_eq!(choose_all(&opponent, &mut RiversIndex::default()).len(), rivers.len());
+    }
+
+    #[test]
+    fn sample_map_simulation_opponent_models() {
+        let (graph, _) = sample_map();
+        let rivers = sample_map_rivers();
+        let mut mcaches = MonteCarloCache::pool(1);
+
+        // enemy moves first: uniform one breaks the route with 1/12 chance, greedy one with 1/4 chance
+        let uniform = UniformOpponent::new(&rivers);
+        let prob = journey_success_simulate(&[1, 0], 0, &mut playouts(&uniform, 1, 2, 10000, &mut mcaches)).unwrap();
+        assert!(prob > 0.85);
+        let greedy = GreedyNearestOpponent::new(&graph, &rivers, &[1]);
+        let prob = journey_success_simulate(&[1, 0], 0, &mut playouts(&greedy, 1, 2, 10000, &mut mcaches)).unwrap();
+        assert!(prob < 0.85);
+    }
+
     #[test]
     fn sample_map_simulation_always_success() {
         let (_, rivers_bw) = sample_map();
-        let mut mcache = Default::default();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut mcaches = MonteCarloCache::pool(1);
+        let mut playouts = playouts(&opponent, 0, 2, 100, &mut mcaches);
 
-        let prob = journey_success_simulate(&[1, 0], &rivers_bw, 0, 2, 0, make_move, 100, &mut mcache);
+        let prob = journey_success_simulate(&[1, 0], 0, &mut playouts);
         assert_eq!(prob.map(|v| (v * 100.0) as usize), Some(100));
-        let prob = journey_success_simulate(&[1, 2], &rivers_bw, 0, 2, 0, make_move, 100, &mut mcache);
+        let prob = journey_success_simulate(&[1, 2], 0, &mut playouts);
         assert_eq!(prob.map(|v| (v * 100.0) as usize), Some(100));
-        let prob = journey_success_simulate(&[1, 3], &rivers_bw, 0, 2, 0, make_move, 100, &mut mcache);
+        let prob = journey_success_simulate(&[1, 3], 0, &mut playouts);
         assert_eq!(prob.map(|v| (v * 100.0) as usize), Some(100));
-        let prob = journey_success_simulate(&[1, 7], &rivers_bw, 0, 2, 0, make_move, 100, &mut mcache);
+        let prob = journey_success_simulate(&[1, 7], 0, &mut playouts);
         assert_eq!(prob.map(|v| (v * 100.0) as usize), Some(100));
     }
 
+    #[test]
+    fn sample_map_parallel_simulation() {
+        let (_, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut mcaches = MonteCarloCache::pool(3);
+
+        let prob = journey_success_simulate(&[1, 0], 0, &mut playouts(&opponent, 0, 2, 100, &mut mcaches));
+        assert_eq!(prob.map(|v| (v * 100.0) as usize), Some(100));
+        let prob = journey_success_simulate(&[1], 0, &mut playouts(&opponent, 0, 2, 100, &mut mcaches));
+        assert_eq!(prob, None);
+
+        // every game is seeded by its index, so splitting among workers does not affect the result
+        let seq_prob =
+            journey_success_simulate(&[1, 3, 5], 0, &mut playouts(&opponent, 1, 2, 10000, &mut mcaches[.. 1]).seed(17)).unwrap();
+        let par_prob =
+            journey_success_simulate(&[1, 3, 5], 0, &mut playouts(&opponent, 1, 2, 10000, &mut mcaches).seed(17)).unwrap();
+        assert_eq!(seq_prob, par_prob);
+        let other_prob =
+            journey_success_simulate(&[1, 3, 5], 0, &mut playouts(&opponent, 1, 2, 10000, &mut mcaches).seed(18)).unwrap();
+        assert!(other_prob != par_prob);
+        assert!((other_prob - par_prob).abs() < 0.05);
+    }
+
+    #[test]
+    #[should_panic(expected = "make_move is broken")]
+    fn sample_map_worker_panic() {
+        let (_, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut mcaches = MonteCarloCache::pool(3);
+
+        let mut playouts = Playouts::new(&opponent, 0, 2, |_, _| panic!("make_move is broken"), 100, &mut mcaches);
+        journey_success_simulate(&[1, 3, 5], 0, &mut playouts);
+    }
+
     #[test]
     fn sample_map_simulation() {
         let (graph, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
         let mut gcache = Default::default();
-        let mut mcache = Default::default();
+        let mut mcaches = MonteCarloCache::pool(1);
+        let mut playouts = playouts(&opponent, 1, 2, 10000, &mut mcaches);
 
         let all_other_sites: HashSet<_> = rivers_bw
             .iter()
@@ -260,7 +1419,7 @@ mod test {
             .filter(|&site| site != 1)
             .flat_map(|target| graph.shortest_path_only::<()>(1, target, &mut gcache).map(|v| v.to_owned()))
             .map(|route| {
-                let prob = journey_success_simulate(&route, &rivers_bw, 1, 2, 0, make_move, 10000, &mut mcache);
+                let prob = journey_success_simulate(&route, 0, &mut playouts);
                 (route, prob)
             })
             .collect();
@@ -284,26 +1443,243 @@ mod test {
         // ]
     }
 
+    #[test]
+    fn success_estimate_confidence_interval() {
+        let (lower, upper) = SuccessEstimate { games: 0, successes: 0, }.confidence_interval(1.96);
+        assert_eq!((lower, upper), (0.0, 1.0));
+        let (lower, upper) = SuccessEstimate { games: 100, successes: 50, }.confidence_interval(1.96);
+        assert_eq!(((lower * 1000.0) as usize, (upper * 1000.0) as usize), (403, 596));
+        let (lower, upper) = SuccessEstimate { games: 64, successes: 64, }.confidence_interval(1.96);
+        assert!(lower > 0.9);
+        assert_eq!(upper, 1.0);
+    }
+
+    #[test]
+    fn sample_map_estimate_early_stop() {
+        let (_, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut mcaches = MonteCarloCache::pool(2);
+
+        let estimate = journey_success_estimate(&[1, 0], 0, None, &mut playouts(&opponent, 0, 2, 10000, &mut mcaches));
+        assert_eq!(estimate, Some(SuccessEstimate { games: 10000, successes: 10000, }));
+        let stop = EarlyStop::for_future_cost(2);
+        let estimate = journey_success_estimate(&[1, 0], 0, Some(stop), &mut playouts(&opponent, 0, 2, 10000, &mut mcaches));
+        assert_eq!(estimate, Some(SuccessEstimate { games: 64, successes: 64, }));
+        let estimate = journey_success_estimate(&[1], 0, Some(stop), &mut playouts(&opponent, 0, 2, 10000, &mut mcaches));
+        assert_eq!(estimate, None);
+
+        // success rate is about 0.7, clearly above break even 0.5 but clearly below 0.95
+        let estimate = journey_success_estimate(&[1, 3, 5], 0, Some(stop), &mut playouts(&opponent, 1, 2, 10000, &mut mcaches)).unwrap();
+        assert!(estimate.games < 10000);
+        assert!(estimate.confidence_interval(stop.z).0 > stop.break_even);
+        let stop = EarlyStop { break_even: 0.95, ..stop };
+        let estimate = journey_success_estimate(&[1, 3, 5], 0, Some(stop), &mut playouts(&opponent, 1, 2, 10000, &mut mcaches)).unwrap();
+        assert!(estimate.games < 10000);
+        assert!(estimate.confidence_interval(stop.z).1 < stop.break_even);
+
+        // batches are accounted in order, so the workers count does not matter even when stopping early
+        let single = journey_success_estimate(&[1, 3, 5], 0, Some(stop), &mut playouts(&opponent, 1, 2, 10000, &mut mcaches[.. 1]));
+        assert_eq!(single, Some(estimate));
+    }
+
     #[test]
     fn sample_map_best_future() {
         let (graph, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
         let mut gcache = Default::default();
-        let mut mcache = Default::default();
+        let mut mcaches = MonteCarloCache::pool(4);
 
         let future =
-            estimate_best_future(&graph, 1, &[1, 5], &rivers_bw, 1, 2, 0, make_move,
-                                 10000, Duration::from_millis(5000), &mut mcache, &mut gcache).unwrap();
+            estimate_best_future(&graph, 1, &[1, 5], 0, search(0, 1), &mut playouts(&opponent, 1, 2, 10000, &mut mcaches), &mut gcache).unwrap();
         assert!((future.1 == 4) || (future.1 == 6));
     }
 
+    #[test]
+    fn fragile_future_penalized() {
+        // sites 3 and 4 are reachable from mine 0 only through the (2, 3) bridge
+        let graph = Graph::from_iter([(0, 1), (1, 2), (0, 2), (2, 3), (3, 4)].iter().cloned());
+        let mut gcache = Default::default();
+        let mut mcaches = MonteCarloCache::pool(1);
+        // enemies are mostly busy far away from the mine
+        let rivers: Vec<_> = (10 .. 100).map(|site| River::new(site, site + 1)).collect();
+        let opponent = UniformOpponent::new(&rivers);
+
+        let mut candidates = |min_connectivity| {
+            let mut candidates =
+                estimate_future_candidates(&graph, 0, &[0], 0, search(min_connectivity, 8),
+                                           &mut playouts(&opponent, 0, 2, 1000, &mut mcaches), &mut gcache);
+            candidates.sort_by_key(|fut| fut.path.clone());
+            candidates
+        };
+        let plain = candidates(1);
+        let penalized = candidates(2);
+        // fragile futures are still there, just valued less
+        assert!(penalized.iter().any(|fut| fut.target == 4));
+        for fut in penalized.iter() {
+            let same = plain.iter().find(|other| other.path == fut.path).unwrap();
+            let penalty = if fut.target == 3 || fut.target == 4 { 0.5 } else { 1.0 };
+            assert_eq!(fut.expected_reward, same.expected_reward * penalty);
+        }
+        // a mine with a single river still gets its future
+        let future =
+            estimate_best_future(&graph, 4, &[4], 0, search(2, 1), &mut playouts(&opponent, 0, 2, 1000, &mut mcaches), &mut gcache);
+        assert!(future.is_some());
+    }
+
+    fn candidate(path: &[SiteId]) -> FutureCandidate {
+        FutureCandidate {
+            source: path[0],
+            target: path[path.len() - 1],
+            path: path.to_owned(),
+            estimate: SuccessEstimate { games: 0, successes: 0, },
+            expected_reward: 0.0,
+        }
+    }
+
+    fn estimated(path: &[SiteId], success_percent: usize) -> FutureCandidate {
+        let fut = candidate(path);
+        let estimate = SuccessEstimate { games: 100, successes: success_percent, };
+        let cost = fut.cost();
+        FutureCandidate {
+            estimate: estimate,
+            expected_reward: (cost * cost * cost) as f64 * estimate.probability(),
+            ..fut
+        }
+    }
+
+    #[test]
+    fn sample_map_future_candidates() {
+        let (graph, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut gcache = Default::default();
+        let mut mcaches = MonteCarloCache::pool(2);
+
+        let candidates =
+            estimate_future_candidates(&graph, 1, &[1, 5], 0, search(0, 3), &mut playouts(&opponent, 1, 2, 1000, &mut mcaches), &mut gcache);
+        assert!(!candidates.is_empty() && candidates.len() <= 3);
+        assert!(candidates.windows(2).all(|w| w[0].expected_reward >= w[1].expected_reward));
+        assert!(candidates.iter().all(|fut| fut.source == 1 && fut.target != 5 && fut.cost() + 1 == fut.path.len()));
+        // games saved on rejected futures are spent on the kept ones
+        assert!(candidates.iter().all(|fut| fut.estimate.games > 1000));
+    }
+
+    #[test]
+    fn sample_map_joint_futures_value() {
+        let (_, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut mcaches = MonteCarloCache::pool(2);
+        let mut playouts = playouts(&opponent, 0, 2, 100, &mut mcaches);
+
+        assert_eq!(joint_futures_value(&[], &mut playouts), 0.0);
+        // the very first claim completes a single river future
+        assert_eq!(joint_futures_value(&[candidate(&[1, 0])], &mut playouts), 1.0);
+        // overlapping futures share the claimed river
+        let shared = [candidate(&[1, 0]), candidate(&[0, 1])];
+        assert_eq!(joint_futures_value(&shared, &mut playouts), 2.0);
+    }
+
+    #[test]
+    fn sample_map_joint_futures() {
+        let (graph, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut gcache = Default::default();
+        let mut mcaches = MonteCarloCache::pool(4);
+
+        let futures =
+            select_joint_futures(&graph, &[1, 5], search(0, 3), &mut playouts(&opponent, 1, 2, 1000, &mut mcaches), &mut gcache);
+        assert!(!futures.is_empty() && futures.len() <= 2);
+        assert!(futures.iter().all(|fut| fut.source == 1 || fut.source == 5));
+        assert!(futures.windows(2).all(|w| w[0].source != w[1].source && w[0].path.len() <= w[1].path.len()));
+    }
+
+    #[test]
+    fn sample_map_joint_futures_deterministic() {
+        let (graph, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut gcache = Default::default();
+        let mut mcaches = MonteCarloCache::pool(4);
+
+        // evaluations budget does not depend on timings, nor on the workers count
+        let search = Search { budget: Budget::Evaluations(64), ..search(0, 3) };
+        let futures = select_joint_futures(&graph, &[1, 5], search, &mut playouts(&opponent, 1, 2, 1000, &mut mcaches), &mut gcache);
+        assert!(!futures.is_empty());
+        for workers in 1 .. 4 {
+            let same = select_joint_futures(&graph, &[1, 5], search, &mut playouts(&opponent, 1, 2, 1000, &mut mcaches[.. workers]), &mut gcache);
+            assert_eq!(same, futures);
+        }
+    }
+
+    #[test]
+    fn sample_map_joint_futures_out_of_time() {
+        let (_, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut mcaches = MonteCarloCache::pool(2);
+
+        let likely = estimated(&[1, 3, 5], 90);
+        let unlikely = estimated(&[5, 6], 60);
+        let hopeless = estimated(&[7, 6], 40);
+        // no budget for joint evaluations at all, still the best single future is taken
+        let selected = with_workers(&mut playouts(&opponent, 1, 2, 100, &mut mcaches), |workers| {
+            let allowance = Allowance::new(Budget::Evaluations(0), workers);
+            grow_joint_futures(vec![hopeless.clone(), unlikely.clone(), likely.clone()], &allowance, workers)
+        });
+        assert_eq!(selected, vec![likely.clone()]);
+        // nothing is worth taking
+        let selected = with_workers(&mut playouts(&opponent, 1, 2, 100, &mut mcaches), |workers| {
+            let allowance = Allowance::new(Budget::Evaluations(0), workers);
+            grow_joint_futures(vec![hopeless.clone()], &allowance, workers)
+        });
+        assert_eq!(selected, vec![]);
+    }
+
+    #[test]
+    fn sample_map_futures_progress() {
+        let (graph, rivers_bw) = sample_map();
+        let opponent = BetweennessOpponent::new(&rivers_bw);
+        let mut mcaches = MonteCarloCache::pool(2);
+        let mut bcache = Default::default();
+
+        let mut claimed_rivers = RiversIndex::default();
+        claimed_rivers.insert(River::new(1, 3), 0);
+        claimed_rivers.insert(River::new(3, 4), 1);
+        claimed_rivers.insert(River::new(4, 5), 1);
+        let futures = [(1, 0), (1, 3), (1, 5), (1, 4)];
+        let progress = futures_progress(&graph, &futures, &claimed_rivers, Some(EarlyStop::at(0.5)), Budget::Evaluations(10),
+                                        &mut playouts(&opponent, 0, 2, 1000, &mut mcaches), &mut bcache);
+        assert_eq!(progress.len(), 4);
+        // it is my turn now, so single river future is completed right away
+        assert_eq!(progress[0].rivers_left, 1);
+        assert_eq!(progress[0].success_probability(), Some(1.0));
+        // already completed
+        assert_eq!(progress[1].rivers_left, 0);
+        assert_eq!(progress[1].estimate, None);
+        assert_eq!(progress[1].success_probability(), Some(1.0));
+        // half way done
+        assert_eq!(progress[2].path, Some(vec![1, 3, 5]));
+        assert_eq!(progress[2].rivers_left, 1);
+        assert_eq!(progress[2].success_probability(), Some(1.0));
+        // enemy cut the target off
+        assert_eq!(progress[3].path, None);
+        assert_eq!(progress[3].success_probability(), Some(0.0));
+
+        // only the first future fits into the budget
+        let progress = futures_progress(&graph, &futures, &claimed_rivers, Some(EarlyStop::at(0.5)), Budget::Evaluations(1),
+                                        &mut playouts(&opponent, 0, 2, 1000, &mut mcaches), &mut bcache);
+        assert_eq!(progress[0].success_probability(), Some(1.0));
+        assert_eq!(progress[2].estimate, None);
+        assert_eq!(progress[2].success_probability(), None);
+        assert_eq!(progress[3].success_probability(), Some(0.0));
+    }
+
     #[test]
     fn random_medium_map_best_future() {
         let graph = random_medium_map_graph();
         let mut gcache = Default::default();
-        let mut mcache = Default::default();
+        let mut mcaches = MonteCarloCache::pool(4);
         let rivers_bw = RiversIndex::from_hash_map(graph.rivers_betweenness(&mut gcache));
+        let opponent = BetweennessOpponent::new(&rivers_bw);
 
-        estimate_best_future(&graph, 60, &[60, 10, 31, 33], &rivers_bw, 3, 4, 0, make_move,
-                             187, Duration::from_millis(5000), &mut mcache, &mut gcache).unwrap();
+        estimate_best_future(&graph, 60, &[60, 10, 31, 33], 0, search(0, 1), &mut playouts(&opponent, 3, 4, 187, &mut mcaches), &mut gcache)
+            .unwrap();
     }
 }
@@ -1,11 +1,14 @@
 use serde::ser::Serialize;
-use serde::de::DeserializeOwned;
-use serde_json::value::Value;
+use serde::de::{Deserialize, Deserializer};
+use serde_json::value::RawValue;
 use serde_json;
+use serde_path_to_error;
+use serde_ignored;
+use std::fmt::{self, Write};
+use std::borrow::Cow;
 
 use super::types::{PunterId, SiteId};
-use super::map::{River, Map};
-use std::collections::BTreeMap;
+use super::map::Map;
 
 #[derive(PartialEq, Debug)]
 pub enum Req {
@@ -47,7 +50,8 @@ pub struct Settings {
     pub options: bool,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Move {
     Claim { punter: PunterId, source: SiteId, target: SiteId, },
     Pass { punter: PunterId, },
@@ -64,204 +68,453 @@ pub struct Score {
 #[derive(Debug)]
 pub enum Error {
     Json(serde_json::Error),
-    UnexpectedJson,
+    Decode(DecodeError),
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DecodeMode {
+    // any deviation from the protocol fails the message
+    Strict,
+    // unknown keys, malformed moves (which are skipped) and malformed settings (taken as off)
+    // are reported as warnings; note that a skipped claim is never applied to the game state,
+    // so the solver view of claimed rivers drifts from the server one from then on
+    Lenient,
+}
 
+// Kind of the server message being decoded.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    Handshake,
+    Timeout,
+    Setup,
+    Move,
+    Stop,
+    Unknown,
+}
 
-#[derive(Debug, Deserialize)]
-#[allow(non_camel_case_types)]
-enum ServerMove {
-    claim { punter: PunterId, source: SiteId, target: SiteId, },
-    pass { punter: PunterId, },
-    splurge { punter: PunterId, route: Vec<SiteId>, },
-    option { punter: PunterId, source: SiteId, target: SiteId, },
+#[derive(Clone, PartialEq, Debug)]
+pub enum Reason {
+    NotAnObject,
+    UnknownMessage,
+    Missing,
+    Invalid(String),
+    UnknownKey,
 }
-#[derive(Debug, Deserialize)]
-struct ServerMoves {
-    moves: Vec<ServerMove>,
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct DecodeError {
+    pub kind: Kind,
+    // path of the offending json node, like `$.move.moves[2].claim.source`
+    pub path: String,
+    pub reason: Reason,
 }
-#[derive(Debug, Deserialize)]
-struct ServerStop {
-    moves: Vec<ServerMove>,
-    scores: Vec<Score>,
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} message at {}: ", self.kind, self.path)?;
+        match self.reason {
+            Reason::NotAnObject =>
+                write!(f, "an object expected"),
+            Reason::UnknownMessage =>
+                write!(f, "unknown message"),
+            Reason::Missing =>
+                write!(f, "missing"),
+            Reason::Invalid(ref message) =>
+                write!(f, "invalid: {}", message),
+            Reason::UnknownKey =>
+                write!(f, "unknown key"),
+        }
+    }
 }
-#[derive(Debug, Deserialize)]
-struct ServerSite {
-    id: SiteId,
+
+// Decoded server message along with problems tolerated in lenient mode.
+#[derive(Debug)]
+pub struct Decoded<S> {
+    pub rep: Rep,
+    pub state: Option<S>,
+    pub warnings: Vec<DecodeError>,
+    // malformed moves dropped from `rep`
+    pub skipped_moves: usize,
 }
-#[derive(Debug, Deserialize)]
-struct ServerMap {
-    sites: Vec<ServerSite>,
-    rivers: Vec<River>,
-    mines: Vec<SiteId>,
+
+impl<S> Decoded<S> {
+    pub fn log_warnings(&self) {
+        for warning in self.warnings.iter() {
+            warn!("{}", warning);
+        }
+        if self.skipped_moves > 0 {
+            warn!("{} malformed moves skipped, claimed rivers may differ from the server ones from now on", self.skipped_moves);
+        }
+    }
 }
 
-impl Rep {
-    pub fn from_json<S>(s: &str) -> Result<(Rep, Option<S>), Error> where S: DeserializeOwned {
-        match serde_json::from_str::<Value>(s).map_err(Error::Json)? {
-            Value::Object(mut map) => {
-                let maybe_state = if let Some(value) = map.remove("state") {
-                    if map.contains_key("move") || map.contains_key("stop") {
-                        Some(serde_json::from_value::<S>(value).map_err(Error::Json)?)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
+// Server messages as they are on the wire, which keys are there tells the message kind. Settings
+// and moves are kept raw here and taken one by one later, so a malformed one could be tolerated
+// on its own.
+#[derive(Deserialize)]
+struct WireRep<'a, S> {
+    #[serde(borrow)]
+    you: Option<Text<'a>>,
+    timeout: Option<f64>,
+    punter: Option<PunterId>,
+    punters: Option<usize>,
+    map: Option<Map>,
+    #[serde(borrow)]
+    settings: Option<&'a RawValue>,
+    #[serde(rename = "move", borrow)]
+    move_: Option<WireMoves<'a>>,
+    #[serde(borrow)]
+    stop: Option<WireMoves<'a>>,
+    state: Option<S>,
+}
+
+// A string borrowed from the message unless it has escapes.
+#[derive(Deserialize)]
+struct Text<'a>(#[serde(borrow)] Cow<'a, str>);
+
+// Body of move and stop messages.
+#[derive(Deserialize)]
+struct WireMoves<'a> {
+    #[serde(borrow)]
+    moves: Option<Vec<&'a RawValue>>,
+    scores: Option<Vec<Score>>,
+}
+
+#[derive(Deserialize)]
+struct WireSettings<'a> {
+    #[serde(borrow)]
+    futures: Option<&'a RawValue>,
+    #[serde(borrow)]
+    splurges: Option<&'a RawValue>,
+    #[serde(borrow)]
+    options: Option<&'a RawValue>,
+}
+
+// Client messages as they go on the wire.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum WireReq<S> {
+    Handshake { me: String, },
+    Ready {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        futures: Option<Vec<Future>>,
+        ready: PunterId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state: Option<S>,
+    },
+    Move {
+        #[serde(flatten)]
+        move_: Move,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state: Option<S>,
+    },
+}
+
+// Deserializes `T` keeping the path of where it fails and of keys it ignores, the latter are
+// relative to the node and go to `unknown_keys`.
+fn deserialize_tracked<'de, D, T>(deserializer: D, unknown_keys: &mut Vec<String>) -> Result<T, serde_path_to_error::Error<D::Error>>
+    where D: Deserializer<'de>, T: Deserialize<'de>
+{
+    let mut track = serde_path_to_error::Track::new();
+    let mut ignored = |key: serde_ignored::Path| {
+        let mut path = String::new();
+        push_ignored_path(&mut path, &key);
+        unknown_keys.push(path);
+    };
+    T::deserialize(serde_path_to_error::Deserializer::new(serde_ignored::Deserializer::new(deserializer, &mut ignored), &mut track))
+        .map_err(|e| serde_path_to_error::Error::new(track.path(), e))
+}
+
+fn push_ignored_path(path: &mut String, key: &serde_ignored::Path) {
+    match *key {
+        serde_ignored::Path::Root =>
+            (),
+        serde_ignored::Path::Seq { parent, index, } => {
+            push_ignored_path(path, parent);
+            let _ = write!(path, "[{}]", index);
+        },
+        serde_ignored::Path::Map { parent, ref key, } => {
+            push_ignored_path(path, parent);
+            let _ = write!(path, ".{}", key);
+        },
+        serde_ignored::Path::Some { parent, } | serde_ignored::Path::NewtypeStruct { parent, } | serde_ignored::Path::NewtypeVariant { parent, } =>
+            push_ignored_path(path, parent),
+    }
+}
+
+// Path of a failed node under `path`, like `$.move.moves[2].claim.source`.
+fn error_path(path: &str, error: &serde_path_to_error::Path) -> String {
+    let mut path = path.to_string();
+    for segment in error.iter() {
+        let _ = match segment {
+            serde_path_to_error::Segment::Seq { index, } =>
+                write!(path, "[{}]", index),
+            serde_path_to_error::Segment::Map { key, } | serde_path_to_error::Segment::Enum { variant: key, } =>
+                write!(path, ".{}", key),
+            serde_path_to_error::Segment::Unknown =>
+                write!(path, ".?"),
+        };
+    }
+    path
+}
 
-                if map.contains_key("move") {
-                    let move_node = map.remove("move").unwrap();
-                    let smove = serde_json::from_value::<ServerMoves>(move_node).map_err(Error::Json)?;
-                    Ok((Rep::Move {
-                        moves: smove.moves.into_iter().map(|m| {
-                            match m {
-                                ServerMove::claim { punter, source, target, } =>
-                                    Move::Claim { punter: punter, source: source, target: target, },
-                                ServerMove::pass { punter, } =>
-                                    Move::Pass { punter: punter, },
-                                ServerMove::splurge { punter, route, } =>
-                                    Move::Splurge { punter: punter, route: route, },
-                                ServerMove::option { punter, source, target, } =>
-                                    Move::Option { punter: punter, source: source, target: target, },
-                            }
-                        }).collect(),
-                    }, maybe_state))
-                } else if map.contains_key("stop") {
-                    let stop_node = map.remove("stop").unwrap();
-                    let stop = serde_json::from_value::<ServerStop>(stop_node).map_err(Error::Json)?;
-                    Ok((Rep::Stop {
-                        moves: stop.moves.into_iter().map(|m| {
-                            match m {
-                                ServerMove::claim { punter, source, target, } =>
-                                    Move::Claim { punter: punter, source: source, target: target, },
-                                ServerMove::pass { punter, } =>
-                                    Move::Pass { punter: punter },
-                                ServerMove::splurge { punter, route, } =>
-                                    Move::Splurge { punter: punter, route: route, },
-                                ServerMove::option { punter, source, target, } =>
-                                    Move::Option { punter: punter, source: source, target: target, },
-                            }
-                        }).collect(),
-                        scores: stop.scores,
-                    }, maybe_state))
-                } else if map.contains_key("punter") && map.contains_key("punters") && map.contains_key("map") {
-                    let smap = serde_json::from_value::<ServerMap>(map.remove("map").unwrap()).map_err(Error::Json)?;
-                    Ok((Rep::Setup(Setup {
-                        punter: serde_json::from_value::<PunterId>(map.remove("punter").unwrap()).map_err(Error::Json)?,
-                        punters: serde_json::from_value::<usize>(map.remove("punters").unwrap()).map_err(Error::Json)?,
-                        map: Map {
-                            sites: smap.sites.into_iter().map(|s| s.id).collect(),
-                            rivers: smap.rivers,
-                            mines: smap.mines,
-                        },
-                        settings: if let Some(Value::Object(mut settings_obj)) = map.remove("settings") {
-                            Settings {
-                                futures: match settings_obj.remove("futures") {
-                                    Some(Value::Bool(true)) => true,
-                                    _ => false,
-                                },
-                                splurges: match settings_obj.remove("splurges") {
-                                    Some(Value::Bool(true)) => true,
-                                    _ => false,
-                                },
-                                options: match settings_obj.remove("options") {
-                                    Some(Value::Bool(true)) => true,
-                                    _ => false,
-                                },
-                            }
-                        } else {
-                            Default::default()
-                        },
-                    }), None))
-                } else if map.contains_key("timeout") {
-                    Ok((Rep::Timeout(serde_json::from_value::<f64>(map.remove("timeout").unwrap()).map_err(Error::Json)?), None))
-                } else if map.contains_key("you") {
-                    Ok((Rep::Handshake {
-                        name: serde_json::from_value::<String>(map.remove("you").unwrap()).map_err(Error::Json)?,
-                    }, None))
-                } else {
-                    Err(Error::UnexpectedJson)
-                }
+// Serde error message without the position, the path tells it instead.
+fn message(e: &serde_json::Error) -> String {
+    let mut message = e.to_string();
+    let position = format!(" at line {} column {}", e.line(), e.column());
+    if message.ends_with(&position) {
+        let len = message.len() - position.len();
+        message.truncate(len);
+    }
+    message
+}
+
+// Problems found while a message is decoded.
+struct Context {
+    mode: DecodeMode,
+    kind: Kind,
+    warnings: Vec<DecodeError>,
+    skipped_moves: usize,
+}
+
+impl Context {
+    fn error(&self, path: String, reason: Reason) -> DecodeError {
+        DecodeError { kind: self.kind, path: path, reason: reason, }
+    }
+
+    fn required<T>(&self, value: Option<T>, path: &str) -> Result<T, DecodeError> {
+        value.ok_or_else(|| self.error(path.to_string(), Reason::Missing))
+    }
+
+    // fatal in strict mode only
+    fn report(&mut self, error: DecodeError) -> Result<(), DecodeError> {
+        match self.mode {
+            DecodeMode::Strict =>
+                Err(error),
+            DecodeMode::Lenient => {
+                self.warnings.push(error);
+                Ok(())
             },
-            _ => {
-                Err(Error::UnexpectedJson)
+        }
+    }
+
+    fn report_unknown(&mut self, path: &str, unknown_keys: Vec<String>) -> Result<(), DecodeError> {
+        for key in unknown_keys {
+            let error = self.error(format!("{}{}", path, key), Reason::UnknownKey);
+            self.report(error)?;
+        }
+        Ok(())
+    }
+
+    // Deserializes a raw node at `path`, along with its unknown keys.
+    fn node<'a, T>(&self, raw: &'a RawValue, path: &str) -> Result<(T, Vec<String>), DecodeError> where T: Deserialize<'a> {
+        let mut unknown_keys = Vec::new();
+        match deserialize_tracked(raw, &mut unknown_keys) {
+            Ok(value) =>
+                Ok((value, unknown_keys)),
+            Err(e) =>
+                Err(self.error(error_path(path, e.path()), Reason::Invalid(message(e.inner())))),
+        }
+    }
+
+    // Malformed moves are skipped, unless in strict mode.
+    fn moves(&mut self, moves: Option<Vec<&RawValue>>, path: &str) -> Result<Vec<Move>, DecodeError> {
+        let moves = self.required(moves, path)?;
+        let mut decoded = Vec::with_capacity(moves.len());
+        for (index, raw) in moves.into_iter().enumerate() {
+            let path = format!("{}[{}]", path, index);
+            match self.node::<Move>(raw, &path) {
+                Ok((move_, unknown_keys)) => {
+                    // ignored keys are tracked without the move kind they are under
+                    self.report_unknown(&format!("{}.{}", path, move_.wire_key()), unknown_keys)?;
+                    decoded.push(move_);
+                },
+                Err(error) => {
+                    self.report(error)?;
+                    self.skipped_moves += 1;
+                },
             }
         }
+        Ok(decoded)
+    }
+
+    // Malformed flags are taken as off, unless in strict mode.
+    fn settings(&mut self, raw: &RawValue) -> Result<Settings, DecodeError> {
+        let path = "$.settings";
+        let (wire, unknown_keys): (WireSettings, _) = match self.node(raw, path) {
+            Ok(node) =>
+                node,
+            Err(error) => {
+                self.report(error)?;
+                return Ok(Default::default());
+            },
+        };
+        let settings = Settings {
+            futures: self.flag(wire.futures, "$.settings.futures")?,
+            splurges: self.flag(wire.splurges, "$.settings.splurges")?,
+            options: self.flag(wire.options, "$.settings.options")?,
+        };
+        self.report_unknown(path, unknown_keys)?;
+        Ok(settings)
+    }
+
+    fn flag(&mut self, raw: Option<&RawValue>, path: &str) -> Result<bool, DecodeError> {
+        match raw.map(|raw| self.node(raw, path)) {
+            None =>
+                Ok(false),
+            Some(Ok((on, _))) =>
+                Ok(on),
+            Some(Err(error)) =>
+                self.report(error).map(|()| false),
+        }
+    }
+}
 
+// Kind of the message a top level key belongs to.
+fn key_kind(key: &str) -> Kind {
+    match key {
+        "move" => Kind::Move,
+        "stop" => Kind::Stop,
+        "punter" | "punters" | "map" | "settings" => Kind::Setup,
+        "timeout" => Kind::Timeout,
+        "you" => Kind::Handshake,
+        _ => Kind::Unknown,
     }
 }
 
-impl Req {
-    pub fn to_json<S>(self, maybe_state: Option<S>) -> Result<String, Error> where S: Serialize {
-        let mut res = BTreeMap::new();
-        match self {
-            Req::Handshake { name } => {
-                res.insert("me".to_string(), serde_json::to_value(name).map_err(Error::Json)?);
-            },
-            Req::Ready { punter, futures, } => {
-                res.insert("ready".to_string(), serde_json::to_value(punter).map_err(Error::Json)?);
-                if let Some(futs) = futures {
-                    let fut_values = futs.into_iter()
-                        .map(|fut| {
-                            serde_json::to_value(
-                                vec![("source".to_string(), serde_json::to_value(fut.source).map_err(Error::Json)?),
-                                     ("target".to_string(), serde_json::to_value(fut.target).map_err(Error::Json)?)]
-                                    .into_iter()
-                                    .collect::<BTreeMap<String, Value>>()).map_err(Error::Json)
-                        })
-                        .collect::<Result<Vec<_>, _>>()?;
-                    res.insert("futures".to_string(), Value::Array(fut_values));
-                }
-                if let Some(state) = maybe_state {
-                    res.insert("state".to_string(), serde_json::to_value(state).map_err(Error::Json)?);
-                }
+impl<'a, S> WireRep<'a, S> {
+    fn kind(&self) -> Kind {
+        if self.move_.is_some() {
+            Kind::Move
+        } else if self.stop.is_some() {
+            Kind::Stop
+        } else if self.punter.is_some() && self.punters.is_some() && self.map.is_some() {
+            Kind::Setup
+        } else if self.timeout.is_some() {
+            Kind::Timeout
+        } else if self.you.is_some() {
+            Kind::Handshake
+        } else {
+            Kind::Unknown
+        }
+    }
+
+    // Makes the message of its kind, keys of the other kinds are reported as unknown ones.
+    fn into_rep(self, ctx: &mut Context) -> Result<(Rep, Option<S>), DecodeError> {
+        let kind = ctx.kind;
+        let present = [
+            ("$.you", self.you.is_some() && kind != Kind::Handshake),
+            ("$.timeout", self.timeout.is_some() && kind != Kind::Timeout),
+            ("$.punter", self.punter.is_some() && kind != Kind::Setup),
+            ("$.punters", self.punters.is_some() && kind != Kind::Setup),
+            ("$.map", self.map.is_some() && kind != Kind::Setup),
+            ("$.settings", self.settings.is_some() && kind != Kind::Setup),
+            ("$.move.scores", self.move_.as_ref().is_some_and(|body| body.scores.is_some())),
+            ("$.state", self.state.is_some() && kind != Kind::Move && kind != Kind::Stop),
+        ];
+        for &(path, _) in present.iter().filter(|&&(_, unknown)| unknown) {
+            let error = ctx.error(path.to_string(), Reason::UnknownKey);
+            ctx.report(error)?;
+        }
+        Ok(match kind {
+            Kind::Move =>
+                (Rep::Move { moves: ctx.moves(self.move_.unwrap().moves, "$.move.moves")?, }, self.state),
+            Kind::Stop => {
+                let WireMoves { moves, scores, } = self.stop.unwrap();
+                (Rep::Stop {
+                    moves: ctx.moves(moves, "$.stop.moves")?,
+                    scores: ctx.required(scores, "$.stop.scores")?,
+                }, self.state)
             },
-            Req::Move(mv) => {
-                match mv {
-                    Move::Claim { punter, source, target, } => {
-                        let m = vec![
-                            ("punter".to_string(), serde_json::to_value(punter).map_err(Error::Json)?),
-                            ("source".to_string(), serde_json::to_value(source).map_err(Error::Json)?),
-                            ("target".to_string(), serde_json::to_value(target).map_err(Error::Json)?),
-                        ].into_iter().collect::<BTreeMap<String, Value>>();
-                        res.insert("claim".to_string(), serde_json::to_value(m).map_err(Error::Json)?);
-                    },
-                    Move::Pass { punter } => {
-                        let m = vec![
-                            ("punter".to_string(), serde_json::to_value(punter).map_err(Error::Json)?),
-                        ].into_iter().collect::<BTreeMap<String,Value>>();
-                        res.insert("pass".to_string(), serde_json::to_value(m).map_err(Error::Json)?);
-                    },
-                    Move::Splurge { punter, route, } => {
-                        let m = vec![
-                            ("punter".to_string(), serde_json::to_value(punter).map_err(Error::Json)?),
-                            ("route".to_string(), serde_json::to_value(route).map_err(Error::Json)?),
-                        ].into_iter().collect::<BTreeMap<String, Value>>();
-                        res.insert("splurge".to_string(), serde_json::to_value(m).map_err(Error::Json)?);
-                    },
-                    Move::Option { punter, source, target, } => {
-                        let m = vec![
-                            ("punter".to_string(), serde_json::to_value(punter).map_err(Error::Json)?),
-                            ("source".to_string(), serde_json::to_value(source).map_err(Error::Json)?),
-                            ("target".to_string(), serde_json::to_value(target).map_err(Error::Json)?),
-                        ].into_iter().collect::<BTreeMap<String, Value>>();
-                        res.insert("option".to_string(), serde_json::to_value(m).map_err(Error::Json)?);
+            Kind::Setup =>
+                (Rep::Setup(Setup {
+                    punter: self.punter.unwrap(),
+                    punters: self.punters.unwrap(),
+                    map: self.map.unwrap(),
+                    settings: match self.settings {
+                        Some(raw) =>
+                            ctx.settings(raw)?,
+                        None =>
+                            Default::default(),
                     },
-                }
-                if let Some(state) = maybe_state {
-                    res.insert("state".to_string(), serde_json::to_value(state).map_err(Error::Json)?);
-                }
-            }
+                }), None),
+            Kind::Timeout =>
+                (Rep::Timeout(self.timeout.unwrap()), None),
+            Kind::Handshake =>
+                (Rep::Handshake { name: self.you.unwrap().0.into_owned(), }, None),
+            Kind::Unknown =>
+                return Err(ctx.error("$".to_string(), Reason::UnknownMessage)),
+        })
+    }
+}
+
+impl Move {
+    // the key the move goes under on the wire
+    fn wire_key(&self) -> &'static str {
+        match *self {
+            Move::Claim { .. } => "claim",
+            Move::Pass { .. } => "pass",
+            Move::Splurge { .. } => "splurge",
+            Move::Option { .. } => "option",
         }
-        Ok(serde_json::to_string(&res).map_err(Error::Json)?)
+    }
+}
+
+impl Rep {
+    // Decodes a server message in lenient mode, warnings go to the log.
+    pub fn from_json<'a, S>(s: &'a str) -> Result<(Rep, Option<S>), Error> where S: Deserialize<'a> {
+        let decoded = Rep::decode(s, DecodeMode::Lenient)?;
+        decoded.log_warnings();
+        Ok((decoded.rep, decoded.state))
+    }
+
+    // Decodes a server message straight from its text, reporting exactly what and where is wrong
+    // with it. The state may borrow from `s`.
+    pub fn decode<'a, S>(s: &'a str, mode: DecodeMode) -> Result<Decoded<S>, Error> where S: Deserialize<'a> {
+        let mut unknown_keys = Vec::new();
+        let mut deserializer = serde_json::Deserializer::from_str(s);
+        let wire: WireRep<S> = match deserialize_tracked(&mut deserializer, &mut unknown_keys) {
+            Ok(wire) =>
+                wire,
+            Err(ref e) if e.inner().is_data() => {
+                let (kind, reason) = match e.path().iter().next() {
+                    Some(serde_path_to_error::Segment::Map { key, }) =>
+                        (key_kind(key), Reason::Invalid(message(e.inner()))),
+                    _ =>
+                        (Kind::Unknown, Reason::NotAnObject),
+                };
+                return Err(Error::Decode(DecodeError { kind: kind, path: error_path("$", e.path()), reason: reason, }));
+            },
+            Err(e) =>
+                return Err(Error::Json(e.into_inner())),
+        };
+        deserializer.end().map_err(Error::Json)?;
+        let mut ctx = Context { mode: mode, kind: wire.kind(), warnings: Vec::new(), skipped_moves: 0, };
+        let (rep, state) = wire.into_rep(&mut ctx)
+            .and_then(|decoded| ctx.report_unknown("$", unknown_keys).map(|()| decoded))
+            .map_err(Error::Decode)?;
+        Ok(Decoded { rep: rep, state: state, warnings: ctx.warnings, skipped_moves: ctx.skipped_moves, })
+    }
+}
+
+impl Req {
+    pub fn to_json<S>(self, maybe_state: Option<S>) -> Result<String, Error> where S: Serialize {
+        let wire = match self {
+            Req::Handshake { name } =>
+                WireReq::Handshake { me: name, },
+            Req::Ready { punter, futures, } =>
+                WireReq::Ready { futures: futures, ready: punter, state: maybe_state, },
+            Req::Move(move_) =>
+                WireReq::Move { move_: move_, state: maybe_state, },
+        };
+        serde_json::to_string(&wire).map_err(Error::Json)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use super::super::map::{River, Site};
 
     #[test]
     fn proto_handshake() {
@@ -342,7 +595,7 @@ mod test {
             punter: 0,
             punters: 2,
             map: Map {
-                sites: vec![4, 1, 3, 6, 5, 0, 7, 2],
+                sites: vec![4, 1, 3, 6, 5, 0, 7, 2].into_iter().map(Site::new).collect(),
                 rivers: vec![
                     River::new(3, 4),
                     River::new(0, 1),
@@ -372,7 +625,7 @@ mod test {
             punter: 0,
             punters: 2,
             map: Map {
-                sites: vec![4, 1, 3, 6, 5, 0, 7, 2],
+                sites: vec![4, 1, 3, 6, 5, 0, 7, 2].into_iter().map(Site::new).collect(),
                 rivers: vec![
                     River::new(3, 4),
                     River::new(0, 1),
@@ -398,6 +651,27 @@ mod test {
         assert_eq!(object,result);
     }
 
+    #[test]
+    fn proto_setup_coords() {
+        let object = Rep::from_json::<()>("{\"punter\":1, \"punters\":2,
+\"map\":{\"sites\":[{\"id\":0,\"x\":0.0,\"y\":1.5},{\"id\":1,\"x\":-2.0,\"y\":3.0},{\"id\":2}], \"rivers\":[{\"source\":0,\"target\":1},{\"source\":1,\"target\":2}], \"mines\":[0]}}").unwrap().0;
+        let result = Rep::Setup(Setup {
+            punter: 1,
+            punters: 2,
+            map: Map {
+                sites: vec![Site::with_coords(0, 0.0, 1.5), Site::with_coords(1, -2.0, 3.0), Site::new(2)],
+                rivers: vec![River::new(0, 1), River::new(1, 2)],
+                mines: vec![0],
+            },
+            settings: Default::default(),
+        });
+        assert_eq!(object, result);
+        if let Rep::Setup(setup) = object {
+            assert_eq!(setup.map.sites[1].coords(), Some((-2.0, 3.0)));
+            assert_eq!(setup.map.sites[2].coords(), None);
+        }
+    }
+
     #[test]
     fn proto_timeout() {
         let object = Rep::from_json::<()>("{\"timeout\": 10.0}").unwrap().0;
@@ -447,4 +721,145 @@ mod test {
         assert_eq!(object.to_json::<()>(None).unwrap(),result.to_string());
     }
 
+
+    fn decode_err(s: &str, mode: DecodeMode) -> DecodeError {
+        match Rep::decode::<()>(s, mode) {
+            Err(Error::Decode(e)) => e,
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn proto_decode_unknown_keys() {
+        let s = "{\"you\":\"alice\",\"motd\":\"hi\"}";
+        let decoded = Rep::decode::<()>(s, DecodeMode::Lenient).unwrap();
+        assert_eq!(decoded.rep, Rep::Handshake { name: "alice".to_string(), });
+        assert_eq!(decoded.warnings, vec![DecodeError { kind: Kind::Handshake, path: "$.motd".to_string(), reason: Reason::UnknownKey, }]);
+        assert_eq!(decode_err(s, DecodeMode::Strict).path, "$.motd");
+        let s = "{\"move\":{\"moves\":[{\"pass\":{\"punter\":0,\"why\":1}}],\"round\":3}}";
+        let decoded = Rep::decode::<()>(s, DecodeMode::Lenient).unwrap();
+        assert_eq!(decoded.rep, Rep::Move { moves: vec![Move::Pass { punter: 0, }], });
+        let paths: Vec<_> = decoded.warnings.iter().map(|w| &*w.path).collect();
+        assert_eq!(paths, vec!["$.move.moves[0].pass.why", "$.move.round"]);
+        assert_eq!(decoded.skipped_moves, 0);
+    }
+
+    #[test]
+    fn proto_decode_malformed_move() {
+        let s = "{\"move\":{\"moves\":[{\"claim\":{\"punter\":0,\"source\":\"x\",\"target\":1}},{\"teleport\":{\"punter\":1}},{\"pass\":{\"punter\":2}}]}}";
+        let decoded = Rep::decode::<()>(s, DecodeMode::Lenient).unwrap();
+        assert_eq!(decoded.rep, Rep::Move { moves: vec![Move::Pass { punter: 2, }], });
+        assert_eq!(decoded.warnings.len(), 2);
+        assert_eq!(decoded.warnings[0].path, "$.move.moves[0].claim.source");
+        assert_eq!(decoded.warnings[1].path, "$.move.moves[1]");
+        assert!(decoded.warnings[1].to_string().contains("unknown variant `teleport`"));
+        assert_eq!(decoded.skipped_moves, 2);
+        let error = decode_err(s, DecodeMode::Strict);
+        assert_eq!(error.kind, Kind::Move);
+        assert_eq!(error.path, "$.move.moves[0].claim.source");
+        assert!(error.to_string().starts_with("Move message at $.move.moves[0].claim.source: invalid: "));
+        // a missing field of the message itself is fatal in any mode
+        let error = decode_err("{\"stop\":{\"moves\":[]}}", DecodeMode::Lenient);
+        assert_eq!((error.kind, &*error.path, error.reason), (Kind::Stop, "$.stop.scores", Reason::Missing));
+    }
+
+    #[test]
+    fn proto_decode_settings() {
+        let s = "{\"punter\":0,\"punters\":2,\"map\":{\"sites\":[],\"rivers\":[],\"mines\":[]},\"settings\":{\"futures\":\"yes\",\"options\":true,\"teleports\":true}}";
+        let decoded = Rep::decode::<()>(s, DecodeMode::Lenient).unwrap();
+        match decoded.rep {
+            Rep::Setup(ref setup) =>
+                assert_eq!(setup.settings, Settings { futures: false, splurges: false, options: true, }),
+            ref other =>
+                panic!("unexpected rep: {:?}", other),
+        }
+        let paths: Vec<_> = decoded.warnings.iter().map(|w| &*w.path).collect();
+        assert_eq!(paths, vec!["$.settings.futures", "$.settings.teleports"]);
+        assert_eq!(decode_err(s, DecodeMode::Strict).path, "$.settings.futures");
+    }
+
+    #[test]
+    fn proto_decode_unknown_message() {
+        assert_eq!(decode_err("{\"hello\":1}", DecodeMode::Lenient),
+                   DecodeError { kind: Kind::Unknown, path: "$".to_string(), reason: Reason::UnknownMessage, });
+        assert_eq!(decode_err("[1]", DecodeMode::Lenient).reason, Reason::NotAnObject);
+    }
+
+    #[test]
+    fn proto_decode_conforming() {
+        let map = ::std::fs::read_to_string(format!("{}/../maps/sample.json", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let messages = vec![
+            "{\"you\":\"alice\"}".to_string(),
+            "{\"timeout\":10.0}".to_string(),
+            format!("{{\"punter\":1,\"punters\":2,\"map\":{},\"settings\":{{\"futures\":true}}}}", map),
+            "{\"move\":{\"moves\":[{\"claim\":{\"punter\":0,\"source\":0,\"target\":1}},{\"splurge\":{\"punter\":1,\"route\":[1,2,3]}}]},\"state\":[7]}".to_string(),
+            "{\"stop\":{\"moves\":[{\"option\":{\"punter\":0,\"source\":5,\"target\":7}},{\"pass\":{\"punter\":1}}],\"scores\":[{\"punter\":0,\"score\":-6}]}}".to_string(),
+        ];
+        for message in messages {
+            let strict = Rep::decode::<Vec<u8>>(&message, DecodeMode::Strict).unwrap();
+            let lenient = Rep::decode::<Vec<u8>>(&message, DecodeMode::Lenient).unwrap();
+            assert_eq!(lenient.warnings, vec![]);
+            assert_eq!((strict.rep, strict.state), (lenient.rep, lenient.state));
+        }
+        // state is borrowed straight from the message
+        let decoded = Rep::decode::<&str>("{\"move\":{\"moves\":[]},\"state\":\"cbor+deflate:AAAA\"}", DecodeMode::Strict).unwrap();
+        assert_eq!(decoded.state, Some("cbor+deflate:AAAA"));
+        // and the message is still checked after the first error is tolerated
+        let s = "{\"move\":{\"moves\":[{\"claim\":{\"punter\":[0],\"source\":{\"a\":[1]},\"target\":1}},{\"pass\":{\"punter\":2}}],\"round\":1}}";
+        let decoded = Rep::decode::<()>(s, DecodeMode::Lenient).unwrap();
+        assert_eq!(decoded.rep, Rep::Move { moves: vec![Move::Pass { punter: 2, }], });
+        let paths: Vec<_> = decoded.warnings.iter().map(|w| &*w.path).collect();
+        assert_eq!(paths, vec!["$.move.moves[0].claim.punter", "$.move.round"]);
+        // while broken json is not a decode error
+        match Rep::decode::<()>("{\"move\":{\"moves\":[{\"pass\":", DecodeMode::Lenient) {
+            Err(Error::Json(..)) => (),
+            other => panic!("unexpected result: {:?}", other.map(|decoded| decoded.rep)),
+        }
+    }
+
+    // The decoder this module had before, which went through a json tree: the setup part of it.
+    fn decode_setup_through_tree(s: &str) -> Setup {
+        use serde_json::{Value, from_value};
+        let mut json = match serde_json::from_str::<Value>(s).unwrap() {
+            Value::Object(map) => map,
+            other => panic!("unexpected json: {:?}", other),
+        };
+        let flag = |settings: &mut serde_json::Map<String, Value>, key| match settings.remove(key) {
+            Some(Value::Bool(true)) => true,
+            _ => false,
+        };
+        Setup {
+            punter: from_value(json.remove("punter").unwrap()).unwrap(),
+            punters: from_value(json.remove("punters").unwrap()).unwrap(),
+            map: from_value(json.remove("map").unwrap()).unwrap(),
+            settings: match json.remove("settings") {
+                Some(Value::Object(mut settings)) =>
+                    Settings { futures: flag(&mut settings, "futures"), splurges: flag(&mut settings, "splurges"), options: flag(&mut settings, "options"), },
+                _ =>
+                    Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_decode_setup() {
+        use std::time::Instant;
+        const ROUNDS: u32 = 20;
+        let map = ::std::fs::read_to_string(format!("{}/../maps/nara-sparse.json", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        let setup = format!("{{\"punter\":0,\"punters\":2,\"map\":{},\"settings\":{{\"futures\":true}}}}", map);
+        let before = decode_setup_through_tree(&setup);
+        let now = Instant::now();
+        for _ in 0 .. ROUNDS {
+            decode_setup_through_tree(&setup);
+        }
+        let before_elapsed = now.elapsed() / ROUNDS;
+        let now = Instant::now();
+        for _ in 0 .. ROUNDS {
+            Rep::decode::<()>(&setup, DecodeMode::Strict).unwrap();
+        }
+        let after_elapsed = now.elapsed() / ROUNDS;
+        assert_eq!(Rep::decode::<()>(&setup, DecodeMode::Strict).unwrap().rep, Rep::Setup(before));
+        println!("nara-sparse setup: {} bytes, decoded through a json tree in {:?}, with wire models in {:?}", setup.len(), before_elapsed, after_elapsed);
+    }
 }
@@ -0,0 +1,407 @@
+use std::{io, fs, str, fmt, error};
+use std::io::{Read, Write, BufRead};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::VecDeque;
+use serde_json;
+
+use super::transport::Transport;
+
+#[derive(Debug)]
+pub enum Error {
+    Open(io::Error),
+    Read(io::Error),
+    Decode(serde_json::Error),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Direction {
+    #[serde(rename = "P->S")]
+    PunterToServer,
+    #[serde(rename = "S->P")]
+    ServerToPunter,
+}
+
+// A single framed message, stored as a json line in a recording file.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub dir: Direction,
+    // milliseconds since unix epoch
+    pub ts_ms: u64,
+    // packet payload without the length prefix
+    pub body: String,
+}
+
+pub fn load<P>(path: P) -> Result<Vec<Message>, Error> where P: AsRef<Path> {
+    let file = fs::File::open(path)
+        .map_err(Error::Open)?;
+    let mut messages = Vec::new();
+    for line in io::BufReader::new(file).lines() {
+        let line = line.map_err(Error::Read)?;
+        if !line.trim().is_empty() {
+            messages.push(serde_json::from_str(&line).map_err(Error::Decode)?);
+        }
+    }
+    Ok(messages)
+}
+
+// Cuts a byte stream into framed packets as they arrive. Bytes not looking like a frame are
+// emitted as is, so nothing is lost from a recording.
+#[derive(Default)]
+struct Splitter {
+    buf: Vec<u8>,
+    len: Option<usize>,
+}
+
+impl Splitter {
+    fn feed(&mut self, mut bytes: &[u8], packets: &mut Vec<Vec<u8>>) {
+        while !bytes.is_empty() {
+            match self.len {
+                None => {
+                    let byte = bytes[0];
+                    bytes = &bytes[1 ..];
+                    match byte {
+                        b':' if !self.buf.is_empty() => {
+                            let len = str::from_utf8(&self.buf).ok().and_then(|s| s.parse().ok());
+                            match len {
+                                Some(len) => {
+                                    self.buf.clear();
+                                    self.len = Some(len);
+                                },
+                                None =>
+                                    self.garbage(byte, packets),
+                            }
+                        },
+                        b'0' ..= b'9' =>
+                            self.buf.push(byte),
+                        _ =>
+                            self.garbage(byte, packets),
+                    }
+                },
+                Some(len) => {
+                    let take = ::std::cmp::min(len - self.buf.len(), bytes.len());
+                    self.buf.extend_from_slice(&bytes[.. take]);
+                    bytes = &bytes[take ..];
+                },
+            }
+            if self.len == Some(self.buf.len()) {
+                packets.push(self.buf.split_off(0));
+                self.len = None;
+            }
+        }
+    }
+
+    fn garbage(&mut self, byte: u8, packets: &mut Vec<Vec<u8>>) {
+        self.buf.push(byte);
+        packets.push(self.buf.split_off(0));
+    }
+}
+
+fn now_ms() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64
+}
+
+// Transport wrapper writing every framed message passing through it to `log`, one json line per message.
+pub struct Recording<T, W> {
+    inner: T,
+    log: W,
+    incoming: Splitter,
+    outgoing: Splitter,
+}
+
+impl<T, W> Recording<T, W> where W: Write {
+    pub fn new(inner: T, log: W) -> Recording<T, W> {
+        Recording {
+            inner: inner,
+            log: log,
+            incoming: Splitter::default(),
+            outgoing: Splitter::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> (T, W) {
+        (self.inner, self.log)
+    }
+
+    fn record(&mut self, dir: Direction, packets: Vec<Vec<u8>>) -> io::Result<()> {
+        for packet in packets {
+            let message = Message {
+                dir: dir,
+                ts_ms: now_ms(),
+                body: String::from_utf8_lossy(&packet).into_owned(),
+            };
+            serde_json::to_writer(&mut self.log, &message)?;
+            self.log.write_all(b"\n")?;
+        }
+        self.log.flush()
+    }
+}
+
+impl<T, W> Read for Recording<T, W> where T: Read, W: Write {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        let mut packets = Vec::new();
+        self.incoming.feed(&buf[.. bytes_read], &mut packets);
+        self.record(Direction::ServerToPunter, packets)?;
+        Ok(bytes_read)
+    }
+}
+
+impl<T, W> Write for Recording<T, W> where T: Write, W: Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = self.inner.write(buf)?;
+        let mut packets = Vec::new();
+        self.outgoing.feed(&buf[.. bytes_written], &mut packets);
+        self.record(Direction::PunterToServer, packets)?;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T, W> Transport for Recording<T, W> where T: Transport, W: Write {
+    fn describe(&self) -> String {
+        format!("recorded {}", self.inner.describe())
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+// What the punter sent differs from the recording, carried inside the `io::Error` of a failed write or read.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Mismatch {
+    // message index in the recording
+    pub index: usize,
+    pub expected: Option<String>,
+    pub sent: Option<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "recorded message #{}: expected {:?}, sent {:?}", self.index, self.expected, self.sent)
+    }
+}
+
+impl error::Error for Mismatch {}
+
+pub fn mismatch(e: &io::Error) -> Option<&Mismatch> {
+    e.get_ref().and_then(|inner| inner.downcast_ref())
+}
+
+// Transport playing the server side of a recording back: recorded server messages are served in order,
+// and every punter message is checked against the recorded one.
+pub struct Replay {
+    script: VecDeque<Message>,
+    index: usize,
+    pending: Vec<u8>,
+    offset: usize,
+    outgoing: Splitter,
+}
+
+impl Replay {
+    pub fn new(recording: Vec<Message>) -> Replay {
+        Replay {
+            script: recording.into_iter().collect(),
+            index: 0,
+            pending: Vec::new(),
+            offset: 0,
+            outgoing: Splitter::default(),
+        }
+    }
+
+    fn mismatch_error(&self, sent: Option<String>) -> io::Error {
+        let mismatch = Mismatch {
+            index: self.index,
+            expected: self.script.front().map(|message| message.body.clone()),
+            sent: sent,
+        };
+        io::Error::new(io::ErrorKind::InvalidData, mismatch)
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset >= self.pending.len() {
+            match self.script.front().map(|message| message.dir) {
+                None =>
+                    return Ok(0),
+                // punter waits for the server while it should have sent something
+                Some(Direction::PunterToServer) =>
+                    return Err(self.mismatch_error(None)),
+                Some(Direction::ServerToPunter) => {
+                    let message = self.script.pop_front().unwrap();
+                    self.pending = format!("{}:{}", message.body.len(), message.body).into_bytes();
+                    self.offset = 0;
+                    self.index += 1;
+                },
+            }
+        }
+        let len = ::std::cmp::min(buf.len(), self.pending.len() - self.offset);
+        buf[.. len].copy_from_slice(&self.pending[self.offset .. self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut packets = Vec::new();
+        self.outgoing.feed(buf, &mut packets);
+        for packet in packets {
+            let sent = String::from_utf8_lossy(&packet).into_owned();
+            let matches = match self.script.front() {
+                Some(&Message { dir: Direction::PunterToServer, ref body, .. }) =>
+                    *body == sent,
+                _ =>
+                    false,
+            };
+            if !matches {
+                return Err(self.mismatch_error(Some(sent)));
+            }
+            self.script.pop_front();
+            self.index += 1;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Replay {
+    fn describe(&self) -> String {
+        format!("replay of {} messages", self.script.len())
+    }
+
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, thread, process};
+    use std::io::{Read, Write};
+    use super::super::chat;
+    use super::super::proto::Score;
+    use super::super::client::{self, Framed, ReadParams};
+    use super::super::transport::{self, Transport};
+    use super::super::solvers::always_pass::AlwaysPassGameStateBuilder;
+    use super::super::solvers::gn::GNGameStateBuilder;
+    use super::{Splitter, Recording, Replay, Message, Direction, load, mismatch};
+
+    // Synthetic: not captured from a real server, but made for the sample game as `serve_sample` plays it,
+    // and its timestamps are not real. Real captures made by `lambda_punter_online --record-dir` go to the
+    // same dir without the "synthetic-" prefix.
+    const SAMPLE_RECORDING: &str = "synthetic-sample-gn.jsonl";
+
+    fn recording_path(name: &str) -> String {
+        format!("{}/../recordings/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn read_frame<T>(framed: &mut Framed<T>) -> Vec<u8> where T: Read {
+        let mut len = Vec::new();
+        let mut byte = [0];
+        loop {
+            framed.read_exact(&mut byte).unwrap();
+            if byte[0] == b':' {
+                break;
+            }
+            len.push(byte[0]);
+        }
+        let mut body = vec![0; String::from_utf8(len).unwrap().parse().unwrap()];
+        framed.read_exact(&mut body).unwrap();
+        body
+    }
+
+    fn write_frame<T>(framed: &mut Framed<T>, body: &[u8]) where T: Read + Write {
+        framed.write_all(format!("{}:", body.len()).as_bytes()).unwrap();
+        framed.write_all(body).unwrap();
+    }
+
+    // plays the sample map as server with a second punter passing all the time
+    fn serve_sample<T>(transport: T) where T: Transport {
+        let mut server = Framed::new(transport);
+        read_frame(&mut server);
+        write_frame(&mut server, br#"{"you":"skobochka"}"#);
+        let map = fs::read_to_string(format!("{}/../maps/sample.json", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        write_frame(&mut server, format!(r#"{{"punter":0,"punters":2,"map":{}}}"#, map).as_bytes());
+        read_frame(&mut server);
+        let mut last_move = br#"{"pass":{"punter":0}}"#.to_vec();
+        for _ in 0 .. 6 {
+            let moves = format!(r#"{{"move":{{"moves":[{},{{"pass":{{"punter":1}}}}]}}}}"#, String::from_utf8(last_move).unwrap());
+            write_frame(&mut server, moves.as_bytes());
+            last_move = read_frame(&mut server);
+        }
+        let stop = format!(r#"{{"stop":{{"moves":[{},{{"pass":{{"punter":1}}}}],"scores":[{{"punter":0,"score":6}},{{"punter":1,"score":0}}]}}}}"#,
+                           String::from_utf8(last_move).unwrap());
+        write_frame(&mut server, stop.as_bytes());
+    }
+
+    fn record_sample_game() -> Vec<Message> {
+        let path = env::temp_dir().join(format!("lambda_punter_record_test_{}.jsonl", process::id()));
+        let (player, server) = transport::pipe();
+        let server_thread = thread::spawn(move || serve_sample(server));
+        let log = fs::File::create(&path).unwrap();
+        let (scores, _) = client::run_network(Recording::new(player, log), ReadParams::default(), "skobochka", GNGameStateBuilder::default(), 0, ()).unwrap();
+        assert_eq!(scores, vec![Score { punter: 0, score: 6, }, Score { punter: 1, score: 0, }]);
+        server_thread.join().unwrap();
+        let recording = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        recording
+    }
+
+    #[test]
+    fn splitter() {
+        let mut splitter = Splitter::default();
+        let mut packets = Vec::new();
+        splitter.feed(b"2:{}1", &mut packets);
+        splitter.feed(b"0:0123", &mut packets);
+        splitter.feed(b"456789x:", &mut packets);
+        assert_eq!(packets, vec![b"{}".to_vec(), b"0123456789".to_vec(), b"x".to_vec(), b":".to_vec()]);
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let recording = record_sample_game();
+        assert_eq!(recording.len(), 2 + 2 + 6 * 2 + 1);
+        assert_eq!(recording[0].dir, Direction::PunterToServer);
+        assert_eq!(recording[0].body, r#"{"me":"skobochka"}"#);
+        assert_eq!(recording[1].dir, Direction::ServerToPunter);
+        assert!(recording.windows(2).all(|pair| pair[0].ts_ms <= pair[1].ts_ms));
+
+        // the same solver sends the same messages
+        let (scores, _) = client::run_network(Replay::new(recording.clone()), ReadParams::default(), "skobochka", GNGameStateBuilder::default(), 0, ()).unwrap();
+        assert_eq!(scores.len(), 2);
+
+        // while another one does not
+        match client::run_network(Replay::new(recording), ReadParams::default(), "skobochka", AlwaysPassGameStateBuilder, 0, ()) {
+            Err(client::Error::Chat(client_chat_error)) =>
+                match client_chat_error {
+                    chat::Error::Send(client::SendError::WritePacket(ref e)) => {
+                        let mismatch = mismatch(e).unwrap();
+                        assert_eq!(mismatch.index, 5);
+                        assert_eq!(mismatch.sent, Some(r#"{"pass":{"punter":0}}"#.to_string()));
+                    },
+                    other =>
+                        panic!("unexpected chat error: {:?}", other),
+                },
+            other =>
+                panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    // the stored recording is synthetic, see `SAMPLE_RECORDING`
+    #[test]
+    fn replay_stored_synthetic_recording() {
+        let recording = load(recording_path(SAMPLE_RECORDING)).unwrap();
+        let (scores, _) = client::run_network(Replay::new(recording), ReadParams::default(), "skobochka", GNGameStateBuilder::default(), 0, ()).unwrap();
+        assert_eq!(scores, vec![Score { punter: 0, score: 6, }, Score { punter: 1, score: 0, }]);
+    }
+}
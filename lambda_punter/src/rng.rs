@@ -0,0 +1,55 @@
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+
+// A seed fixes every random choice of a solver, but searches limited by time (like gn futures selection)
+// also depend on the machine speed, unless they are limited by a fixed amount of work instead.
+pub type Seed = u64;
+pub type GameRng = XorShiftRng;
+
+pub fn random_seed() -> Seed {
+    rand::thread_rng().gen()
+}
+
+// Makes an independent generator for the `stream`-th consumer of `seed` (a game turn, a playout, etc).
+pub fn make_rng(seed: Seed, stream: u64) -> GameRng {
+    let mut state = seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let mut words = [0; 4];
+    for word in words.iter_mut() {
+        *word = splitmix64(&mut state) as u32;
+    }
+    if words.iter().all(|&w| w == 0) {
+        // xorshift does not accept zero state
+        words[0] = 1;
+    }
+    XorShiftRng::from_seed(words)
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use rand::Rng;
+    use super::make_rng;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let seq_a: Vec<u64> = make_rng(17, 3).gen_iter().take(16).collect();
+        let seq_b: Vec<u64> = make_rng(17, 3).gen_iter().take(16).collect();
+        assert_eq!(seq_a, seq_b);
+    }
+
+    #[test]
+    fn streams_differ() {
+        let seq_a: Vec<u64> = make_rng(17, 3).gen_iter().take(16).collect();
+        let seq_b: Vec<u64> = make_rng(17, 4).gen_iter().take(16).collect();
+        let seq_c: Vec<u64> = make_rng(18, 3).gen_iter().take(16).collect();
+        assert!(seq_a != seq_b);
+        assert!(seq_a != seq_c);
+        let _: u64 = make_rng(0, 0).gen();
+    }
+}
@@ -1,20 +1,21 @@
 use super::super::types::PunterId;
 use super::super::proto::{Move, Setup};
 use super::super::game::{GameState, GameStateBuilder};
+use super::super::rng::Seed;
 
 pub struct AlwaysPassGameStateBuilder;
 
 impl GameStateBuilder for AlwaysPassGameStateBuilder {
     type GameState = AlwaysPassGameState;
 
-    fn build(self, setup: Setup) -> Self::GameState {
+    fn build(self, setup: Setup, _seed: Seed) -> Self::GameState {
         AlwaysPassGameState {
             punter: setup.punter,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct AlwaysPassGameState {
     punter: PunterId,
 }
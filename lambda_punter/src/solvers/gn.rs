@@ -1,90 +1,140 @@
 use std::{time, thread};
 use std::cmp::{min, max};
-use std::sync::{mpsc, Arc};
-use std::collections::HashMap;
-use rand::{self, Rng};
+use std::sync::{mpsc, Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use rand::Rng;
 
 use super::super::types::{PunterId, SiteId};
-use super::super::map::{River, RiversIndex};
+use super::super::rng::{self, Seed, GameRng};
+use super::super::map::{Map, River, RiversIndex};
+use super::super::book::{Book, BookKey, Opening};
 use super::super::proto::{Move, Setup, Future};
 use super::super::game::{GameState, GameStateBuilder};
 use super::super::graph::{Graph, GraphCache, EdgeAttr, StepCommand};
+use super::super::derived::{self, Derived};
 use super::super::prob;
 
-pub struct GNGameStateBuilder;
+// futures which could be broken by a single enemy claim are valued half as much
+const FUTURE_MIN_CONNECTIVITY: usize = 2;
+// how many best futures of each mine take part in joint selection
+const FUTURE_CANDIDATES_PER_MINE: usize = 4;
+// selection checks its time limit between simulations only, so let the last one finish
+const FUTURES_SELECTION_GRACE: time::Duration = time::Duration::from_millis(500);
+// estimations budget of futures selection in deterministic mode, takes about 10 seconds
+// on a single core for edinburgh-sparse map with 4 punters
+const FUTURES_SELECTION_EVALUATIONS: usize = 128;
+// stop chasing a future when its completion chance drops below this
+const FUTURE_GIVE_UP_PROBABILITY: f64 = 0.1;
+// simulations budget for reviewing a future during the game
+const FUTURE_REVIEW_GAMES: usize = 256;
+// time budget for reviewing futures on every move, the ones left are reviewed on the next moves
+const FUTURE_REVIEW_TIME: time::Duration = time::Duration::from_millis(200);
+// how many futures are reviewed on every move in deterministic mode
+const FUTURE_REVIEW_EVALUATIONS: usize = 2;
+// derived cache name for betweenness coeffs, and its version to bump on any change in how they are calculated
+const RIVERS_BW_CACHE: &str = "gn_rivers_bw";
+const RIVERS_BW_CACHE_VERSION: u32 = 1;
+
+#[derive(Default)]
+pub struct GNGameStateBuilder {
+    book: Option<Book>,
+    deterministic: bool,
+}
+
+impl GNGameStateBuilder {
+    pub fn new(maybe_book: Option<Book>) -> GNGameStateBuilder {
+        GNGameStateBuilder {
+            book: maybe_book,
+            deterministic: false,
+        }
+    }
+
+    // Futures are selected within a fixed estimations budget instead of the setup time limit, so the game
+    // replays the same given the same seed, no matter how fast the machine is. The time limit could be missed though.
+    pub fn deterministic(mut self, deterministic: bool) -> GNGameStateBuilder {
+        self.deterministic = deterministic;
+        self
+    }
+}
 
 impl GameStateBuilder for GNGameStateBuilder {
     type GameState = GNGameState;
 
-    fn build(self, setup: Setup) -> Self::GameState {
+    fn build(self, setup: Setup, seed: Seed) -> Self::GameState {
         let timeout_start = time::Instant::now();
         let max_timeout = time::Duration::from_secs(8);
+        debug!("building game state with seed {}", seed);
+        let mut rng = rng::make_rng(seed, 0);
+
+        // maybe everything is already precomputed
+        let maybe_opening = self.book.and_then(|book| match book.lookup(&BookKey::from_setup(&setup)) {
+            Ok(Some(opening)) => {
+                debug!("found an opening in book");
+                Some(opening)
+            },
+            Ok(None) => {
+                debug!("no opening in book for this setup");
+                None
+            },
+            Err(e) => {
+                warn!("failed to lookup opening in book: {:?}", e);
+                None
+            },
+        });
+        let (book_rivers_bw, book_futures) = match maybe_opening {
+            Some(Opening { rivers_bw, futures, .. }) =>
+                (Some(rivers_bw), futures),
+            None =>
+                (None, Vec::new()),
+        };
 
         // make map graph
         let rivers_graph = Arc::new(Graph::from_map(&setup.map));
-        let mut gcache = Default::default();
+        let mut gcache: GraphCache = Default::default();
 
         // calculate betweenness coeffs
-        let rivers_bw = Arc::new(RiversIndex::from_hash_map(
-            rivers_graph.rivers_betweenness::<()>(&mut gcache)));
+        let book_rivers_bw = book_rivers_bw.and_then(|rivers_bw| {
+            let map_rivers: HashSet<_> = setup.map.rivers.iter().map(|r| River::new(r.source, r.target)).collect();
+            if rivers_bw.len() == map_rivers.len() && rivers_bw.keys().all(|river| map_rivers.contains(river)) {
+                Some(rivers_bw)
+            } else {
+                warn!("opening rivers betweenness does not match the map, ignoring it");
+                None
+            }
+        });
+        let rivers_bw = Arc::new(if let Some(rivers_bw) = book_rivers_bw {
+            // store it for the next turns as well
+            derived::store(RIVERS_BW_CACHE, RIVERS_BW_CACHE_VERSION, &setup.map.fingerprint().to_string(), &rivers_bw);
+            rivers_bw
+        } else {
+            make_rivers_bw(&setup.map, &rivers_graph)
+        });
 
         let mut futures = None;
         if setup.settings.futures {
-            // in case there is futures support, try to estimate the best ones
-            let mines = Arc::new(setup.map.mines.to_owned());
-            let mut futures_estimated = Vec::with_capacity(setup.map.mines.len());
-            let mut start_turn = 0;
-            for &mine in setup.map.mines.iter() {
-                if let Some(time_avail) = max_timeout.checked_sub(timeout_start.elapsed()) {
-                    debug!("guessing a future for mine {}, {:?} time left", mine, time_avail);
-                    let (tx, rx) = mpsc::channel();
-                    let rivers_graph = rivers_graph.clone();
-                    let rivers_bw = rivers_bw.clone();
-                    let mines = mines.clone();
-                    let punter = setup.punter;
-                    let punters = setup.punters;
-                    let rivers_count = setup.map.rivers.len();
-                    thread::spawn(move || {
-                        tx.send(prob::estimate_best_future(
-                            &rivers_graph,
-                            mine,
-                            &mines,
-                            &rivers_bw,
-                            punter,
-                            punters,
-                            start_turn,
-                            |path_rivers, claimed_rivers| {
-                                path_rivers
-                                    .iter()
-                                    .filter(|&r| !claimed_rivers.contains_key(r))
-                                    .max_by_key(|&r| rivers_bw.get(r).map(|bw| (bw * 1000.0) as u64).unwrap_or(0))
-                            },
-                            min(max(rivers_count, 128), 1024),
-                            time_avail,
-                            &mut Default::default(),
-                            &mut Default::default())).ok();
-                    });
-                    match rx.recv_timeout(time_avail) {
-                        Ok(Some((source, target, path_len))) => {
-                            debug!("guessed a future from {} to {} (path len = {})", source, target, path_len);
-                            futures_estimated.push(Future { source: source, target: target, });
-                            start_turn += path_len * setup.punters;
-                        },
-                        Ok(None) => {
-                            debug!("cannot estimate any future for mine {}, proceeding with next", mine);
-                        },
-                        Err(mpsc::RecvTimeoutError::Timeout) => {
-                            debug!("no more futures guessing, bg thread is timed out");
-                            break;
-                        },
-                        Err(mpsc::RecvTimeoutError::Disconnected) => {
-                            error!("bg thread suddenly disconnected");
-                        },
-                    }
-                } else {
-                    debug!("no more futures guessing, time is expired");
-                    break;
-                }
+            let book_futures_valid = book_futures
+                .iter()
+                .all(|fut| setup.map.mines.contains(&fut.source) && setup.map.sites.iter().any(|site| site.id == fut.target));
+            let mut futures_estimated = if book_futures_valid {
+                book_futures
+            } else {
+                warn!("opening futures does not match the map, ignoring them");
+                Vec::new()
+            };
+            if !futures_estimated.is_empty() {
+                debug!("using {} futures from opening book", futures_estimated.len());
+            } else if let Some(budget) = futures_budget(self.deterministic, timeout_start, max_timeout) {
+                // in case there is futures support, try to estimate the best ones
+                futures_estimated = select_futures(
+                    &rivers_graph,
+                    &rivers_bw,
+                    &setup.map.mines,
+                    setup.punter,
+                    setup.punters,
+                    budget,
+                    rng.gen());
+            } else {
+                debug!("no futures selection, time is expired");
             }
 
             if !futures_estimated.is_empty() {
@@ -125,7 +175,7 @@ impl GameStateBuilder for GNGameStateBuilder {
             }
 
             let mut pairs: Vec<_> = mine_pairs.into_iter().collect();
-            pairs.sort_by_key(|p| (p.1).len());
+            pairs.sort_by_key(|p| ((p.1).len(), p.0));
             pairs.into_iter().map(|p| ((p.0).0, (p.0).1)).collect()
         };
 
@@ -133,14 +183,19 @@ impl GameStateBuilder for GNGameStateBuilder {
 
         GNGameState {
             punter: setup.punter,
-            rivers: setup.map.rivers,
-            rivers_graph: ArcSerDe(rivers_graph),
+            rivers_graph: Derived::new(rivers_graph),
             goals: goals,
             claimed_rivers: Default::default(),
             futures: futures,
             mines: setup.map.mines.to_owned(),
-            rivers_bw: ArcSerDe(rivers_bw),
+            review: Derived::new(Arc::new(Review::new(&rivers_bw))),
+            rivers_bw: Derived::new(rivers_bw),
+            punters: setup.punters,
             options_left: if setup.settings.options { setup.map.mines.len() } else { 0 },
+            map: setup.map,
+            seed: seed,
+            turns: 0,
+            deterministic: self.deterministic,
         }
     }
 }
@@ -150,14 +205,34 @@ type ClaimedRivers = RiversIndex<u64>;
 #[derive(Serialize, Deserialize)]
 pub struct GNGameState {
     punter: PunterId,
-    rivers: Vec<River>,
-    rivers_graph: ArcSerDe<Graph>,
+    map: Map,
+    rivers_graph: Derived<Graph>,
     goals: Vec<(SiteId, SiteId)>,
     claimed_rivers: ClaimedRivers,
     futures: Option<Vec<Future>>,
     mines: Vec<SiteId>,
-    rivers_bw: ArcSerDe<RiversIndex<f64>>,
+    rivers_bw: Derived<RiversIndex<f64>>,
+    review: Derived<Review>,
+    punters: usize,
     options_left: usize,
+    seed: Seed,
+    turns: u64,
+    deterministic: bool,
+}
+
+// Reused by futures review on every move.
+struct Review {
+    opponent: prob::BetweennessOpponent,
+    mcaches: Mutex<Vec<prob::MonteCarloCache>>,
+}
+
+impl Review {
+    fn new(rivers_bw: &RiversIndex<f64>) -> Review {
+        Review {
+            opponent: prob::BetweennessOpponent::new(rivers_bw),
+            mcaches: Mutex::new(prob::MonteCarloCache::pool(prob::available_workers())),
+        }
+    }
 }
 
 impl GameState for GNGameState {
@@ -165,6 +240,10 @@ impl GameState for GNGameState {
 
     fn play(mut self, moves: Vec<Move>) -> Result<(Move, Self), Self::Error> {
         self.update_moves(moves);
+        // every turn gets its own generator, so a game replays the same given the same seed
+        self.turns += 1;
+        let mut rng = rng::make_rng(self.seed, self.turns);
+        self.review_futures(&mut rng);
         let mut gcache = Default::default();
         loop {
             while let Some((source, target)) = self.goals.pop() {
@@ -185,7 +264,7 @@ impl GameState for GNGameState {
             debug!("no more goals to reach, choosing a new random one");
 
             // all current goals are reached for now, let's choose a fallback move
-            let new_goal_path = self.choose_fallback(&mut gcache);
+            let new_goal_path = self.choose_fallback(&mut rng, &mut gcache);
             if let Some((path, source, target)) = new_goal_path {
                 // new goal is choosen
                 if let Some(move_) = self.choose_route_segment(&path) {
@@ -199,11 +278,10 @@ impl GameState for GNGameState {
 
             // no new goals, claim some random river if any
             let move_ = {
-                let free_rivers: Vec<_> = self.rivers
+                let free_rivers: Vec<_> = self.map.rivers
                     .iter()
                     .filter(|r| !self.claimed_rivers.contains_key(r))
                     .collect();
-                let mut rng = rand::thread_rng();
                 if let Some(river) = rng.choose(&free_rivers) {
                     Move::Claim { punter: self.punter, source: river.source, target: river.target, }
                 } else {
@@ -235,9 +313,25 @@ impl GameState for GNGameState {
     fn get_futures(&mut self) -> Option<Vec<Future>> {
         self.futures.clone()
     }
+
+    fn rebuild_derived(&mut self) {
+        self.review();
+    }
 }
 
 impl GNGameState {
+    fn rivers_graph(&self) -> &Graph {
+        self.rivers_graph.get_or_build(|| Graph::from_map(&self.map))
+    }
+
+    fn rivers_bw(&self) -> &RiversIndex<f64> {
+        self.rivers_bw.get_or_build(|| make_rivers_bw(&self.map, self.rivers_graph()))
+    }
+
+    fn review(&self) -> &Review {
+        self.review.get_or_build(|| Review::new(self.rivers_bw()))
+    }
+
     fn update_moves(&mut self, moves: Vec<Move>) {
         for move_ in moves {
             match move_ {
@@ -249,7 +343,7 @@ impl GNGameState {
                 Move::Splurge { punter, route, } => {
                     let mut offset = 0;
                     while let (Some(&source), Some(&target)) = (route.get(offset), route.get(offset + 1)) {
-                        self.claimed_rivers.insert(River::new(source, target), punter);
+                        self.claimed_rivers.insert(River::new(source, target), 1 << punter);
                         offset += 1;
                     }
                 },
@@ -260,11 +354,79 @@ impl GNGameState {
         }
     }
 
+    // Drops goals of futures which became nearly impossible to complete. There is no need to reroute
+    // the rest explicitly: goal paths are recalculated on every move anyway.
+    fn review_futures(&mut self, rng: &mut GameRng) {
+        let mut chased: Vec<_> = if let Some(ref futures) = self.futures {
+            futures
+                .iter()
+                .map(|fut| (fut.source, fut.target))
+                .filter(|&(s, t)| self.goals.iter().any(|&goal| goal == (s, t) || goal == (t, s)))
+                .collect()
+        } else {
+            return;
+        };
+        if chased.is_empty() {
+            return;
+        }
+        // the budget may not suffice for all of them, so start with a different one every move
+        let shift = self.turns as usize % chased.len();
+        chased.rotate_left(shift);
+        let budget = if self.deterministic {
+            prob::Budget::Evaluations(FUTURE_REVIEW_EVALUATIONS)
+        } else {
+            prob::Budget::Time(FUTURE_REVIEW_TIME)
+        };
+
+        // rivers available for options are not considered as lost
+        let my_punter = self.punter;
+        let options_left = self.options_left;
+        let claimed_rivers = RiversIndex::from_hash_map(
+            self.claimed_rivers
+                .iter()
+                .filter(|&(_, &owners)| (owners & (1 << my_punter) != 0) || (options_left == 0) || (owners.count_ones() > 1))
+                .map(|(river, &owners)| {
+                    let owner = if owners & (1 << my_punter) != 0 { my_punter } else { owners.trailing_zeros() as PunterId };
+                    (river.clone(), owner)
+                })
+                .collect());
+        let progress = {
+            let rivers_bw = self.rivers_bw();
+            let review = self.review();
+            let mut mcaches = review.mcaches.lock().unwrap();
+            let mut playouts = prob::Playouts::new(
+                &review.opponent,
+                my_punter,
+                self.punters,
+                |path_rivers, claimed_rivers| choose_by_betweenness(rivers_bw, path_rivers, claimed_rivers),
+                FUTURE_REVIEW_GAMES,
+                &mut mcaches)
+                .seed(rng.gen());
+            prob::futures_progress(
+                self.rivers_graph(),
+                &chased,
+                &claimed_rivers,
+                Some(prob::EarlyStop::at(FUTURE_GIVE_UP_PROBABILITY)),
+                budget,
+                &mut playouts,
+                &mut Default::default())
+        };
+        for fut in progress {
+            let prob = fut.success_probability();
+            debug!("future from {} to {}: {} rivers left, success probability {:?}", fut.source, fut.target, fut.rivers_left, prob);
+            if prob.is_some_and(|prob| prob < FUTURE_GIVE_UP_PROBABILITY) {
+                debug!("giving up future from {} to {}", fut.source, fut.target);
+                let (source, target) = (fut.source, fut.target);
+                self.goals.retain(|&goal| goal != (source, target) && goal != (target, source));
+            }
+        }
+    }
+
     fn shortest_path<'a>(&self, source: SiteId, target: SiteId, gcache: &'a mut GraphCache<usize>) -> Option<&'a [SiteId]> {
         let my_punter = self.punter;
         let claimed_rivers = &self.claimed_rivers;
         let options_left = self.options_left;
-        self.rivers_graph.generic_bfs(source, options_left, |path, _cost, &options_left| {
+        self.rivers_graph().generic_bfs(source, options_left, |path, _cost, &options_left| {
             if let Some(&pt) = path.last() {
                 if pt == target {
                     // reached the target
@@ -322,7 +484,7 @@ impl GNGameState {
             if river_owner.map(|p| p & (1 << self.punter) != 0).unwrap_or(false) {
                 debug!("  -- from {} to {}: already claimed by me", ps, pt);
             } else {
-                let bw_coeff = self.rivers_bw
+                let bw_coeff = self.rivers_bw()
                     .get(&wanted_river)
                     .cloned()
                     .unwrap_or(0.0);
@@ -359,8 +521,7 @@ impl GNGameState {
         }
     }
 
-    fn choose_fallback(&mut self, gcache: &mut GraphCache<usize>) -> Option<(Vec<SiteId>, SiteId, SiteId)> {
-        let mut rng = rand::thread_rng();
+    fn choose_fallback(&mut self, rng: &mut GameRng, gcache: &mut GraphCache<usize>) -> Option<(Vec<SiteId>, SiteId, SiteId)> {
         rng.shuffle(&mut self.mines);
         for &mine in self.mines.iter() {
             debug!("fallback: trying to upgrade route from mine {}", mine);
@@ -368,7 +529,7 @@ impl GNGameState {
             let claimed_rivers = &self.claimed_rivers;
             let options_left = self.options_left;
             let mut best = None;
-            self.rivers_graph.generic_bfs(mine, options_left, |path, cost, &options_left| {
+            self.rivers_graph().generic_bfs(mine, options_left, |path, cost, &options_left| {
                 if let Some(&pt) = path.last() {
                     let cmd = if path.len() > 1 {
                         // maybe we could use an option
@@ -437,29 +598,265 @@ impl GNGameState {
     }
 }
 
-struct ArcSerDe<T>(Arc<T>);
-
-use std::ops::Deref;
+// betweenness is the most expensive part of the state to build, so it is memoized between offline turns
+fn make_rivers_bw(map: &Map, rivers_graph: &Graph) -> RiversIndex<f64> {
+    derived::cached(RIVERS_BW_CACHE, RIVERS_BW_CACHE_VERSION, &map.fingerprint().to_string(), || {
+        RiversIndex::from_hash_map(rivers_graph.rivers_betweenness::<()>(&mut GraphCache::default()))
+    })
+}
 
-impl<T> Deref for ArcSerDe<T> {
-    type Target = T;
+// Time left until `max_timeout` or, in deterministic mode, a fixed estimations budget.
+fn futures_budget(deterministic: bool, timeout_start: time::Instant, max_timeout: time::Duration) -> Option<prob::Budget> {
+    if deterministic {
+        Some(prob::Budget::Evaluations(FUTURES_SELECTION_EVALUATIONS))
+    } else {
+        max_timeout.checked_sub(timeout_start.elapsed()).map(prob::Budget::Time)
+    }
+}
 
-    fn deref(&self) -> &Self::Target {
-        &*self.0
+// Runs futures selection in a separate thread, so it could be abandoned in case it does not fit in the time `budget`.
+// Returns futures in execution order. Every river gets its betweenness, so `rivers_bw` size is the rivers count.
+fn select_futures(
+    rivers_graph: &Arc<Graph>,
+    rivers_bw: &Arc<RiversIndex<f64>>,
+    mines: &[SiteId],
+    punter: PunterId,
+    punters: usize,
+    budget: prob::Budget,
+    seed: Seed)
+    -> Vec<Future>
+{
+    let workers = prob::available_workers();
+    debug!("estimating futures with {} monte carlo workers", workers);
+    let (tx, rx) = mpsc::channel();
+    let rivers_graph = rivers_graph.clone();
+    let rivers_bw = rivers_bw.clone();
+    let mines = mines.to_owned();
+    thread::spawn(move || {
+        let opponent = prob::BetweennessOpponent::new(&rivers_bw);
+        let search = prob::Search {
+            min_connectivity: FUTURE_MIN_CONNECTIVITY,
+            candidates_per_mine: FUTURE_CANDIDATES_PER_MINE,
+            budget: budget,
+        };
+        let mut mcaches = prob::MonteCarloCache::pool(workers);
+        let mut playouts = prob::Playouts::new(
+            &opponent,
+            punter,
+            punters,
+            |path_rivers, claimed_rivers| choose_by_betweenness(&rivers_bw, path_rivers, claimed_rivers),
+            min(max(rivers_bw.len(), 128), 1024),
+            &mut mcaches)
+            .seed(seed);
+        tx.send(prob::select_joint_futures(&rivers_graph, &mines, search, &mut playouts, &mut Default::default())).ok();
+    });
+    let mut futures = Vec::new();
+    let maybe_selected = match budget {
+        prob::Budget::Time(time_avail) =>
+            rx.recv_timeout(time_avail + FUTURES_SELECTION_GRACE),
+        prob::Budget::Evaluations(..) =>
+            rx.recv().map_err(|mpsc::RecvError| mpsc::RecvTimeoutError::Disconnected),
+    };
+    match maybe_selected {
+        Ok(selected) => {
+            for fut in selected {
+                debug!("selected a future from {} to {} (path len = {})", fut.source, fut.target, fut.path.len());
+                futures.push(Future { source: fut.source, target: fut.target, });
+            }
+        },
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            debug!("no futures selected, bg thread is timed out");
+        },
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            error!("bg thread suddenly disconnected");
+        },
     }
+    futures
 }
 
-use serde::{ser, de};
+// Makes opening book entries for `map` played by `punters`, one for every seat. Futures are selected
+// with `time_limit` given for each seat.
+pub fn precompute_openings(map: &Map, punters: usize, time_limit: time::Duration, seed: Seed) -> Vec<Opening> {
+    let rivers_graph = Arc::new(Graph::from_map(map));
+    let rivers_bw = Arc::new(RiversIndex::from_hash_map(
+        rivers_graph.rivers_betweenness::<()>(&mut GraphCache::default())));
+    (0 .. punters)
+        .map(|punter| {
+            let futures = select_futures(
+                &rivers_graph,
+                &rivers_bw,
+                &map.mines,
+                punter as PunterId,
+                punters,
+                prob::Budget::Time(time_limit),
+                seed.wrapping_add(punter as Seed));
+            Opening {
+                rivers_bw: (*rivers_bw).clone(),
+                futures: futures,
+            }
+        })
+        .collect()
+}
 
-impl<T> ser::Serialize for ArcSerDe<T> where T: ser::Serialize {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: ser::Serializer {
-        self.0.serialize(serializer)
-    }
+fn choose_by_betweenness<'a>(rivers_bw: &RiversIndex<f64>, path_rivers: &'a [River], claimed_rivers: &RiversIndex<PunterId>) -> Option<&'a River> {
+    path_rivers
+        .iter()
+        .filter(|&r| !claimed_rivers.contains_key(r))
+        .max_by_key(|&r| rivers_bw.get(r).map(|bw| (bw * 1000.0) as u64).unwrap_or(0))
 }
 
-impl<'de, T> de::Deserialize<'de> for ArcSerDe<T> where T: de::Deserialize<'de> {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: de::Deserializer<'de> {
-        let item: T = de::Deserialize::deserialize(deserializer)?;
-        Ok(ArcSerDe(Arc::new(item)))
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+    use std::collections::HashMap;
+    use serde_json;
+    use super::super::super::types::PunterId;
+    use super::super::super::proto::Move;
+    use super::super::super::rng::{self, Seed};
+    use super::super::super::game::{GameStateBuilder, GameState};
+    use super::super::super::test_common::*;
+    use super::super::super::book::{Book, BookKey, Opening};
+    use super::super::super::proto::Future;
+    use super::super::super::map::{Site, River, RiversIndex};
+    use super::super::super::graph::Graph;
+    use super::{GNGameStateBuilder, GNGameState};
+
+    // returns moves made and state json before each turn
+    fn play_against_passing(seed: Seed) -> (Vec<String>, Vec<String>) {
+        let mut setup = load_map_setup("sample");
+        setup.settings.futures = true;
+        let rivers_count = setup.map.rivers.len();
+        // futures selection limited by time could differ from run to run
+        let mut state = GNGameStateBuilder::default().deterministic(true).build(setup, seed);
+        let mut moves = Vec::new();
+        let mut history = Vec::new();
+        let mut states = Vec::new();
+        for _ in 0 .. rivers_count {
+            // offline mode: state goes through json between turns
+            let json = serde_json::to_string(&state).unwrap();
+            let mut restored: GNGameState = serde_json::from_str(&json).unwrap();
+            restored.rebuild_derived();
+            let (move_, next_state) = restored.play(moves).unwrap();
+            history.push(format!("{:?}", move_));
+            moves = vec![move_, Move::Pass { punter: 1 as PunterId, }];
+            state = next_state;
+            states.push(json);
+        }
+        (history, states)
+    }
+
+    #[test]
+    fn same_seed_same_game() {
+        let (game_a, _) = play_against_passing(1);
+        let (game_b, _) = play_against_passing(1);
+        assert_eq!(game_a, game_b);
+    }
+
+    #[test]
+    fn derived_fields_not_shipped() {
+        let (_, states) = play_against_passing(3);
+        for json in states {
+            assert!(json.contains(r#""rivers_graph":null"#));
+            assert!(json.contains(r#""rivers_bw":null"#));
+            // and they are rebuilt on demand even if `rebuild_derived` is skipped
+            let restored: GNGameState = serde_json::from_str(&json).unwrap();
+            assert!(restored.play(Vec::new()).is_ok());
+        }
+    }
+
+    #[test]
+    fn same_game_same_state_json() {
+        let (_, states_a) = play_against_passing(2);
+        let (_, states_b) = play_against_passing(2);
+        assert_eq!(states_a, states_b);
+        // and restoring then saving again changes nothing
+        for json in states_a {
+            let restored: GNGameState = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn futures_from_book() {
+        let dir = env::temp_dir().join(format!("lambda_punter_gn_book_test_{}", process::id()));
+        let book = Book::new(&dir);
+        let mut setup = load_map_setup("sample");
+        setup.settings.futures = true;
+        // futures are kept in book in execution order
+        let futures = vec![Future { source: 1, target: 4, }, Future { source: 5, target: 0, }];
+        let rivers_bw = Graph::from_map(&setup.map).rivers_betweenness::<()>(&mut Default::default());
+        book.store(&BookKey::from_setup(&setup), Opening {
+            rivers_bw: RiversIndex::from_hash_map(rivers_bw.clone()),
+            futures: futures.clone(),
+        }).unwrap();
+        let mut state = GNGameStateBuilder::new(Some(book.clone())).build(setup, 0);
+        let mut state_futures = state.get_futures().unwrap();
+        state_futures.reverse();
+        assert_eq!(state_futures, futures);
+
+        // futures to sites which are not on the map are not trusted
+        let mut setup = load_map_setup("sample");
+        setup.settings.futures = true;
+        book.store(&BookKey::from_setup(&setup), Opening {
+            rivers_bw: RiversIndex::from_hash_map(rivers_bw),
+            futures: vec![Future { source: 1, target: 100, }],
+        }).unwrap();
+        let mut state = GNGameStateBuilder::new(Some(book)).build(setup, 0);
+        assert!(state.get_futures().unwrap_or_default().iter().all(|fut| fut.target != 100));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn splurged_rivers_reviewed() {
+        // future from mine 1 to site 4, which is reachable through rivers 3-4 and 4-5 only
+        let review_after_splurge = |punter: PunterId| {
+            let setup = load_map_setup("sample");
+            let mut state = GNGameStateBuilder::default().deterministic(true).build(setup, 0);
+            state.futures = Some(vec![Future { source: 1, target: 4, }]);
+            state.goals = vec![(1, 4)];
+            state.update_moves(vec![Move::Splurge { punter: punter, route: vec![3, 4, 5], }]);
+            state.review_futures(&mut rng::make_rng(0, 1));
+            state.goals
+        };
+        // my own splurge completes the future
+        assert_eq!(review_after_splurge(0), vec![(1, 4)]);
+        // while the opponent's one makes it impossible
+        assert_eq!(review_after_splurge(1), vec![]);
+    }
+
+    #[test]
+    fn rivers_bw_from_book() {
+        let dir = env::temp_dir().join(format!("lambda_punter_gn_book_bw_test_{}", process::id()));
+        let book = Book::new(&dir);
+        // a map of its own, so the book betweenness stored to the cache affects no other tests
+        let make_setup = || {
+            let mut setup = load_map_setup("sample");
+            setup.map.sites.push(Site::new(8));
+            setup
+        };
+        let setup = make_setup();
+        let rivers_bw = Graph::from_map(&setup.map).rivers_betweenness::<()>(&mut Default::default());
+        let book_rivers_bw: HashMap<_, _> = rivers_bw.keys().map(|river| (river.clone(), 7.0)).collect();
+
+        // betweenness for other rivers is not trusted
+        let mut other_rivers_bw = book_rivers_bw.clone();
+        other_rivers_bw.insert(River::new(0, 100), 7.0);
+        book.store(&BookKey::from_setup(&setup), Opening {
+            rivers_bw: RiversIndex::from_hash_map(other_rivers_bw),
+            futures: Vec::new(),
+        }).unwrap();
+        let state = GNGameStateBuilder::new(Some(book.clone())).build(make_setup(), 0);
+        assert_eq!(**state.rivers_bw(), rivers_bw);
+
+        // while a matching one is used even though betweenness is in the cache already, in later turns as well
+        book.store(&BookKey::from_setup(&setup), Opening {
+            rivers_bw: RiversIndex::from_hash_map(book_rivers_bw.clone()),
+            futures: Vec::new(),
+        }).unwrap();
+        let state = GNGameStateBuilder::new(Some(book)).build(make_setup(), 0);
+        assert_eq!(**state.rivers_bw(), book_rivers_bw);
+        let restored: GNGameState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(**restored.rivers_bw(), book_rivers_bw);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -5,20 +5,21 @@ use super::super::types::{PunterId, SiteId};
 use super::super::map::{River, RiversIndex};
 use super::super::proto::{Move, Setup, Future};
 use super::super::game::{GameState, GameStateBuilder};
-use super::super::graph::{Graph, GraphCache, EdgeAttr};
+use super::super::rng::Seed;
+use super::super::graph::{Graph, BidirCache, EdgeAttr};
 
 pub struct LinkMinesGameStateBuilder;
 
 impl GameStateBuilder for LinkMinesGameStateBuilder {
     type GameState = LinkMinesGameState;
 
-    fn build(self, setup: Setup) -> Self::GameState {
+    fn build(self, setup: Setup, _seed: Seed) -> Self::GameState {
         let rivers_graph = Graph::from_map(&setup.map);
         let mut gcache = Default::default();
         let mut mine_pairs = HashMap::new();
         if setup.map.mines.len() < 2 {
             if let Some(&mine) = setup.map.mines.iter().next() {
-                for &site in setup.map.sites.iter() {
+                for site in setup.map.sites.iter().map(|s| s.id) {
                     let key = (min(mine, site), max(mine, site));
                     if let Some(path) = rivers_graph.shortest_path_only::<()>(key.0, key.1, &mut gcache) {
                         mine_pairs.insert(key, path.to_owned());
@@ -92,12 +93,12 @@ impl GameState for LinkMinesGameState {
 
     fn play(mut self, moves: Vec<Move>) -> Result<(Move, Self), Self::Error> {
         self.update_moves(moves);
-        let mut gcache = Default::default();
+        let mut bcache = Default::default();
         loop {
             while let Some((orig_source, source, orig_target, target)) = self.goals.pop() {
                 debug!(" ;; found current goal: from {} (originally {}) to {} (originally {})",
                        source, orig_source, target, orig_target);
-                let maybe_path = self.shortest_path(source, target, &mut gcache);
+                let maybe_path = self.shortest_path(source, target, &mut bcache);
                 if let Some(path) = maybe_path {
                     debug!(" ;; there is a path for goal from {} to {}: {:?}", source, target, path);
                     let mut offset = 0;
@@ -126,7 +127,7 @@ impl GameState for LinkMinesGameState {
             for river in self.rivers.iter() {
                 if !self.claimed_rivers.contains_key(river) {
                     for &mine_site in self.mines_connected_sites.iter() {
-                        if self.shortest_path(river.source, mine_site, &mut gcache).is_some() {
+                        if self.shortest_path(river.source, mine_site, &mut bcache).is_some() {
                             debug!(" ;; fallback: new goal is chosen: from {} (as a part of mine path) to {}", mine_site, river.target);
                             let move_ = Move::Claim { punter: self.punter, source: river.source, target: river.target, };
                             new_goal = Some((move_, mine_site, river.source, river.target));
@@ -189,7 +190,7 @@ impl LinkMinesGameState {
         }
     }
 
-    fn shortest_path<'a>(&self, source: SiteId, target: SiteId, gcache: &'a mut GraphCache) -> Option<&'a [SiteId]> {
+    fn shortest_path<'a>(&self, source: SiteId, target: SiteId, bcache: &'a mut BidirCache) -> Option<&'a [SiteId]> {
         let my_punter = self.punter;
         let claimed_rivers = &self.claimed_rivers;
         let probe_claimed = |(s, t)| claimed_rivers
@@ -201,6 +202,6 @@ impl LinkMinesGameState {
             })
             .unwrap_or(EdgeAttr::Accessible { edge_cost: 1, });
 
-        self.rivers_graph.shortest_path(source, target, gcache, probe_claimed)
+        self.rivers_graph.shortest_path_bidir(source, target, bcache, probe_claimed)
     }
 }
@@ -6,13 +6,14 @@ use super::super::types::{PunterId, SiteId};
 use super::super::map::Map;
 use super::super::proto::{Move, Setup};
 use super::super::game::{GameState, GameStateBuilder};
+use super::super::rng::Seed;
 
 pub struct NearestGameStateBuilder;
 
 impl GameStateBuilder for NearestGameStateBuilder {
     type GameState = NearestGameState;
 
-    fn build(self, setup: Setup) -> Self::GameState {
+    fn build(self, setup: Setup, _seed: Seed) -> Self::GameState {
         let mut all_rivs = HashMap::new();
         for r in &setup.map.rivers {
             let src = min(r.source,r.target);
@@ -1,4 +1,7 @@
+use std::fs::File;
+use std::io::Read;
 use super::graph::Graph;
+use super::proto::{Rep, Setup};
 
 pub fn sample_map_graph() -> Graph {
     Graph::from_iter(
@@ -22,3 +25,19 @@ pub fn random_medium_map_graph() -> Graph {
          (90,91),(33,92),(43,92),(48,92),(76,92),(83,92),(50,93),(10,94),(15,94),(26,94),(62,94),(73,94),(11,95),(40,95),(78,95),(0,96),
          (21,96),(39,96),(56,96),(85,96)].iter().cloned())
 }
+
+pub fn load_map_setup(name: &str) -> Setup {
+    let path = format!("{}/../maps/{}.json", env!("CARGO_MANIFEST_DIR"), name);
+    let mut map_json = String::new();
+    File::open(&path).and_then(|mut f| f.read_to_string(&mut map_json)).unwrap();
+    match Rep::from_json::<()>(&format!("{{\"punter\":0,\"punters\":2,\"map\":{}}}", map_json)).unwrap().0 {
+        Rep::Setup(setup) =>
+            setup,
+        other =>
+            panic!("unexpected rep for map {}: {:?}", name, other),
+    }
+}
+
+pub fn load_map_graph(name: &str) -> Graph {
+    Graph::from_map(&load_map_setup(name).map)
+}
@@ -0,0 +1,207 @@
+use std::{io, net, cmp};
+use std::io::{Read, Write};
+use std::sync::mpsc;
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+// A duplex byte stream the client talks to a server over.
+pub trait Transport: Read + Write {
+    // human readable peer description for logs
+    fn describe(&self) -> String;
+
+    // Limits how long a single read may block, `None` means forever. Expired reads fail
+    // with `WouldBlock` or `TimedOut` error (see `is_timeout`).
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "read timeouts are not supported by this transport"))
+    }
+}
+
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+impl Transport for net::TcpStream {
+    fn describe(&self) -> String {
+        match self.peer_addr() {
+            Ok(addr) =>
+                format!("tcp {}", addr),
+            Err(..) =>
+                "tcp (disconnected)".to_string(),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        net::TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn describe(&self) -> String {
+        match self.peer_addr().ok().and_then(|addr| addr.as_pathname().map(|path| path.to_owned())) {
+            Some(path) =>
+                format!("unix socket {:?}", path),
+            None =>
+                "unix socket (unnamed)".to_string(),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+// Process stdin and stdout, as used in offline mode.
+pub struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let s = io::stdin();
+        let mut l = s.lock();
+        l.read(buf)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let s = io::stdin();
+        let mut l = s.lock();
+        l.read_to_end(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let s = io::stdout();
+        let mut l = s.lock();
+        l.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let s = io::stdout();
+        let mut l = s.lock();
+        l.flush()
+    }
+}
+
+impl Transport for Stdio {
+    fn describe(&self) -> String {
+        "stdio".to_string()
+    }
+}
+
+// One end of an in-memory pipe pair, see `pipe`.
+pub struct PipeEnd {
+    tx: mpsc::Sender<Vec<u8>>,
+    rx: mpsc::Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    offset: usize,
+    read_timeout: Option<Duration>,
+}
+
+// Makes a connected pair of in-memory transports: whatever is written to one end can be read from the other.
+// Reads block until there is some data, and return eof after the other end is dropped.
+pub fn pipe() -> (PipeEnd, PipeEnd) {
+    let (tx_a, rx_b) = mpsc::channel();
+    let (tx_b, rx_a) = mpsc::channel();
+    let end_a = PipeEnd { tx: tx_a, rx: rx_a, pending: Vec::new(), offset: 0, read_timeout: None, };
+    let end_b = PipeEnd { tx: tx_b, rx: rx_b, pending: Vec::new(), offset: 0, read_timeout: None, };
+    (end_a, end_b)
+}
+
+impl Read for PipeEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset >= self.pending.len() {
+            let recv_result = match self.read_timeout {
+                Some(timeout) =>
+                    self.rx.recv_timeout(timeout),
+                None =>
+                    self.rx.recv().map_err(|mpsc::RecvError| mpsc::RecvTimeoutError::Disconnected),
+            };
+            match recv_result {
+                Ok(chunk) => {
+                    self.pending = chunk;
+                    self.offset = 0;
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) =>
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "pipe read timed out")),
+                Err(mpsc::RecvTimeoutError::Disconnected) =>
+                    return Ok(0),
+            }
+        }
+        let len = cmp::min(buf.len(), self.pending.len() - self.offset);
+        buf[.. len].copy_from_slice(&self.pending[self.offset .. self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+impl Write for PipeEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.tx.send(buf.to_owned())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "pipe other end is dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for PipeEnd {
+    fn describe(&self) -> String {
+        "in-memory pipe".to_string()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+    use std::io::{self, Read, Write};
+    use super::{Transport, pipe, is_timeout};
+
+    #[test]
+    fn pipe_pair() {
+        let (mut end_a, mut end_b) = pipe();
+        end_a.write_all(b"hello, ").unwrap();
+        end_a.write_all(b"world").unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(end_b.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"hell");
+        let worker = thread::spawn(move || {
+            let mut received = [0; 9];
+            end_b.read_exact(&mut received).unwrap();
+            end_b.write_all(&received).unwrap();
+        });
+        end_a.write_all(b"!").unwrap();
+        worker.join().unwrap();
+        // the other end is dropped now, so there is eof after the data sent
+        let mut echoed = Vec::new();
+        end_a.read_to_end(&mut echoed).unwrap();
+        assert_eq!(echoed, b"o, world!");
+        match end_a.write_all(b"anybody?") {
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe =>
+                (),
+            other =>
+                panic!("unexpected write result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pipe_read_timeout() {
+        let (mut end_a, mut end_b) = pipe();
+        end_a.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let mut buf = [0; 4];
+        assert!(is_timeout(&end_a.read(&mut buf).unwrap_err()));
+        end_b.write_all(b"late").unwrap();
+        assert_eq!(end_a.read(&mut buf).unwrap(), 4);
+    }
+}
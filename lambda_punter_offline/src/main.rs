@@ -3,9 +3,11 @@ extern crate lambda_punter;
 #[macro_use] extern crate log;
 #[macro_use] extern crate clap;
 
-use std::process;
-use clap::{Arg, SubCommand};
-use lambda_punter::{client, game, solvers};
+use std::{time, process};
+use clap::{Arg, ArgMatches, SubCommand};
+use lambda_punter::{client, game, proto, solvers, transport, observer};
+use lambda_punter::rng::{self, Seed};
+use lambda_punter::book::{self, Book};
 use lambda_punter::game::GameState;
 
 fn main() {
@@ -23,6 +25,11 @@ fn main() {
 #[derive(Debug)]
 enum Error {
     MissingParameter(&'static str),
+    InvalidSeed(clap::Error),
+    InvalidPuntersCount(clap::Error),
+    InvalidTimeLimit(clap::Error),
+    InvalidMaxFrameLen(clap::Error),
+    PrecomputeBook(book::Error),
     AlwaysPassSolver(client::Error<()>),
     NearestSolver(client::Error<()>),
     LinkMinesSolver(client::Error<()>),
@@ -39,6 +46,22 @@ fn run() -> Result<(), Error> {
              .help("welcome name for Handshake packet")
              .default_value("skobochka")
              .takes_value(true))
+        .arg(Arg::with_name("seed")
+             .display_order(2)
+             .long("seed")
+             .value_name("SEED")
+             .help("random seed for solver (a random one is used if omitted)")
+             .takes_value(true))
+        .arg(Arg::with_name("max-frame-len")
+             .display_order(3)
+             .long("max-frame-len")
+             .value_name("BYTES")
+             .help("largest server packet accepted (16 MiB if omitted)")
+             .takes_value(true))
+        .arg(Arg::with_name("strict-decode")
+             .display_order(4)
+             .long("strict-decode")
+             .help("fail on any server message deviating from the protocol, instead of skipping malformed moves with a warning"))
         .subcommand(SubCommand::with_name("always_pass")
                     .display_order(1)
                     .about("solvers::always_pass"))
@@ -50,40 +73,106 @@ fn run() -> Result<(), Error> {
                     .about("solvers::link_mines"))
         .subcommand(SubCommand::with_name("gn")
                     .display_order(4)
-                    .about("solvers::gn"))
+                    .about("solvers::gn")
+                    .arg(Arg::with_name("book")
+                         .long("book")
+                         .value_name("DIR")
+                         .help("opening book directory to consult")
+                         .takes_value(true))
+                    .arg(Arg::with_name("deterministic")
+                         .long("deterministic")
+                         .help("select futures within a fixed simulations budget instead of time, so the same seed replays the same game")))
+        .subcommand(SubCommand::with_name("precompute_book")
+                    .display_order(5)
+                    .about("fill solvers::gn opening book for maps given")
+                    .arg(Arg::with_name("maps-dir")
+                         .display_order(1)
+                         .long("maps-dir")
+                         .value_name("DIR")
+                         .help("directory with maps json files")
+                         .default_value("maps")
+                         .takes_value(true))
+                    .arg(Arg::with_name("book")
+                         .display_order(2)
+                         .long("book")
+                         .value_name("DIR")
+                         .help("opening book directory to fill")
+                         .default_value("book")
+                         .takes_value(true))
+                    .arg(Arg::with_name("punters")
+                         .display_order(3)
+                         .long("punters")
+                         .value_name("COUNT,...")
+                         .help("punters counts to precompute for")
+                         .default_value("2,4,8,16")
+                         .use_delimiter(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("time-limit")
+                         .display_order(4)
+                         .long("time-limit")
+                         .value_name("SECS")
+                         .help("futures selection time limit for each map, punters count and seat")
+                         .default_value("60")
+                         .takes_value(true)))
         .get_matches();
 
     let hello_name = matches.value_of("hello-name")
         .ok_or(Error::MissingParameter("hello-name"))?;
+    let seed = if matches.is_present("seed") {
+        value_t!(matches, "seed", Seed)
+            .map_err(Error::InvalidSeed)?
+    } else {
+        rng::random_seed()
+    };
+
+    if let Some(precompute_matches) = matches.subcommand_matches("precompute_book") {
+        return precompute_book(precompute_matches, seed);
+    }
+
+    let read_params = client::ReadParams {
+        max_frame_len: if matches.is_present("max-frame-len") {
+            value_t!(matches, "max-frame-len", usize)
+                .map_err(Error::InvalidMaxFrameLen)?
+        } else {
+            client::DEFAULT_MAX_FRAME_LEN
+        },
+        decode_mode: if matches.is_present("strict-decode") { proto::DecodeMode::Strict } else { proto::DecodeMode::Lenient },
+        ..Default::default()
+    };
 
-    info!("initializing as [ {} ]", hello_name);
+    info!("initializing as [ {} ] with seed {}", hello_name, seed);
     if let Some(..) = matches.subcommand_matches("always_pass") {
         debug!("using solvers::always_pass");
-        proceed_with_solver(hello_name, solvers::always_pass::AlwaysPassGameStateBuilder, Error::AlwaysPassSolver)
+        proceed_with_solver(hello_name, seed, read_params, solvers::always_pass::AlwaysPassGameStateBuilder, Error::AlwaysPassSolver)
     } else if let Some(..) = matches.subcommand_matches("nearest") {
         debug!("using solvers::nearest");
-        proceed_with_solver(hello_name, solvers::nearest::NearestGameStateBuilder, Error::NearestSolver)
+        proceed_with_solver(hello_name, seed, read_params, solvers::nearest::NearestGameStateBuilder, Error::NearestSolver)
     } else if let Some(..) = matches.subcommand_matches("link_mines") {
         debug!("using solvers::link_mines");
-        proceed_with_solver(hello_name, solvers::link_mines::LinkMinesGameStateBuilder, Error::LinkMinesSolver)
-    } else if let Some(..) = matches.subcommand_matches("gn") {
+        proceed_with_solver(hello_name, seed, read_params, solvers::link_mines::LinkMinesGameStateBuilder, Error::LinkMinesSolver)
+    } else if let Some(gn_matches) = matches.subcommand_matches("gn") {
         debug!("using solvers::gn");
-        proceed_with_solver(hello_name, solvers::gn::GNGameStateBuilder, Error::GNSolver)
+        let maybe_book = gn_matches.value_of("book").map(Book::new);
+        let gs_builder = solvers::gn::GNGameStateBuilder::new(maybe_book)
+            .deterministic(gn_matches.is_present("deterministic"));
+        proceed_with_solver(hello_name, seed, read_params, gs_builder, Error::GNSolver)
     } else {
         debug!("using solvers::link_mines");
-        proceed_with_solver(hello_name, solvers::link_mines::LinkMinesGameStateBuilder, Error::LinkMinesSolver)
+        proceed_with_solver(hello_name, seed, read_params, solvers::link_mines::LinkMinesGameStateBuilder, Error::LinkMinesSolver)
     }
 }
 
 fn proceed_with_solver<GB, EF>(
     hello_name: &str,
+    seed: Seed,
+    read_params: client::ReadParams,
     gs_builder: GB,
     err_map: EF)
     -> Result<(), Error>
     where GB: game::GameStateBuilder,
           EF: Fn(client::Error<<GB::GameState as game::GameState>::Error>) -> Error
 {
-    let maybe_results = client::run_offline(hello_name, gs_builder)
+    let maybe_results = client::run_offline(transport::Stdio, read_params, hello_name, gs_builder, seed, observer::Log::default())
         .map_err(err_map)?;
     info!("all done");
 
@@ -100,3 +189,27 @@ fn proceed_with_solver<GB, EF>(
 
     Ok(())
 }
+
+fn precompute_book(matches: &ArgMatches, seed: Seed) -> Result<(), Error> {
+    let maps_dir = matches.value_of("maps-dir")
+        .ok_or(Error::MissingParameter("maps-dir"))?;
+    let book_dir = matches.value_of("book")
+        .ok_or(Error::MissingParameter("book"))?;
+    let punters_counts = values_t!(matches, "punters", usize)
+        .map_err(Error::InvalidPuntersCount)?;
+    let time_limit = value_t!(matches, "time-limit", u64)
+        .map_err(Error::InvalidTimeLimit)?;
+
+    info!("precomputing opening book in {} for maps in {} with seed {}", book_dir, maps_dir, seed);
+    let book = Book::new(book_dir);
+    let mut openings_count = 0;
+    let stored = book::precompute(&book, maps_dir, &punters_counts, |map, punters| {
+        openings_count += 1;
+        let openings_seed = seed.wrapping_add(openings_count);
+        solvers::gn::precompute_openings(map, punters, time::Duration::from_secs(time_limit), openings_seed)
+    }).map_err(Error::PrecomputeBook)?;
+    for (map_path, key) in stored {
+        info!("  {:?}: {:?}", map_path, key);
+    }
+    Ok(())
+}
@@ -4,13 +4,18 @@ extern crate lambda_punter;
 #[macro_use] extern crate log;
 #[macro_use] extern crate clap;
 
-use std::{io, thread, process};
+use std::{io, fs, thread, process};
+use std::path::PathBuf;
 use std::sync::mpsc;
+use std::time::Duration;
+use std::collections::HashMap;
 
 use rand::Rng;
 use clap::{Arg, AppSettings, SubCommand};
-use lambda_punter::{client, game, solvers, proto};
+use lambda_punter::{client, game, solvers, proto, record, observer};
 use lambda_punter::types::PunterId;
+use lambda_punter::rng::{self, Seed};
+use lambda_punter::book::Book;
 use lambda_punter::game::GameState;
 
 fn main() {
@@ -31,6 +36,14 @@ enum Error {
     InvalidServerPort(clap::Error),
     InvalidSlavesCount(clap::Error),
     InvalidGamesCount(clap::Error),
+    InvalidSeed(clap::Error),
+    InvalidTimeout(clap::Error),
+    InvalidConnectAttempts(clap::Error),
+    InvalidMaxFrameLen(clap::Error),
+    InvalidPortFailuresLimit(clap::Error),
+    NoPortsLeft,
+    CreateRecordDir(io::Error),
+    CreateRecording(io::Error),
     NoSubcommandProvided,
     AlwaysPassSolver(client::Error<()>),
     NearestSolver(client::Error<()>),
@@ -99,6 +112,63 @@ fn run() -> Result<(), Error> {
              .help("welcome name for Handshake packet")
              .default_value("skobochka")
              .takes_value(true))
+        .arg(Arg::with_name("seed")
+             .display_order(7)
+             .long("seed")
+             .value_name("SEED")
+             .help("random seed for solver, each game gets its own one derived from it (a random one is used if omitted)")
+             .takes_value(true))
+        .arg(Arg::with_name("connect-timeout")
+             .display_order(8)
+             .long("connect-timeout")
+             .value_name("SECS")
+             .help("server tcp connect attempt timeout (0 for system default)")
+             .default_value("10")
+             .takes_value(true))
+        .arg(Arg::with_name("connect-attempts")
+             .display_order(9)
+             .long("connect-attempts")
+             .value_name("COUNT")
+             .help("server tcp connect attempts before giving up, with a growing pause between them")
+             .default_value("5")
+             .takes_value(true))
+        .arg(Arg::with_name("read-timeout")
+             .display_order(10)
+             .long("read-timeout")
+             .value_name("SECS")
+             .help("longest pause allowed while a server packet is arriving (0 to wait forever)")
+             .default_value("30")
+             .takes_value(true))
+        .arg(Arg::with_name("idle-timeout")
+             .display_order(11)
+             .long("idle-timeout")
+             .value_name("SECS")
+             .help("longest wait for the next server packet to begin (0 to wait forever)")
+             .default_value("600")
+             .takes_value(true))
+        .arg(Arg::with_name("max-frame-len")
+             .display_order(12)
+             .long("max-frame-len")
+             .value_name("BYTES")
+             .help("largest server packet accepted (16 MiB if omitted)")
+             .takes_value(true))
+        .arg(Arg::with_name("strict-decode")
+             .display_order(13)
+             .long("strict-decode")
+             .help("fail on any server message deviating from the protocol, instead of skipping malformed moves with a warning"))
+        .arg(Arg::with_name("port-failures-limit")
+             .display_order(14)
+             .long("port-failures-limit")
+             .value_name("COUNT")
+             .help("consecutive network failures (unreachable or stalled server) after which a port is skipped")
+             .default_value("3")
+             .takes_value(true))
+        .arg(Arg::with_name("record-dir")
+             .display_order(15)
+             .long("record-dir")
+             .value_name("DIR")
+             .help("directory to record every game traffic to, one file per game")
+             .takes_value(true))
         .subcommand(SubCommand::with_name("always_pass")
                     .display_order(1)
                     .about("solvers::always_pass"))
@@ -110,7 +180,15 @@ fn run() -> Result<(), Error> {
                     .about("solvers::link_mines"))
         .subcommand(SubCommand::with_name("gn")
                     .display_order(3)
-                    .about("solvers::gn"))
+                    .about("solvers::gn")
+                    .arg(Arg::with_name("book")
+                         .long("book")
+                         .value_name("DIR")
+                         .help("opening book directory to consult")
+                         .takes_value(true))
+                    .arg(Arg::with_name("deterministic")
+                         .long("deterministic")
+                         .help("select futures within a fixed simulations budget instead of time, so the same seed replays the same game")))
         .get_matches();
 
     let server_host = matches.value_of("server-host")
@@ -125,7 +203,45 @@ fn run() -> Result<(), Error> {
         .map_err(Error::InvalidGamesCount)?;
     let hello_name = matches.value_of("hello-name")
         .ok_or(Error::MissingParameter("hello-name"))?;
+    let base_seed = if matches.is_present("seed") {
+        value_t!(matches, "seed", Seed)
+            .map_err(Error::InvalidSeed)?
+    } else {
+        rng::random_seed()
+    };
+    info!("using base seed {}", base_seed);
+    let timeout_secs = |name| value_t!(matches, name, u64)
+        .map(|secs| if secs == 0 { None } else { Some(Duration::from_secs(secs)) })
+        .map_err(Error::InvalidTimeout);
+    let connect_params = client::ConnectParams {
+        timeout: timeout_secs("connect-timeout")?,
+        attempts: value_t!(matches, "connect-attempts", usize)
+            .map_err(Error::InvalidConnectAttempts)?,
+        ..Default::default()
+    };
+    let read_params = client::ReadParams {
+        timeouts: client::Timeouts {
+            read: timeout_secs("read-timeout")?,
+            idle: timeout_secs("idle-timeout")?,
+        },
+        max_frame_len: if matches.is_present("max-frame-len") {
+            value_t!(matches, "max-frame-len", usize)
+                .map_err(Error::InvalidMaxFrameLen)?
+        } else {
+            client::DEFAULT_MAX_FRAME_LEN
+        },
+        decode_mode: if matches.is_present("strict-decode") { proto::DecodeMode::Strict } else { proto::DecodeMode::Lenient },
+    };
+    let port_failures_limit = value_t!(matches, "port-failures-limit", usize)
+        .map_err(Error::InvalidPortFailuresLimit)?;
+    let maybe_record_dir = matches.value_of("record-dir").map(PathBuf::from);
+    if let Some(ref record_dir) = maybe_record_dir {
+        fs::create_dir_all(record_dir)
+            .map_err(Error::CreateRecordDir)?;
+    }
 
+    let mut maybe_book = None;
+    let mut gn_deterministic = false;
     let solver =
         if let Some(..) = matches.subcommand_matches("always_pass") {
             debug!("using solvers::always_pass");
@@ -136,8 +252,10 @@ fn run() -> Result<(), Error> {
         } else if let Some(..) = matches.subcommand_matches("link_mines") {
             debug!("using solvers::link_mines");
             Solver::LinkMines
-        } else if let Some(..) = matches.subcommand_matches("gn") {
+        } else if let Some(gn_matches) = matches.subcommand_matches("gn") {
             debug!("using solvers::gn");
+            maybe_book = gn_matches.value_of("book").map(Book::new);
+            gn_deterministic = gn_matches.is_present("deterministic");
             Solver::GN
         } else {
             return Err(Error::NoSubcommandProvided);
@@ -155,6 +273,7 @@ fn run() -> Result<(), Error> {
 
     let mut ports_done: Vec<_> = (server_start_port .. server_end_port + 1).collect();
     let mut ports_avail = Vec::with_capacity(ports_done.len());
+    let mut ports_failures = HashMap::new();
 
     while games_played < total_games {
         while slaves.len() < slaves_count {
@@ -163,12 +282,19 @@ fn run() -> Result<(), Error> {
                 ports_avail.extend(ports_done.drain(..));
                 rng.shuffle(&mut ports_avail);
             }
+            if ports_avail.is_empty() {
+                break;
+            }
 
             let tx = tx.clone();
             let server_host = server_host.to_string();
             let server_port = ports_avail.pop().unwrap();
             let hello_name = hello_name.to_string();
+            let maybe_book = maybe_book.clone();
+            let connect_params = connect_params.clone();
+            let maybe_record_dir = maybe_record_dir.clone();
             slave_id_counter += 1;
+            let seed = base_seed.wrapping_add(slave_id_counter as Seed);
             debug!("running slave {} for game on port {}", slave_id_counter, server_port);
             let slave = thread::Builder::new()
                 .name(format!("game slave {}", slaves.len()))
@@ -180,6 +306,10 @@ fn run() -> Result<(), Error> {
                                 &server_host,
                                 server_port,
                                 &hello_name,
+                                &connect_params,
+                                read_params,
+                                maybe_record_dir.as_ref(),
+                                seed,
                                 solvers::always_pass::AlwaysPassGameStateBuilder,
                                 Error::AlwaysPassSolver),
                         Solver::Nearest =>
@@ -188,6 +318,10 @@ fn run() -> Result<(), Error> {
                                 &server_host,
                                 server_port,
                                 &hello_name,
+                                &connect_params,
+                                read_params,
+                                maybe_record_dir.as_ref(),
+                                seed,
                                 solvers::nearest::NearestGameStateBuilder,
                                 Error::NearestSolver),
                         Solver::LinkMines =>
@@ -196,6 +330,10 @@ fn run() -> Result<(), Error> {
                                 &server_host,
                                 server_port,
                                 &hello_name,
+                                &connect_params,
+                                read_params,
+                                maybe_record_dir.as_ref(),
+                                seed,
                                 solvers::link_mines::LinkMinesGameStateBuilder,
                                 Error::LinkMinesSolver),
                         Solver::GN =>
@@ -204,15 +342,22 @@ fn run() -> Result<(), Error> {
                                 &server_host,
                                 server_port,
                                 &hello_name,
-                                solvers::gn::GNGameStateBuilder,
+                                &connect_params,
+                                read_params,
+                                maybe_record_dir.as_ref(),
+                                seed,
+                                solvers::gn::GNGameStateBuilder::new(maybe_book).deterministic(gn_deterministic),
                                 Error::GNSolver),
                     }).ok();
                 })
                 .map_err(Error::GameThreadSpawn)?;
             slaves.push((slave_id_counter, slave));
         }
+        if slaves.is_empty() {
+            return Err(Error::NoPortsLeft);
+        }
 
-        let (slave_id, port) =
+        let (slave_id, port, port_failed) =
             match rx.recv().unwrap() {
                 Ok((slave_id, port, my_punter, scores)) => {
                     println!("SUCCESS for game port {}:", port);
@@ -243,16 +388,26 @@ fn run() -> Result<(), Error> {
                             loses.push((port, my_punter, scores));
                         }
                     }
-                    (slave_id, port)
+                    (slave_id, port, false)
                 },
                 Err((slave_id, port, err)) => {
                     println!("ERROR for game port {}: {:?}", port, err);
-                    (slave_id, port)
+                    (slave_id, port, err.is_network_failure())
                 },
             };
         let slave_i = slaves.iter().position(|s| s.0 == slave_id).unwrap();
         let (_, slave) = slaves.swap_remove(slave_i);
         let () = slave.join().map_err(Error::GameThreadJoin)?;
+        if port_failed {
+            let failures = ports_failures.entry(port).or_insert(0);
+            *failures += 1;
+            if *failures >= port_failures_limit {
+                println!("SKIPPING game port {} after {} network failures in a row", port, failures);
+                continue;
+            }
+        } else {
+            ports_failures.remove(&port);
+        }
         ports_done.push(port);
     }
 
@@ -266,19 +421,46 @@ fn run() -> Result<(), Error> {
     Ok(())
 }
 
+impl Error {
+    fn is_network_failure(&self) -> bool {
+        match *self {
+            Error::AlwaysPassSolver(ref e) | Error::NearestSolver(ref e) | Error::LinkMinesSolver(ref e) | Error::GNSolver(ref e) =>
+                e.is_network_failure(),
+            _ =>
+                false,
+        }
+    }
+}
+
 fn proceed_with_solver<GB, EF>(
     slave_id: usize,
     server_host: &str,
     server_port: u16,
     hello_name: &str,
+    connect_params: &client::ConnectParams,
+    read_params: client::ReadParams,
+    maybe_record_dir: Option<&PathBuf>,
+    seed: Seed,
     gs_builder: GB,
     err_map: EF)
     -> Result<(usize, u16, PunterId, Vec<proto::Score>), (usize, u16, Error)>
     where GB: game::GameStateBuilder,
           EF: Fn(client::Error<<GB::GameState as GameState>::Error>) -> Error
 {
-    info!("playing game on {}:{} as {} (slave {}) ", server_host, server_port, hello_name, slave_id);
-    let (scores, game_state) = client::run_network((server_host, server_port), hello_name, gs_builder)
+    info!("playing game on {}:{} as {} (slave {}, seed {}) ", server_host, server_port, hello_name, slave_id, seed);
+    let tcp = client::connect_tcp((server_host, server_port), connect_params)
+        .map_err(&err_map)
+        .map_err(|e| (slave_id, server_port, e))?;
+    let game_result = if let Some(record_dir) = maybe_record_dir {
+        let record_path = record_dir.join(format!("game-{}-slave{}-seed{}.jsonl", server_port, slave_id, seed));
+        info!("recording game traffic to {:?}", record_path);
+        let log = fs::File::create(record_path)
+            .map_err(|e| (slave_id, server_port, Error::CreateRecording(e)))?;
+        client::run_network(record::Recording::new(tcp, io::BufWriter::new(log)), read_params, hello_name, gs_builder, seed, observer::Log::default())
+    } else {
+        client::run_network(tcp, read_params, hello_name, gs_builder, seed, observer::Log::default())
+    };
+    let (scores, game_state) = game_result
         .map_err(err_map)
         .map_err(|e| (slave_id, server_port, e))?;
     Ok((slave_id, server_port, game_state.get_punter(), scores))
@@ -4,7 +4,6 @@ extern crate piston_window;
 extern crate lambda_punter as lp;
 #[macro_use] extern crate log;
 #[macro_use] extern crate clap;
-#[macro_use] extern crate serde_derive;
 
 use std::{io, fs, thread, process};
 use std::sync::{mpsc, Arc};
@@ -23,6 +22,7 @@ use piston_window::{
     Key
 };
 use lp::types::{SiteId, PunterId};
+use lp::map::{Map, Site, River};
 
 const CONSOLE_HEIGHT: u32 = 32;
 const BORDER_WIDTH: u32 = 16;
@@ -53,6 +53,7 @@ enum Error {
     WorldNoSourceSiteId(SiteId),
     WorldNoTargetSiteId(SiteId),
     WorldNoMineSiteId(SiteId),
+    WorldNoSiteCoords(SiteId),
     WorldNoSitesAtAll,
     GNThreadSpawn(io::Error),
     GNThreadJoin(Box<std::any::Any + Send + 'static>),
@@ -136,7 +137,7 @@ fn run() -> Result<(), Error> {
             error: e,
         }))?;
 
-    let map = Map::new(map_file)?;
+    let map = load_map(map_file)?;
     let world = World::new(&map, punter_id, punters_count, time_limit_ms, map_file.to_string())?;
 
     let mut gui_state = GuiState::Standard;
@@ -191,39 +192,26 @@ fn run() -> Result<(), Error> {
     Ok(())
 }
 
-#[derive(Deserialize)]
-struct Site {
-    id: SiteId,
-    x: f64,
-    y: f64,
+fn load_map<P>(map_file: P) -> Result<Map, Error> where P: AsRef<Path> {
+    let file = io::BufReader::new(
+        fs::File::open(&map_file)
+            .map_err(|e| Error::MapFileOpen {
+                file: map_file.as_ref().to_string_lossy().to_string(),
+                error: e,
+            })?);
+    serde_json::from_reader(file).map_err(|e| Error::MapFileDecode {
+        file: map_file.as_ref().to_string_lossy().to_string(),
+        error: e,
+    })
 }
 
-#[derive(Deserialize)]
-struct River {
-    source: SiteId,
-    target: SiteId,
+// coords presence is checked in `World::new`
+fn site_x(site: &Site) -> f64 {
+    site.x.unwrap_or(0.0)
 }
 
-#[derive(Deserialize)]
-struct Map {
-    sites: Vec<Site>,
-    rivers: Vec<River>,
-    mines: Vec<SiteId>,
-}
-
-impl Map {
-    fn new<P>(map_file: P) -> Result<Map, Error> where P: AsRef<Path> {
-        let file = io::BufReader::new(
-            fs::File::open(&map_file)
-                .map_err(|e| Error::MapFileOpen {
-                    file: map_file.as_ref().to_string_lossy().to_string(),
-                    error: e,
-                })?);
-        serde_json::from_reader(file).map_err(|e| Error::MapFileDecode {
-            file: map_file.as_ref().to_string_lossy().to_string(),
-            error: e,
-        })
-    }
+fn site_y(site: &Site) -> f64 {
+    site.y.unwrap_or(0.0)
 }
 
 struct RiverRef<'a> {
@@ -282,7 +270,8 @@ impl<'a> World<'a> {
         }
 
         let mut bounds = None;
-        for &Site { x, y, .. } in map.sites.iter() {
+        for site in map.sites.iter() {
+            let (x, y) = site.coords().ok_or_else(|| Error::WorldNoSiteCoords(site.id))?;
             if let Some((ref mut min_x, ref mut min_y, ref mut max_x, ref mut max_y)) = bounds {
                 if x < *min_x { *min_x = x; }
                 if y < *min_y { *min_y = y; }
@@ -301,7 +290,7 @@ impl<'a> World<'a> {
             rivers_refs: rivers_refs,
             mines_refs: mines_refs,
             bounds: bounds.ok_or(Error::WorldNoSitesAtAll)?,
-            graph: Arc::new(lp::graph::Graph::from_iter(map.rivers.iter().map(|r| (r.source, r.target)))),
+            graph: Arc::new(lp::graph::Graph::from_map(map)),
         })
     }
 
@@ -318,15 +307,15 @@ impl<'a> World<'a> {
             draw_element(DrawElement::River {
                 color: color,
                 radius: radius,
-                source_x: source.x,
-                source_y: source.y,
-                target_x: target.x,
-                target_y: target.y,
+                source_x: site_x(source),
+                source_y: site_y(source),
+                target_x: site_x(target),
+                target_y: site_y(target),
             });
         }
 
         for &site in self.mines_refs.iter() {
-            draw_element(DrawElement::Mine { x: site.x, y: site.y, });
+            draw_element(DrawElement::Mine { x: site_x(site), y: site_y(site), });
         }
     }
 
@@ -480,22 +469,25 @@ impl GuiState {
                     .name("futures estimator slave".to_string())
                     .spawn(move || {
                         let mut gcache = Default::default();
-                        let mut mcache = Default::default();
+                        let mut mcaches = lp::prob::MonteCarloCache::pool(lp::prob::available_workers());
+                        let seed = lp::rng::random_seed();
                         let gn_table = lp::map::RiversIndex::from_hash_map(
                             graph.rivers_betweenness(&mut gcache));
+                        let opponent = lp::prob::BetweennessOpponent::new(&gn_table);
                         let mut futures = Vec::new();
                         let mut start_turn = 0;
                         let timeout_start = std::time::Instant::now();
                         for &mine in mines.iter() {
                             if let Some(time_avail) = max_timeout.checked_sub(timeout_start.elapsed()) {
-                                let maybe_future = lp::prob::estimate_best_future(
-                                    &graph,
-                                    mine,
-                                    &mines,
-                                    &gn_table,
+                                let search = lp::prob::Search {
+                                    min_connectivity: 2,
+                                    candidates_per_mine: 1,
+                                    budget: lp::prob::Budget::Time(time_avail),
+                                };
+                                let mut playouts = lp::prob::Playouts::new(
+                                    &opponent,
                                     punter_id,
                                     punters_count,
-                                    start_turn,
                                     |path_rivers, claimed_rivers| {
                                         path_rivers
                                             .iter()
@@ -503,8 +495,15 @@ impl GuiState {
                                             .max_by_key(|&r| gn_table.get(r).map(|bw| (bw * 1000.0) as u64).unwrap_or(0))
                                     },
                                     std::cmp::min(std::cmp::max(rivers_count, 128), 1024),
-                                    time_avail,
-                                    &mut mcache,
+                                    &mut mcaches)
+                                    .seed(seed);
+                                let maybe_future = lp::prob::estimate_best_future(
+                                    &graph,
+                                    mine,
+                                    &mines,
+                                    start_turn,
+                                    search,
+                                    &mut playouts,
                                     &mut gcache);
                                 if let Some((source, target, path_len)) = maybe_future {
                                     futures.push((source, target));
@@ -562,7 +561,7 @@ impl GuiState {
                         let mut futures = Vec::with_capacity(raw_futures.len());
                         for (source, target) in raw_futures {
                             if let (Some(source_ref), Some(target_ref)) = (find_ref(source), find_ref(target)) {
-                                futures.push((source_ref.x, source_ref.y, target_ref.x, target_ref.y));
+                                futures.push((site_x(source_ref), site_y(source_ref), site_x(target_ref), site_y(target_ref)));
                             }
                         }
                         let () = slave.join().map_err(Error::FuturesThreadJoin)?;